@@ -0,0 +1,231 @@
+use cid::{Cid, Version};
+use multihash::Multihash;
+use multihash_codetable::{Code, MultihashDigest};
+use thiserror::Error;
+
+pub const RAW_CODEC: u64 = 0x55;
+pub const DAG_PB_CODEC: u64 = 0x70;
+
+// a cid is backed by a 64 byte multihash, identity hashes have to fit in it
+pub const MAX_INLINE_SIZE: usize = 64;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum CidErrors {
+    #[error("Unsupported multihash code: {0:#x}")]
+    UnsupportedHashError(u64),
+    #[error("CIDv0 only supports sha2-256 hashed dag-pb blocks")]
+    InvalidV0Error,
+    #[error("Payload of {0} bytes is too large for an identity hash")]
+    InlineTooLargeError(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum HashAlgorithm {
+    Identity,
+    Sha2_256,
+    Sha2_512,
+    Sha3_256,
+    Sha3_512,
+    Blake2b256,
+    Blake2b512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn code(&self) -> u64 {
+        match self {
+            HashAlgorithm::Identity => 0x00,
+            HashAlgorithm::Sha2_256 => 0x12,
+            HashAlgorithm::Sha2_512 => 0x13,
+            HashAlgorithm::Sha3_256 => 0x16,
+            HashAlgorithm::Sha3_512 => 0x14,
+            HashAlgorithm::Blake2b256 => 0xb220,
+            HashAlgorithm::Blake2b512 => 0xb240,
+            HashAlgorithm::Blake3 => 0x1e,
+        }
+    }
+
+    pub fn from_code(code: u64) -> Result<Self, CidErrors> {
+        Ok(match code {
+            0x00 => HashAlgorithm::Identity,
+            0x12 => HashAlgorithm::Sha2_256,
+            0x13 => HashAlgorithm::Sha2_512,
+            0x16 => HashAlgorithm::Sha3_256,
+            0x14 => HashAlgorithm::Sha3_512,
+            0xb220 => HashAlgorithm::Blake2b256,
+            0xb240 => HashAlgorithm::Blake2b512,
+            0x1e => HashAlgorithm::Blake3,
+            other => return Err(CidErrors::UnsupportedHashError(other)),
+        })
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Result<Multihash<64>, CidErrors> {
+        if *self == HashAlgorithm::Identity {
+            return Multihash::<64>::wrap(self.code(), data)
+                .map_err(|_| CidErrors::InlineTooLargeError(data.len()));
+        }
+        let code = Code::try_from(self.code())
+            .map_err(|_| CidErrors::UnsupportedHashError(self.code()))?;
+        Ok(code.digest(data))
+    }
+}
+
+/*
+tldr; how it works
+describes how a cid gets made: version, codec and hash function.
+v0 is just a base58 sha2-256 multihash with an implied dag-pb codec, so anything else asked
+of a v0 builder is an error. with an inline limit set, payloads at or below it skip hashing
+and get embedded in the cid itself through the identity hash, the way kubo's --inline does.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct CidBuilder {
+    version: Version,
+    codec: u64,
+    hash: HashAlgorithm,
+    inline_limit: Option<usize>,
+}
+
+impl Default for CidBuilder {
+    fn default() -> Self {
+        CidBuilder {
+            version: Version::V1,
+            codec: RAW_CODEC,
+            hash: HashAlgorithm::Sha2_256,
+            inline_limit: None,
+        }
+    }
+}
+
+impl CidBuilder {
+    pub fn new() -> Self {
+        CidBuilder::default()
+    }
+
+    pub fn v0() -> Self {
+        CidBuilder {
+            version: Version::V0,
+            codec: DAG_PB_CODEC,
+            hash: HashAlgorithm::Sha2_256,
+            inline_limit: None,
+        }
+    }
+
+    pub fn version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn codec(mut self, codec: u64) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn hash(mut self, hash: HashAlgorithm) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    pub fn inline_limit(mut self, limit: Option<usize>) -> Self {
+        self.inline_limit = limit;
+        self
+    }
+
+    pub fn get_version(&self) -> Version {
+        self.version
+    }
+
+    pub fn get_codec(&self) -> u64 {
+        self.codec
+    }
+
+    pub fn get_hash(&self) -> HashAlgorithm {
+        self.hash
+    }
+
+    pub fn build(&self, data: &[u8]) -> Result<Cid, CidErrors> {
+        let inline = matches!(self.inline_limit, Some(limit) if data.len() <= limit.min(MAX_INLINE_SIZE));
+        match self.version {
+            Version::V0 => {
+                if self.codec != DAG_PB_CODEC || self.hash != HashAlgorithm::Sha2_256 || inline {
+                    return Err(CidErrors::InvalidV0Error);
+                }
+                Ok(Cid::new_v0(self.hash.digest(data)?).expect("sha2-256 is a valid v0 multihash"))
+            }
+            Version::V1 => {
+                let hash = if inline {
+                    HashAlgorithm::Identity
+                } else {
+                    self.hash
+                };
+                Ok(Cid::new_v1(self.codec, hash.digest(data)?))
+            }
+        }
+    }
+}
+
+/*
+rehashes the data with whatever function the cid names and compares digests,
+so blocks made by other implementations with other hashes still verify
+*/
+pub fn verify_cid(cid: &Cid, data: &[u8]) -> Result<bool, CidErrors> {
+    let hash = HashAlgorithm::from_code(cid.hash().code())?;
+    Ok(hash.digest(data)?.digest() == cid.hash().digest())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_every_hash_algorithm() {
+        let data = b"hello world";
+        for hash in [
+            HashAlgorithm::Sha2_256,
+            HashAlgorithm::Sha2_512,
+            HashAlgorithm::Sha3_256,
+            HashAlgorithm::Sha3_512,
+            HashAlgorithm::Blake2b256,
+            HashAlgorithm::Blake2b512,
+            HashAlgorithm::Blake3,
+        ] {
+            let cid = CidBuilder::new().hash(hash).build(data).unwrap();
+            assert_eq!(cid.hash().code(), hash.code());
+            assert_eq!(HashAlgorithm::from_code(cid.hash().code()), Ok(hash));
+            assert!(verify_cid(&cid, data).unwrap());
+            assert!(!verify_cid(&cid, b"hello there").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_known_cids() {
+        // kubo: echo -n "hello world" | ipfs add --raw-leaves --cid-version 1
+        let cid = CidBuilder::new().build(b"hello world").unwrap();
+        assert_eq!(
+            cid.to_string(),
+            "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e"
+        );
+
+        let v0 = CidBuilder::v0().build(b"hello world").unwrap();
+        assert_eq!(v0.version(), Version::V0);
+        assert!(v0.to_string().starts_with("Qm"));
+        assert!(CidBuilder::v0().codec(RAW_CODEC).build(b"x").is_err());
+        assert!(CidBuilder::v0().hash(HashAlgorithm::Blake3).build(b"x").is_err());
+    }
+
+    #[test]
+    fn test_identity_inlining() {
+        let builder = CidBuilder::new().inline_limit(Some(32));
+        let small = builder.build(b"tiny").unwrap();
+        assert_eq!(small.hash().code(), HashAlgorithm::Identity.code());
+        assert_eq!(small.hash().digest(), b"tiny");
+        assert!(verify_cid(&small, b"tiny").unwrap());
+
+        let large = builder.build(&[0u8; 33]).unwrap();
+        assert_eq!(large.hash().code(), HashAlgorithm::Sha2_256.code());
+
+        assert_eq!(
+            CidBuilder::new().hash(HashAlgorithm::Identity).build(&[0u8; 65]),
+            Err(CidErrors::InlineTooLargeError(65))
+        );
+    }
+}
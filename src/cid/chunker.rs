@@ -0,0 +1,448 @@
+use std::str::FromStr;
+use thiserror::Error;
+
+/*
+tldr; how it works
+a chunker only decides where the next chunk of a byte stream ends, it never owns the stream.
+callers keep at least max_size() bytes buffered (less only at the end of the input) and ask
+cut() how many of them make up the next chunk, then drop those bytes and ask again.
+fixed size chunking cuts every n bytes, so one inserted byte shifts every chunk after it.
+rabin and fastcdc are content defined: they run a rolling hash over the bytes and cut
+wherever the hash matches a mask, so boundaries move with the content and an edit only
+changes the chunks around it. everything after the edit dedups against the old leaves.
+*/
+
+// kubo's default, 256 KiB leaves
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+pub const DEFAULT_MIN_SIZE: usize = 64 * 1024;
+pub const DEFAULT_AVG_SIZE: usize = 256 * 1024;
+pub const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+
+// width of the sliding window the rabin fingerprint is computed over
+const RABIN_WINDOW_SIZE: usize = 64;
+// irreducible polynomial of degree 53, same default restic uses
+const RABIN_POLYNOMIAL: u64 = 0x3DA3358B4DC173;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ChunkerErrors {
+    #[error("Unknown chunker: {0}")]
+    UnknownChunkerError(String),
+    #[error("Invalid chunker parameters: {0}")]
+    InvalidParametersError(String),
+}
+
+pub trait Chunker: Send + Sync {
+    // largest chunk cut() can return, callers buffer at least this much before asking
+    fn max_size(&self) -> usize;
+    // length of the chunk at the start of buf, buf is only shorter than max_size() at eof
+    fn cut(&self, buf: &[u8]) -> usize;
+}
+
+#[derive(Debug, Clone)]
+pub struct FixedSizeChunker {
+    size: usize,
+}
+
+impl FixedSizeChunker {
+    pub fn new(size: usize) -> Result<Self, ChunkerErrors> {
+        if size == 0 {
+            return Err(ChunkerErrors::InvalidParametersError(
+                "chunk size must be greater than zero".to_string(),
+            ));
+        }
+        Ok(FixedSizeChunker { size })
+    }
+}
+
+impl Chunker for FixedSizeChunker {
+    fn max_size(&self) -> usize {
+        self.size
+    }
+
+    fn cut(&self, buf: &[u8]) -> usize {
+        buf.len().min(self.size)
+    }
+}
+
+fn validate_bounds(min: usize, avg: usize, max: usize) -> Result<(), ChunkerErrors> {
+    if min == 0 || !(min <= avg && avg <= max) {
+        return Err(ChunkerErrors::InvalidParametersError(format!(
+            "expected 0 < min <= avg <= max, got {}-{}-{}",
+            min, avg, max
+        )));
+    }
+    if !avg.is_power_of_two() {
+        return Err(ChunkerErrors::InvalidParametersError(format!(
+            "average chunk size must be a power of two, got {}",
+            avg
+        )));
+    }
+    Ok(())
+}
+
+/*
+rabin fingerprinting over GF(2): the window is treated as a polynomial and reduced modulo
+an irreducible polynomial. out_table removes the byte leaving the window and mod_table
+folds the top byte back in, so sliding one byte costs two lookups.
+*/
+#[derive(Debug, Clone)]
+pub struct RabinChunker {
+    min_size: usize,
+    max_size: usize,
+    split_mask: u64,
+    pol_shift: u32,
+    out_table: [u64; 256],
+    mod_table: [u64; 256],
+}
+
+fn pol_degree(p: u64) -> u32 {
+    63 - p.leading_zeros()
+}
+
+fn pol_mod(mut x: u64, p: u64) -> u64 {
+    let degree = pol_degree(p);
+    while x != 0 && pol_degree(x) >= degree {
+        x ^= p << (pol_degree(x) - degree);
+    }
+    x
+}
+
+fn pol_append_byte(hash: u64, byte: u8, p: u64) -> u64 {
+    pol_mod((hash << 8) | byte as u64, p)
+}
+
+impl RabinChunker {
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> Result<Self, ChunkerErrors> {
+        validate_bounds(min_size, avg_size, max_size)?;
+        let degree = pol_degree(RABIN_POLYNOMIAL);
+
+        let mut out_table = [0u64; 256];
+        let mut mod_table = [0u64; 256];
+        for byte in 0..256usize {
+            let mut hash = pol_append_byte(0, byte as u8, RABIN_POLYNOMIAL);
+            for _ in 0..RABIN_WINDOW_SIZE - 1 {
+                hash = pol_append_byte(hash, 0, RABIN_POLYNOMIAL);
+            }
+            out_table[byte] = hash;
+            let shifted = (byte as u64) << degree;
+            mod_table[byte] = pol_mod(shifted, RABIN_POLYNOMIAL) | shifted;
+        }
+
+        Ok(RabinChunker {
+            min_size,
+            max_size,
+            split_mask: avg_size as u64 - 1,
+            pol_shift: degree - 8,
+            out_table,
+            mod_table,
+        })
+    }
+}
+
+impl Default for RabinChunker {
+    fn default() -> Self {
+        RabinChunker::new(DEFAULT_MIN_SIZE, DEFAULT_AVG_SIZE, DEFAULT_MAX_SIZE)
+            .expect("default rabin parameters are valid")
+    }
+}
+
+impl Chunker for RabinChunker {
+    fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    fn cut(&self, buf: &[u8]) -> usize {
+        let end = buf.len().min(self.max_size);
+        if end <= self.min_size {
+            return end;
+        }
+
+        let mut window = [0u8; RABIN_WINDOW_SIZE];
+        let mut wpos = 0;
+        let mut digest: u64 = 0;
+        // bytes before min_size - window can never influence a cut point
+        let start = self.min_size.saturating_sub(RABIN_WINDOW_SIZE);
+        for (i, &byte) in buf.iter().enumerate().take(end).skip(start) {
+            let out = window[wpos];
+            window[wpos] = byte;
+            wpos = (wpos + 1) % RABIN_WINDOW_SIZE;
+            digest ^= self.out_table[out as usize];
+
+            let index = (digest >> self.pol_shift) as u8;
+            digest = (digest << 8) | byte as u64;
+            digest ^= self.mod_table[index as usize];
+
+            if i + 1 >= self.min_size && digest & self.split_mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/*
+fastcdc: a gear hash (shift left, add a random value per byte) is cheaper than rabin.
+normalized chunking uses a stricter mask before the average size and a looser one after,
+which pulls chunk sizes towards the average. masks use the top bits of the hash since
+those depend on the last 64 bytes, the low bits only see the last few.
+*/
+#[derive(Debug, Clone)]
+pub struct FastCdcChunker {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    mask_small: u64,
+    mask_large: u64,
+    gear: [u64; 256],
+}
+
+fn top_bits_mask(bits: u32) -> u64 {
+    if bits == 0 {
+        return 0;
+    }
+    u64::MAX << (64 - bits)
+}
+
+fn gear_table() -> [u64; 256] {
+    // splitmix64 with a fixed seed, boundaries must be identical across runs and nodes
+    let mut state: u64 = 0x6970_6673_2d72_7573;
+    let mut table = [0u64; 256];
+    for entry in table.iter_mut() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        *entry = z ^ (z >> 31);
+    }
+    table
+}
+
+impl FastCdcChunker {
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> Result<Self, ChunkerErrors> {
+        validate_bounds(min_size, avg_size, max_size)?;
+        let bits = avg_size.trailing_zeros();
+        Ok(FastCdcChunker {
+            min_size,
+            avg_size,
+            max_size,
+            mask_small: top_bits_mask((bits + 1).min(64)),
+            mask_large: top_bits_mask(bits.saturating_sub(1)),
+            gear: gear_table(),
+        })
+    }
+}
+
+impl Default for FastCdcChunker {
+    fn default() -> Self {
+        FastCdcChunker::new(DEFAULT_MIN_SIZE, DEFAULT_AVG_SIZE, DEFAULT_MAX_SIZE)
+            .expect("default fastcdc parameters are valid")
+    }
+}
+
+impl Chunker for FastCdcChunker {
+    fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    fn cut(&self, buf: &[u8]) -> usize {
+        let end = buf.len().min(self.max_size);
+        if end <= self.min_size {
+            return end;
+        }
+        let normal = end.min(self.avg_size);
+
+        let mut fingerprint: u64 = 0;
+        let mut i = self.min_size;
+        while i < normal {
+            fingerprint = (fingerprint << 1).wrapping_add(self.gear[buf[i] as usize]);
+            if fingerprint & self.mask_small == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < end {
+            fingerprint = (fingerprint << 1).wrapping_add(self.gear[buf[i] as usize]);
+            if fingerprint & self.mask_large == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        end
+    }
+}
+
+/*
+which chunker an import uses, parsed from the same strings kubo accepts for --chunker:
+size-<bytes>, rabin, rabin-<avg>, rabin-<min>-<avg>-<max>, and the fastcdc equivalents
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkerType {
+    FixedSize(usize),
+    Rabin { min: usize, avg: usize, max: usize },
+    FastCdc { min: usize, avg: usize, max: usize },
+}
+
+impl Default for ChunkerType {
+    fn default() -> Self {
+        ChunkerType::FixedSize(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+impl ChunkerType {
+    pub fn build(&self) -> Result<Box<dyn Chunker>, ChunkerErrors> {
+        Ok(match *self {
+            ChunkerType::FixedSize(size) => Box::new(FixedSizeChunker::new(size)?),
+            ChunkerType::Rabin { min, avg, max } => Box::new(RabinChunker::new(min, avg, max)?),
+            ChunkerType::FastCdc { min, avg, max } => {
+                Box::new(FastCdcChunker::new(min, avg, max)?)
+            }
+        })
+    }
+}
+
+fn parse_size(value: &str, spec: &str) -> Result<usize, ChunkerErrors> {
+    value
+        .parse::<usize>()
+        .map_err(|_| ChunkerErrors::InvalidParametersError(spec.to_string()))
+}
+
+fn parse_bounds(params: &[&str], spec: &str) -> Result<(usize, usize, usize), ChunkerErrors> {
+    match params {
+        [] => Ok((DEFAULT_MIN_SIZE, DEFAULT_AVG_SIZE, DEFAULT_MAX_SIZE)),
+        [avg] => {
+            let avg = parse_size(avg, spec)?;
+            Ok((avg / 4, avg, avg * 4))
+        }
+        [min, avg, max] => Ok((
+            parse_size(min, spec)?,
+            parse_size(avg, spec)?,
+            parse_size(max, spec)?,
+        )),
+        _ => Err(ChunkerErrors::InvalidParametersError(spec.to_string())),
+    }
+}
+
+impl FromStr for ChunkerType {
+    type Err = ChunkerErrors;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut parts = spec.split('-');
+        let name = parts.next().unwrap_or_default();
+        let params: Vec<&str> = parts.collect();
+        let chunker = match name {
+            "size" => match params.as_slice() {
+                [] => ChunkerType::FixedSize(DEFAULT_CHUNK_SIZE),
+                [size] => ChunkerType::FixedSize(parse_size(size, spec)?),
+                _ => return Err(ChunkerErrors::InvalidParametersError(spec.to_string())),
+            },
+            "rabin" => {
+                let (min, avg, max) = parse_bounds(&params, spec)?;
+                ChunkerType::Rabin { min, avg, max }
+            }
+            "fastcdc" => {
+                let (min, avg, max) = parse_bounds(&params, spec)?;
+                ChunkerType::FastCdc { min, avg, max }
+            }
+            _ => return Err(ChunkerErrors::UnknownChunkerError(spec.to_string())),
+        };
+        // surface bad bounds at parse time rather than at the first import
+        chunker.build()?;
+        Ok(chunker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn split(chunker: &dyn Chunker, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = chunker.cut(&data[offset..]);
+            chunks.push(data[offset..offset + len].to_vec());
+            offset += len;
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_fixed_size_chunker() {
+        let chunker = FixedSizeChunker::new(10).unwrap();
+        let chunks = split(&chunker, &[7u8; 35]);
+        let sizes: Vec<usize> = chunks.iter().map(|c| c.len()).collect();
+        assert_eq!(sizes, vec![10, 10, 10, 5]);
+        assert!(FixedSizeChunker::new(0).is_err());
+    }
+
+    #[test]
+    fn test_content_defined_chunkers_respect_bounds() {
+        let data = random_bytes(200_000, 42);
+        let chunkers: Vec<Box<dyn Chunker>> = vec![
+            Box::new(RabinChunker::new(1024, 4096, 16384).unwrap()),
+            Box::new(FastCdcChunker::new(1024, 4096, 16384).unwrap()),
+        ];
+        for chunker in chunkers {
+            let chunks = split(chunker.as_ref(), &data);
+            assert!(chunks.len() > 1);
+            for chunk in &chunks[..chunks.len() - 1] {
+                assert!(chunk.len() >= 1024 && chunk.len() <= 16384);
+            }
+            assert_eq!(chunks.concat(), data);
+        }
+    }
+
+    #[test]
+    fn test_content_defined_chunkers_survive_inserts() {
+        let original = random_bytes(200_000, 7);
+        let mut edited = original.clone();
+        edited.insert(100, 0xFF);
+
+        let chunkers: Vec<Box<dyn Chunker>> = vec![
+            Box::new(RabinChunker::new(1024, 4096, 16384).unwrap()),
+            Box::new(FastCdcChunker::new(1024, 4096, 16384).unwrap()),
+        ];
+        for chunker in chunkers {
+            let before = split(chunker.as_ref(), &original);
+            let after = split(chunker.as_ref(), &edited);
+            let shared = after.iter().filter(|c| before.contains(c)).count();
+            // only the chunks around the insert should differ
+            assert!(shared + 3 >= before.len(), "{} of {} shared", shared, before.len());
+        }
+
+        // whereas a fixed size chunker shares nothing after the insert point
+        let fixed = FixedSizeChunker::new(4096).unwrap();
+        let before = split(&fixed, &original);
+        let after = split(&fixed, &edited);
+        assert_eq!(after.iter().filter(|c| before.contains(c)).count(), 0);
+    }
+
+    #[test]
+    fn test_parse_chunker_type() {
+        assert_eq!("size-1024".parse(), Ok(ChunkerType::FixedSize(1024)));
+        assert_eq!(
+            "rabin-1024-4096-16384".parse(),
+            Ok(ChunkerType::Rabin { min: 1024, avg: 4096, max: 16384 })
+        );
+        assert_eq!(
+            "fastcdc-8192".parse(),
+            Ok(ChunkerType::FastCdc { min: 2048, avg: 8192, max: 32768 })
+        );
+        assert!("buzz-10".parse::<ChunkerType>().is_err());
+        assert!("rabin-10-3000-20".parse::<ChunkerType>().is_err());
+        assert!("size-0".parse::<ChunkerType>().is_err());
+    }
+}
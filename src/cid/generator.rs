@@ -1,40 +1,54 @@
 use cid::Cid;
-use multihash::Multihash;
 use std::fs::File;
 use std::io::{self, Read};
+use crate::cid::builder::CidBuilder;
+use crate::cid::chunker::{Chunker, ChunkerType};
 use crate::storage::MerkleNode;
 
-const CHUNK_SIZE: usize = 1024; 
-
-use sha2::{Sha256, Digest};
-
+// CIDv1, raw codec, sha2-256
 pub fn generate_cid(data: &[u8]) -> Cid {
-    const SHA2_256: u64 = 0x12; 
-
-    let hash_output = Sha256::digest(data); 
-
-    let hash = Multihash::<64>::wrap(SHA2_256, &hash_output)
-        .expect("Could not wrap SHA-256 hash in Multihash");
-
-    Cid::new_v1(0x55, hash) // 0x55 is the raw codec
+    CidBuilder::default()
+        .build(data)
+        .expect("Could not wrap SHA-256 hash in Multihash")
 }
 
 
 pub fn generate_leaves_from_file(file_path: &str) -> io::Result<Vec<MerkleNode>> {
+    let chunker = ChunkerType::default()
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    generate_leaves_from_file_with_chunker(file_path, chunker.as_ref())
+}
+
+pub fn generate_leaves_from_file_with_chunker(
+    file_path: &str,
+    chunker: &dyn Chunker,
+) -> io::Result<Vec<MerkleNode>> {
     let mut file = File::open(file_path)?;
-    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut buffer: Vec<u8> = Vec::with_capacity(chunker.max_size());
     let mut leaves = Vec::new();
-    while let Ok(bytes_read) = file.read(&mut buffer) {
-        if bytes_read == 0 {
-            break; 
+    let mut eof = false;
+    loop {
+        // keep a full max_size window buffered so the chunker sees every candidate cut point
+        while !eof && buffer.len() < chunker.max_size() {
+            let filled = buffer.len();
+            buffer.resize(chunker.max_size(), 0);
+            let bytes_read = file.read(&mut buffer[filled..])?;
+            buffer.truncate(filled + bytes_read);
+            eof = bytes_read == 0;
         }
-        let chunk = &buffer[..bytes_read];
-        let cid = generate_cid(chunk);
+        if buffer.is_empty() {
+            break;
+        }
+        let len = chunker.cut(&buffer);
+        let chunk: Vec<u8> = buffer.drain(..len).collect();
+        let cid = generate_cid(&chunk);
         let node:MerkleNode=MerkleNode{
             cid,
-            data:Some(chunk.to_vec()),
+            data:Some(chunk),
             links:vec![],
-            is_dup:false
+            unixfs:None,
+            metadata:None,
         };
         leaves.push(node);
     }
@@ -47,7 +61,9 @@ pub fn generate_leaves_from_file(file_path: &str) -> io::Result<Vec<MerkleNode>>
 mod tests {
     use super::*;
     use multihash::Multihash;
+    use sha2::{Digest, Sha256};
     use crate::storage::dag::generate_merkle_tree;
+    use crate::storage::metadata::FileMetadata;
     use std::fs::File;
     use std::io::{self, Read};
     #[test]
@@ -55,7 +71,7 @@ mod tests {
         const SHA2_256: u64 = 0x12;
         let data: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
         let hash: Multihash<64> =
-            Multihash::<64>::wrap(SHA2_256, &data).expect("Could not hash slice");
+            Multihash::<64>::wrap(SHA2_256, &Sha256::digest(data)).expect("Could not hash slice");
         let cid = Cid::new_v1(0x55, hash);
         assert_eq!(generate_cid(&data), cid);
     }
@@ -80,9 +96,9 @@ mod tests {
         let leaves = generate_leaves_from_file(file_path.to_str().unwrap())?;
     
         assert!(!leaves.is_empty(), "leaves list should not be empty");
-        assert!(leaves.len() >= 1, "At least one leaf should be generated");
+        assert!(!leaves.is_empty(), "At least one leaf should be generated");
     
-        let tree=generate_merkle_tree(leaves.clone(), "png").unwrap();
+        let tree=generate_merkle_tree(leaves.clone(), FileMetadata::default()).unwrap();
         for node in tree {
             print!("{:?}",node.data)
         }
@@ -108,12 +124,29 @@ mod tests {
             assert!(node.data.is_some(), "Generated node has no data");
         }
 
-        let tree=generate_merkle_tree(nodes, "md").unwrap();
+        let tree=generate_merkle_tree(nodes, FileMetadata::default()).unwrap();
         for x in tree {
             println!("{:?}",x.links);
         }
 
     
     }
+    #[test]
+    fn test_generate_leaves_with_chunker() {
+        use crate::cid::chunker::FastCdcChunker;
+        use std::io::Write;
+
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let mut temp_file = tempfile::NamedTempFile::new().unwrap();
+        temp_file.write_all(&content).unwrap();
+        let file_path = temp_file.path().to_str().unwrap();
+
+        let chunker = FastCdcChunker::new(1024, 4096, 16384).unwrap();
+        let leaves = generate_leaves_from_file_with_chunker(file_path, &chunker).unwrap();
+        assert!(leaves.len() > 1);
+
+        let rebuilt: Vec<u8> = leaves.into_iter().flat_map(|leaf| leaf.data.unwrap()).collect();
+        assert_eq!(rebuilt, content);
+    }
     
 }
@@ -1,7 +1,11 @@
+pub mod builder;
+pub mod chunker;
 pub mod generator;
-// pub mod resolver;
+pub mod resolver;
 
 pub use generator::generate_cid;
-// pub use resolver::resolve_cid;
+pub use resolver::resolve_cid;
 
-pub use generator::generate_leaves_from_file;
\ No newline at end of file
+pub use builder::{verify_cid, CidBuilder, HashAlgorithm};
+pub use chunker::{Chunker, ChunkerType};
+pub use generator::{generate_leaves_from_file, generate_leaves_from_file_with_chunker};
\ No newline at end of file
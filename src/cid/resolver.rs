@@ -0,0 +1,176 @@
+use crate::repo::Repo;
+use crate::storage::directory::{find_entry, DirectoryErrors};
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors, FjallBlockstore};
+use crate::storage::load::load_node_with_record;
+use crate::storage::metadata::{record_of, FileMetadata};
+use crate::storage::unixfs::DataType;
+use cid::Cid;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ResolverErrors {
+    #[error("The path is empty")]
+    EmptyPathError,
+    #[error("{0:?} is not a valid cid")]
+    InvalidCidError(String),
+    #[error(transparent)]
+    StorageError(#[from] BlockstoreErrors),
+    #[error("No entry at {0}")]
+    MissingSegmentError(String),
+    #[error("{0} is not a directory")]
+    NotADirectoryError(String),
+    #[error(transparent)]
+    DirectoryError(#[from] DirectoryErrors),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolved {
+    pub cid: Cid,
+    pub metadata: FileMetadata,
+}
+
+/*
+tldr; how it works
+splits "<cid>/a/b" (an /ipfs/ prefix and stray slashes are fine) and walks one named link
+per segment, each hop has to land on a directory (plain or sharded) to go any further.
+errors carry the path walked so far so the caller can tell which segment broke.
+*/
+pub async fn resolve_cid(path: &str, repo: &Repo) -> Result<Resolved, ResolverErrors> {
+    let store = FjallBlockstore::new(repo);
+    resolve_cid_with_handle(path, &store).await
+}
+
+pub async fn resolve_cid_with_handle(
+    path: &str,
+    store: &dyn Blockstore,
+) -> Result<Resolved, ResolverErrors> {
+    let path = path.trim_start_matches('/');
+    let path = path.strip_prefix("ipfs/").unwrap_or(path);
+    let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+    let root = segments.next().ok_or(ResolverErrors::EmptyPathError)?;
+    let cid = Cid::try_from(root).map_err(|_| ResolverErrors::InvalidCidError(root.to_string()))?;
+
+    let mut node = load_node_with_record(&cid, store).await?;
+    let mut walked = root.to_string();
+    for segment in segments {
+        let is_directory = matches!(
+            node.unixfs.as_ref().map(|unixfs| unixfs.data_type),
+            Some(DataType::Directory) | Some(DataType::HamtShard)
+        );
+        if !is_directory {
+            return Err(ResolverErrors::NotADirectoryError(walked));
+        }
+        walked = format!("{}/{}", walked, segment);
+        let link = find_entry(&node, segment, store)
+            .await?
+            .ok_or_else(|| ResolverErrors::MissingSegmentError(walked.clone()))?;
+        node = load_node_with_record(&link.cid, store).await?;
+    }
+
+    Ok(Resolved {
+        cid: node.cid,
+        metadata: record_of(&node),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::blockstore::{Cipher, EncryptedBlockstore, EncryptionKey, MemoryBlockstore};
+    use crate::cid::chunker::ChunkerType;
+    use crate::storage::directory::import_directory;
+    use crate::storage::import::ImportOptions;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_resolve_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemoryBlockstore::default();
+        let src = dir.path().join("site");
+        fs::create_dir_all(src.join("docs")).unwrap();
+        fs::write(src.join("docs/readme.md"), b"# readme\n").unwrap();
+        fs::write(src.join("index.html"), b"<html></html>").unwrap();
+        // sharded folders resolve the same way
+        fs::create_dir_all(src.join("many")).unwrap();
+        for i in 0..10 {
+            fs::write(src.join(format!("many/{}.txt", i)), format!("{}", i)).unwrap();
+        }
+        let options = ImportOptions {
+            chunker: ChunkerType::FixedSize(4),
+            shard_threshold: 5,
+            ..Default::default()
+        };
+        let root = import_directory(&src, &store, &options).await.unwrap();
+
+        let readme = resolve_cid_with_handle(&format!("{}/docs/readme.md", root), &store)
+            .await
+            .unwrap();
+        assert_eq!(readme.metadata.size, 9);
+        assert_eq!(readme.metadata.filename.as_deref(), Some("readme.md"));
+
+        let docs = resolve_cid_with_handle(&format!("/ipfs/{}/docs/", root), &store).await.unwrap();
+        assert_eq!(docs.metadata.filename.as_deref(), Some("docs"));
+        let same = resolve_cid_with_handle(&format!("{}/readme.md", docs.cid), &store)
+            .await
+            .unwrap();
+        assert_eq!(same, readme);
+
+        let seven = resolve_cid_with_handle(&format!("{}/many/7.txt", root), &store).await.unwrap();
+        assert_eq!(seven.metadata.size, 1);
+
+        let root_only = resolve_cid_with_handle(&root.to_string(), &store).await.unwrap();
+        assert_eq!(root_only.cid, root);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemoryBlockstore::default();
+        let src = dir.path().join("site");
+        fs::create_dir_all(src.join("docs")).unwrap();
+        fs::write(src.join("docs/readme.md"), b"# readme\n").unwrap();
+        let root = import_directory(&src, &store, &ImportOptions::default()).await.unwrap();
+
+        match resolve_cid_with_handle(&format!("{}/docs/missing.md", root), &store).await {
+            Err(ResolverErrors::MissingSegmentError(walked)) => {
+                assert_eq!(walked, format!("{}/docs/missing.md", root))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match resolve_cid_with_handle(&format!("{}/docs/readme.md/deeper", root), &store).await {
+            Err(ResolverErrors::NotADirectoryError(walked)) => {
+                assert_eq!(walked, format!("{}/docs/readme.md", root))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            resolve_cid_with_handle("/ipfs/", &store).await,
+            Err(ResolverErrors::EmptyPathError)
+        ));
+        assert!(matches!(
+            resolve_cid_with_handle("not-a-cid/docs", &store).await,
+            Err(ResolverErrors::InvalidCidError(_))
+        ));
+        let unknown = crate::cid::generate_cid(b"never stored");
+        assert!(matches!(
+            resolve_cid_with_handle(&unknown.to_string(), &store).await,
+            Err(ResolverErrors::StorageError(BlockstoreErrors::NotFoundError(_)))
+        ));
+
+        // a block that rotted, or a store that can not be read, is not a missing path
+        let rotted = crate::storage::create_leaf(b"fine once");
+        store.put_block(&rotted.cid, b"bit flipped").await.unwrap();
+        assert!(matches!(
+            resolve_cid_with_handle(&rotted.cid.to_string(), &store).await,
+            Err(ResolverErrors::StorageError(BlockstoreErrors::CorruptedError(_)))
+        ));
+        let inner: std::sync::Arc<dyn Blockstore> = std::sync::Arc::new(MemoryBlockstore::default());
+        let sealed = EncryptedBlockstore::new(inner.clone(), Cipher::default(), EncryptionKey::generate(), Vec::new());
+        sealed.put(&rotted).await.unwrap();
+        let wrong_key = EncryptedBlockstore::new(inner, Cipher::default(), EncryptionKey::generate(), Vec::new());
+        assert!(matches!(
+            resolve_cid_with_handle(&rotted.cid.to_string(), &wrong_key).await,
+            Err(ResolverErrors::StorageError(BlockstoreErrors::UnknownKeyError { .. }))
+        ));
+    }
+}
@@ -0,0 +1,5 @@
+pub mod node_config;
+
+pub use node_config::{
+    BlockstoreConfig, CacheConfig, EncryptionConfig, KeySource, NodeConfig, QuotaConfig, ScrubConfig,
+};
@@ -0,0 +1,460 @@
+use crate::cid::builder::verify_cid;
+use crate::constants::constants::{_DB_PATH, _QUARANTINE_PATH};
+use crate::repo::Repo;
+use crate::storage::blockstore::encrypted_store::KEY_LEN;
+use crate::storage::blockstore::flatfs_store::write_atomic;
+use crate::storage::blockstore::{
+    Blockstore, BlockstoreErrors, Cipher, EncryptedBlockstore, EncryptionKey, FjallBlockstore,
+    FlatfsBlockstore, MemoryBlockstore,
+};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+#[derive(Debug, Error)]
+pub enum ConfigErrors {
+    #[error("Error reading the config file: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Error parsing the config file: {0}")]
+    ParseError(#[from] serde_json::Error),
+    #[error("Error loading an encryption key: {0}")]
+    KeyError(String),
+    #[error("Error reading the block store: {0}")]
+    StoreError(#[from] BlockstoreErrors),
+}
+
+// kept in the repo folder, passphrase keys can not be derived again without it
+const SALT_FILE: &str = "keysalt";
+const SALT_LEN: usize = 16;
+
+/*
+tldr; how it works
+the node reads a small json file on start, anything left out falls back to the defaults
+and a missing file means all defaults (the repo under ./tmp/data, blocks in the repo). e.g.
+    { "repo_path": "./tmp/data", "blockstore": { "type": "flatfs", "path": "./tmp/blocks" } }
+fjall keeps the blocks in the repo's keyspace, flatfs in a folder of its own.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BlockstoreConfig {
+    #[default]
+    Fjall,
+    Flatfs { path: String },
+    Memory,
+}
+
+impl BlockstoreConfig {
+    pub fn open(&self, repo: &Repo) -> Result<Arc<dyn Blockstore>, BlockstoreErrors> {
+        Ok(match self {
+            BlockstoreConfig::Fjall => Arc::new(FjallBlockstore::new(repo)),
+            BlockstoreConfig::Flatfs { path } => Arc::new(FlatfsBlockstore::open(path)?),
+            BlockstoreConfig::Memory => Arc::new(MemoryBlockstore::default()),
+        })
+    }
+}
+
+/*
+tldr; how it works
+no storage_max, no limit. with one, block bytes are capped at storage_max (imports that
+would go over are refused), going over high_watermark (percent of storage_max) starts a
+gc that frees unpinned blocks down to low_watermark. e.g.
+    { "quota": { "storage_max": 10737418240, "high_watermark": 90, "low_watermark": 70 } }
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub storage_max: Option<u64>,
+    #[serde(default = "default_high_watermark")]
+    pub high_watermark: u8,
+    #[serde(default = "default_low_watermark")]
+    pub low_watermark: u8,
+}
+
+fn default_high_watermark() -> u8 {
+    90
+}
+
+fn default_low_watermark() -> u8 {
+    70
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            storage_max: None,
+            high_watermark: default_high_watermark(),
+            low_watermark: default_low_watermark(),
+        }
+    }
+}
+
+/*
+tldr; how it works
+no interval_secs, no background scrub. with one, every interval the next batch of blocks
+is rehashed and checked, corrupt ones are moved to quarantine_path when quarantine is set.
+the same folder is used by POST /verify, GET /verify only reports. e.g.
+    { "scrub": { "interval_secs": 60, "batch": 256, "quarantine": true } }
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScrubConfig {
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    #[serde(default = "default_scrub_batch")]
+    pub batch: usize,
+    #[serde(default)]
+    pub quarantine: bool,
+    #[serde(default = "default_quarantine_path")]
+    pub quarantine_path: String,
+}
+
+fn default_scrub_batch() -> usize {
+    256
+}
+
+fn default_quarantine_path() -> String {
+    _QUARANTINE_PATH.to_string()
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        ScrubConfig {
+            interval_secs: None,
+            batch: default_scrub_batch(),
+            quarantine: false,
+            quarantine_path: default_quarantine_path(),
+        }
+    }
+}
+
+/*
+tldr; how it works
+blocks read are kept in memory up to block_cache_size bytes, a bloom filter of
+bloom_filter_size bytes answers lookups for cids that are not stored without going to
+disk (it is filled on start, so a big store starts slower). 0 turns either off. e.g.
+    { "cache": { "block_cache_size": 268435456, "bloom_filter_size": 1048576 } }
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default = "default_block_cache_size")]
+    pub block_cache_size: u64,
+    #[serde(default)]
+    pub bloom_filter_size: u64,
+}
+
+fn default_block_cache_size() -> u64 {
+    64 << 20
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            block_cache_size: default_block_cache_size(),
+            bloom_filter_size: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    // a file holding the 32 key bytes, raw or as 64 hex digits
+    Keyfile(String),
+    // the environment variable holding a passphrase, the key is derived from it
+    PassphraseEnv(String),
+}
+
+impl KeySource {
+    pub fn load(&self, repo: &Repo) -> Result<EncryptionKey, ConfigErrors> {
+        match self {
+            // every copy of the key material is wiped when it is dropped
+            KeySource::Keyfile(path) => {
+                let raw = Zeroizing::new(std::fs::read(path)?);
+                let bytes = match raw.len() {
+                    KEY_LEN => raw,
+                    _ => {
+                        let hex = std::str::from_utf8(&raw).map_err(|_| {
+                            ConfigErrors::KeyError(format!("{} holds neither {} bytes nor their hex", path, KEY_LEN))
+                        })?;
+                        let bytes = hex::decode(hex.trim())
+                            .map_err(|err| ConfigErrors::KeyError(format!("{} is not valid hex: {}", path, err)))?;
+                        Zeroizing::new(bytes)
+                    }
+                };
+                if bytes.len() != KEY_LEN {
+                    return Err(ConfigErrors::KeyError(format!(
+                        "{} holds a {} byte key, keys are {} bytes",
+                        path,
+                        bytes.len(),
+                        KEY_LEN
+                    )));
+                }
+                let mut key = Zeroizing::new([0; KEY_LEN]);
+                key.copy_from_slice(&bytes);
+                Ok(EncryptionKey::new(*key))
+            }
+            KeySource::PassphraseEnv(name) => match std::env::var(name).map(Zeroizing::new) {
+                Ok(passphrase) if !passphrase.is_empty() => {
+                    Ok(EncryptionKey::from_passphrase(passphrase.as_bytes(), &salt(repo)?))
+                }
+                _ => Err(ConfigErrors::KeyError(format!("{} is not set", name))),
+            },
+        }
+    }
+}
+
+// made by create_salt when the store is opened, never here
+fn salt(repo: &Repo) -> Result<Vec<u8>, ConfigErrors> {
+    let path = repo.path().join(SALT_FILE);
+    let salt = match std::fs::read(&path) {
+        Ok(salt) => salt,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(ConfigErrors::KeyError(format!("{:?} is missing", path)))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if salt.len() != SALT_LEN {
+        return Err(ConfigErrors::KeyError(format!(
+            "{:?} holds {} bytes instead of {}, restore it from a backup of the repo",
+            path,
+            salt.len(),
+            SALT_LEN
+        )));
+    }
+    Ok(salt)
+}
+
+/*
+tldr; how it works
+a new salt derives another key from the same passphrase, so one is only made for a store
+with nothing sealed in it yet (the first start with encryption on). a store holding blocks
+that do not hash to their cid lost the salt its key came from, it is refused instead of
+coming up under a key that reads none of them. that check reads every block once, only
+when there is no salt. the salt is written to a temp file, fsynced and renamed into place,
+a crash never leaves half of one.
+*/
+async fn create_salt(repo: &Repo, store: &dyn Blockstore) -> Result<(), ConfigErrors> {
+    let path = repo.path().join(SALT_FILE);
+    if path.exists() {
+        return Ok(());
+    }
+    for cid in store.list().await? {
+        let sealed = match store.get_block_uncached(&cid).await? {
+            Some(block) => !verify_cid(&cid, &block).unwrap_or(false),
+            None => false,
+        };
+        if sealed {
+            return Err(ConfigErrors::KeyError(format!(
+                "{:?} is missing but the store holds encrypted blocks, restore it from a backup of the repo",
+                path
+            )));
+        }
+    }
+    let mut salt = [0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    write_atomic(repo.path(), &path, &salt)?;
+    Ok(())
+}
+
+/*
+tldr; how it works
+no encryption section, blocks and file records are stored as they are. with one, both are
+encrypted before they reach the blockstore with the key from a keyfile or from a
+passphrase (the salt it is stretched with is created in the repo folder, keysalt, and has
+to stay with the repo, a store holding encrypted blocks without it does not open).
+to rotate, put the new key in key and the old one in old_keys, the blocks are re-encrypted
+in the background, reencrypt_batch at a time every reencrypt_interval_secs. e.g.
+    { "encryption": { "cipher": "aes-256-gcm", "key": { "keyfile": "./secrets/block.key" },
+                      "old_keys": [{ "passphrase_env": "IPFS_OLD_PASSPHRASE" }] } }
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    #[serde(default)]
+    pub cipher: Cipher,
+    pub key: KeySource,
+    #[serde(default)]
+    pub old_keys: Vec<KeySource>,
+    #[serde(default = "default_reencrypt_batch")]
+    pub reencrypt_batch: usize,
+    #[serde(default = "default_reencrypt_interval_secs")]
+    pub reencrypt_interval_secs: u64,
+}
+
+fn default_reencrypt_batch() -> usize {
+    256
+}
+
+fn default_reencrypt_interval_secs() -> u64 {
+    1
+}
+
+impl EncryptionConfig {
+    pub async fn open(&self, inner: Arc<dyn Blockstore>, repo: &Repo) -> Result<EncryptedBlockstore, ConfigErrors> {
+        let mut sources = std::iter::once(&self.key).chain(&self.old_keys);
+        if sources.any(|source| matches!(source, KeySource::PassphraseEnv(_))) {
+            create_salt(repo, inner.as_ref()).await?;
+        }
+        let key = self.key.load(repo)?;
+        let old_keys = self
+            .old_keys
+            .iter()
+            .map(|source| source.load(repo))
+            .collect::<Result<_, _>>()?;
+        Ok(EncryptedBlockstore::new(inner, self.cipher, key, old_keys))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeConfig {
+    // the pin set and everything else kept on disk live here whatever the blockstore is
+    #[serde(default = "default_repo_path")]
+    pub repo_path: String,
+    #[serde(default)]
+    pub blockstore: BlockstoreConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub scrub: ScrubConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+}
+
+fn default_repo_path() -> String {
+    _DB_PATH.to_string()
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            repo_path: default_repo_path(),
+            blockstore: BlockstoreConfig::default(),
+            quota: QuotaConfig::default(),
+            scrub: ScrubConfig::default(),
+            cache: CacheConfig::default(),
+            encryption: None,
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigErrors> {
+        match std::fs::read(path) {
+            Ok(raw) => Ok(serde_json::from_slice(&raw)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(NodeConfig::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::create_leaf;
+
+    #[test]
+    fn test_load_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        assert_eq!(NodeConfig::load(&path).unwrap(), NodeConfig::default());
+
+        std::fs::write(&path, r#"{ "blockstore": { "type": "flatfs", "path": "./blocks" } }"#).unwrap();
+        assert_eq!(
+            NodeConfig::load(&path).unwrap().blockstore,
+            BlockstoreConfig::Flatfs {
+                path: "./blocks".to_string()
+            }
+        );
+
+        std::fs::write(&path, r#"{ "repo_path": "/srv/ipfs" }"#).unwrap();
+        let config = NodeConfig::load(&path).unwrap();
+        assert_eq!(config.repo_path, "/srv/ipfs");
+        assert_eq!(config.blockstore, BlockstoreConfig::Fjall);
+
+        std::fs::write(&path, r#"{ "quota": { "storage_max": 1024 } }"#).unwrap();
+        let quota = NodeConfig::load(&path).unwrap().quota;
+        assert_eq!(quota.storage_max, Some(1024));
+        assert_eq!(quota.high_watermark, 90);
+
+        std::fs::write(&path, r#"{ "cache": { "bloom_filter_size": 4096 } }"#).unwrap();
+        let cache = NodeConfig::load(&path).unwrap().cache;
+        assert_eq!((cache.block_cache_size, cache.bloom_filter_size), (64 << 20, 4096));
+
+        std::fs::write(&path, r#"{ "encryption": { "key": { "keyfile": "./block.key" } } }"#).unwrap();
+        let encryption = NodeConfig::load(&path).unwrap().encryption.unwrap();
+        assert_eq!(encryption.cipher, Cipher::XChaCha20Poly1305);
+        assert_eq!(encryption.key, KeySource::Keyfile("./block.key".to_string()));
+        assert!(encryption.old_keys.is_empty());
+
+        std::fs::write(&path, "{}").unwrap();
+        assert_eq!(NodeConfig::load(&path).unwrap(), NodeConfig::default());
+        std::fs::write(&path, r#"{ "blockstore": { "type": "tape" } }"#).unwrap();
+        assert!(matches!(NodeConfig::load(&path), Err(ConfigErrors::ParseError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_open_configured_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repo::open(dir.path().join("repo")).unwrap();
+        let config = BlockstoreConfig::Flatfs {
+            path: dir.path().join("blocks").to_str().unwrap().to_string(),
+        };
+        let store = config.open(&repo).unwrap();
+        let leaf = create_leaf(b"configured");
+        store.put(&leaf).await.unwrap();
+        assert!(config.open(&repo).unwrap().has(&leaf.cid).await.unwrap());
+        assert!(!BlockstoreConfig::Fjall.open(&repo).unwrap().has(&leaf.cid).await.unwrap());
+    }
+
+    #[test]
+    fn test_load_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repo::open(dir.path().join("repo")).unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        std::fs::write(path("raw.key"), [7; KEY_LEN]).unwrap();
+        std::fs::write(path("hex.key"), format!("{}\n", "07".repeat(KEY_LEN))).unwrap();
+        std::fs::write(path("short.key"), [7; 16]).unwrap();
+        std::fs::write(path("bad.key"), "not hex\n").unwrap();
+        let raw = KeySource::Keyfile(path("raw.key")).load(&repo).unwrap();
+        let hex = KeySource::Keyfile(path("hex.key")).load(&repo).unwrap();
+        assert_eq!(raw.id(), hex.id());
+        assert!(matches!(
+            KeySource::Keyfile(path("short.key")).load(&repo),
+            Err(ConfigErrors::KeyError(_))
+        ));
+        match KeySource::Keyfile(path("bad.key")).load(&repo) {
+            Err(ConfigErrors::KeyError(message)) => assert!(message.contains("not valid hex"), "{}", message),
+            other => panic!("unexpected {:?}", other.map(|key| key.id())),
+        }
+        assert!(matches!(
+            KeySource::PassphraseEnv("IPFS_RUST_TEST_UNSET".to_string()).load(&repo),
+            Err(ConfigErrors::KeyError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_salt_is_not_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repo::open(dir.path().join("repo")).unwrap();
+        let inner: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        std::env::set_var("IPFS_RUST_TEST_SALT_PASSPHRASE", "correct horse");
+        let config: EncryptionConfig =
+            serde_json::from_str(r#"{ "key": { "passphrase_env": "IPFS_RUST_TEST_SALT_PASSPHRASE" } }"#).unwrap();
+        // plaintext blocks from before are no reason to refuse
+        inner.put(&create_leaf(b"plain")).await.unwrap();
+        let store = config.open(inner.clone(), &repo).await.unwrap();
+        store.put(&create_leaf(b"sealed")).await.unwrap();
+        let salt_path = repo.path().join(SALT_FILE);
+        assert_eq!(std::fs::read(&salt_path).unwrap().len(), SALT_LEN);
+
+        std::fs::write(&salt_path, b"cut").unwrap();
+        assert!(matches!(config.open(inner.clone(), &repo).await, Err(ConfigErrors::KeyError(_))));
+        std::fs::remove_file(&salt_path).unwrap();
+        assert!(matches!(config.open(inner, &repo).await, Err(ConfigErrors::KeyError(_))));
+        assert!(!salt_path.exists());
+    }
+}
@@ -14,7 +14,8 @@ pub fn default_merkle_node() -> MerkleNode {
         cid: default_cid(),
         data: Some(_DEFAULTDATA.to_vec()),
         links: Vec::new(),
-        is_dup: false,
+        unixfs: None,
+        metadata: None,
     }
 }
 
@@ -26,4 +27,7 @@ pub const _MAX_FILE_COUNT: usize = 3;
 pub const _MAX_FILE_SIZE: u64 = 10 * 1024;
 pub const _LEGAL_FILE_TYPES: [Mime; 3] = [IMAGE_PNG, IMAGE_JPEG, IMAGE_GIF];
 pub const _UPLOAD_DIR: &str = "uploads/";
+pub const _DB_PATH: &str = "./tmp/data";
+pub const _QUARANTINE_PATH: &str = "./tmp/quarantine";
+pub const _CONFIG_PATH: &str = "./config.json";
 pub const _STREAMPROTOCOLNAME: &str = "/manaslibp2p/connection/1.0.0";
\ No newline at end of file
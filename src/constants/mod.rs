@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod constants;
 
 pub use constants::{default_cid,default_merkle_node,_UPLOAD_DIR,_MAX_FILE_SIZE,_STREAMPROTOCOLNAME};
@@ -1,6 +1,9 @@
 pub mod cid;
 pub mod storage;
 pub mod helpers;
+pub mod config;
 pub mod constants;
 pub mod network;
+pub mod pinning;
 
+pub mod repo;
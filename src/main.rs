@@ -1,14 +1,204 @@
 use actix_cors::Cors;
 use actix_multipart::form::MultipartFormConfig;
-use actix_web::{http::header, App, HttpServer};
-use ipfs_rust::constants::constants::_PORT;
+use actix_web::{http::header, web, App, HttpServer};
+use ipfs_rust::config::NodeConfig;
+use ipfs_rust::constants::constants::{_CONFIG_PATH, _PORT};
+use ipfs_rust::network::http_gateway::add::add;
+use ipfs_rust::network::http_gateway::block::block;
+use ipfs_rust::network::http_gateway::car;
+use ipfs_rust::network::http_gateway::cat::cat;
+use ipfs_rust::network::http_gateway::gc::gc;
 use ipfs_rust::network::http_gateway::health::greet;
+use ipfs_rust::network::http_gateway::pin;
+use ipfs_rust::network::http_gateway::resolve::resolve;
+use ipfs_rust::network::http_gateway::stats;
 use ipfs_rust::network::http_gateway::upload::upload;
-use paris::Logger;
+use ipfs_rust::network::http_gateway::verify::{quarantine_cid, quarantine_store, verify_cid, verify_store};
+use ipfs_rust::pinning::gc::gc_on_watermarks;
+use ipfs_rust::pinning::{pin_add, Pinset};
+use ipfs_rust::repo::{Repo, RepoErrors};
+use ipfs_rust::storage::blockstore::{
+    reencrypt_in_background, Blockstore, CachedBlockstore, EncryptedBlockstore, QuotaBlockstore,
+};
+use ipfs_rust::storage::car::{export_car, import_car, CarVersion};
+use ipfs_rust::storage::scrub::{scrub_in_background, ScrubOptions};
+use cid::Cid;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{BufReader, BufWriter};
+
+const USAGE: &str = "usage:
+    ipfs-rust                                   run the node
+    ipfs-rust car export <cid> <file> [--v2]    write the dag under cid to a car file
+    ipfs-rust car import <file> [--no-pin]      store the blocks of a car file and pin its roots";
+
+// the store to build on, and the encryption layer again when there is one (it rotates keys)
+type Stores = (Arc<dyn Blockstore>, Option<Arc<EncryptedBlockstore>>);
+
+// the configured store, behind the encryption layer when there is one
+async fn open_blockstore(config: &NodeConfig, repo: &Repo) -> Result<Stores, String> {
+    let store = config
+        .blockstore
+        .open(repo)
+        .map_err(|e| format!("Failed to open the block store: {}", e))?;
+    let Some(encryption) = &config.encryption else {
+        return Ok((store, None));
+    };
+    let encrypted = encryption.open(store, repo).await.map_err(|e| e.to_string())?;
+    let encrypted = Arc::new(encrypted);
+    Ok((encrypted.clone(), Some(encrypted)))
+}
+
+// what a one shot command works on, with the same quota the daemon enforces
+async fn open_store(config: &NodeConfig) -> Result<(Repo, Arc<dyn Blockstore>), String> {
+    let repo = match Repo::open(&config.repo_path) {
+        Ok(repo) => repo,
+        Err(e @ RepoErrors::LockedError(_)) => {
+            return Err(format!("{} (the daemon has it, use GET /car/<cid> and POST /car)", e))
+        }
+        Err(e) => return Err(format!("Failed to open the repo at {}: {}", config.repo_path, e)),
+    };
+    let (store, _) = open_blockstore(config, &repo).await?;
+    let store: Arc<dyn Blockstore> = match config.quota.storage_max {
+        Some(storage_max) => {
+            let quota = &config.quota;
+            let store = QuotaBlockstore::new(store, storage_max, quota.high_watermark, quota.low_watermark)
+                .await
+                .map_err(|e| format!("Failed to measure the block store: {}", e))?;
+            Arc::new(store)
+        }
+        None => store,
+    };
+    Ok((repo, store))
+}
+
+async fn run_command(args: &[String], config: &NodeConfig) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["car", "export", cid, path, ref flags @ ..] => {
+            let cid = Cid::try_from(cid).map_err(|e| format!("{:?} is not a valid cid: {}", cid, e))?;
+            let version = match flags.contains(&"--v2") {
+                true => CarVersion::V2,
+                false => CarVersion::V1,
+            };
+            let (_repo, store) = open_store(config).await?;
+            // before the file is created, not after
+            if !store.has(&cid).await.map_err(|e| e.to_string())? {
+                return Err(format!("{} is not stored here", cid));
+            }
+            let file = tokio::fs::File::create(path).await.map_err(|e| format!("{}: {}", path, e))?;
+            let written = export_car(&cid, store.as_ref(), version, &mut BufWriter::new(file))
+                .await
+                .map_err(|e| e.to_string())?;
+            println!("Wrote {} bytes to {}", written, path);
+        }
+        ["car", "import", path, ref flags @ ..] => {
+            let (repo, store) = open_store(config).await?;
+            let file = tokio::fs::File::open(path).await.map_err(|e| format!("{}: {}", path, e))?;
+            let imported = import_car(BufReader::new(file), store.as_ref())
+                .await
+                .map_err(|e| e.to_string())?;
+            if !flags.contains(&"--no-pin") {
+                let pins = Pinset::new(&repo);
+                for root in &imported.roots {
+                    pin_add(root, true, &pins, store.as_ref())
+                        .await
+                        .map_err(|e| format!("Failed to pin {}: {}", root, e))?;
+                }
+            }
+            println!("Imported {} blocks", imported.blocks);
+            for root in &imported.roots {
+                println!("root {}", root);
+            }
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    Ok(())
+}
+
 #[actix_web::main]
 pub async fn main() {
+    let config = match NodeConfig::load(_CONFIG_PATH) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load {}: {:?}", _CONFIG_PATH, e);
+            return;
+        }
+    };
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = run_command(&args, &config).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    // held until the server stops, a second daemon on the same repo is turned away here
+    let repo = match Repo::open(&config.repo_path) {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!("Failed to open the repo at {}: {}", config.repo_path, e);
+            return;
+        }
+    };
+    let (store, encrypted) = match open_blockstore(&config, &repo).await {
+        Ok(stores) => stores,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let pins = Pinset::new(&repo);
+    if let (Some(encrypted), Some(encryption)) = (encrypted, &config.encryption) {
+        let interval = Duration::from_secs(encryption.reencrypt_interval_secs);
+        let rotation = reencrypt_in_background(encrypted, pins.clone(), encryption.reencrypt_batch, interval);
+        actix_web::rt::spawn(rotation);
+    }
+    // under the quota store, so blocks gc deletes through it also leave the cache
+    let cache = CachedBlockstore::new(store, config.cache.block_cache_size, config.cache.bloom_filter_size);
+    let cache = match cache.await {
+        Ok(cache) => Arc::new(cache),
+        Err(e) => {
+            eprintln!("Failed to fill the bloom filter: {:?}", e);
+            return;
+        }
+    };
+    let store: Arc<dyn Blockstore> = cache.clone();
+    let store: Arc<dyn Blockstore> = match config.quota.storage_max {
+        Some(storage_max) => {
+            let quota = &config.quota;
+            let store = QuotaBlockstore::new(store, storage_max, quota.high_watermark, quota.low_watermark);
+            let store = match store.await {
+                Ok(store) => Arc::new(store),
+                Err(e) => {
+                    eprintln!("Failed to measure the block store: {:?}", e);
+                    return;
+                }
+            };
+            actix_web::rt::spawn(gc_on_watermarks(store.clone(), pins.clone()));
+            store
+        }
+        None => store,
+    };
+    let quarantine = ScrubOptions {
+        quarantine: Some(config.scrub.quarantine_path.clone().into()),
+    };
+    if let Some(interval) = config.scrub.interval_secs {
+        let options = match config.scrub.quarantine {
+            true => quarantine.clone(),
+            false => ScrubOptions::default(),
+        };
+        let interval = Duration::from_secs(interval);
+        let scrub = scrub_in_background(store.clone(), pins.clone(), options, config.scrub.batch, interval);
+        actix_web::rt::spawn(scrub);
+    }
+    let quarantine = web::Data::new(quarantine);
+    let pins = web::Data::new(pins);
+    // one handle for every worker, opening the keyspace per request would fight over it
+    let store = web::Data::from(store);
+    let cache = web::Data::from(cache);
     //defining and spinning up the http server
-    let server = HttpServer::new(|| {
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allowed_origin("http://127.0.0.1:5500")
             .allowed_methods(vec!["GET", "POST"])
@@ -21,9 +211,29 @@ pub async fn main() {
                     .total_limit(10 * 1024) // 10 KB
                     .memory_limit(10 * 1024), // 10 KB
             )
+            .app_data(store.clone())
+            .app_data(pins.clone())
+            .app_data(quarantine.clone())
+            .app_data(cache.clone())
             .wrap(cors)
             .service(upload)
+            .service(add)
             .service(greet)
+            .service(resolve)
+            .service(block)
+            .service(car::export)
+            .service(car::import)
+            .service(cat)
+            .service(pin::add)
+            .service(pin::rm)
+            .service(pin::ls)
+            .service(pin::verify)
+            .service(gc)
+            .service(verify_store)
+            .service(verify_cid)
+            .service(quarantine_store)
+            .service(quarantine_cid)
+            .service(stats::cache)
     })
     .bind(("127.0.0.1", _PORT));
     match server {
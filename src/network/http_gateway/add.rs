@@ -0,0 +1,45 @@
+use crate::pinning::pin::{pin_add, Pinset};
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::import::{import_reader, ImportErrors, ImportOptions};
+use actix_web::{web, HttpResponse};
+use futures_util::TryStreamExt as _;
+use serde::Deserialize;
+use tokio_util::io::StreamReader;
+
+#[derive(Debug, Deserialize)]
+pub struct AddQuery {
+    #[serde(default = "super::pin_default")]
+    pin: bool,
+}
+
+fn import_error(err: ImportErrors) -> actix_web::error::Error {
+    match err {
+        ImportErrors::StorageError(err @ BlockstoreErrors::QuotaExceededError { .. }) => {
+            actix_web::error::ErrorInsufficientStorage(err.to_string())
+        }
+        ImportErrors::IoError(err) => actix_web::error::ErrorBadRequest(err.to_string()),
+        err => actix_web::error::ErrorInternalServerError(err.to_string()),
+    }
+}
+
+// POST /add?pin=false with the file as the request body -> {cid}, 507 when it does not fit
+#[actix_web::post("/add")]
+pub async fn add(
+    payload: web::Payload,
+    query: web::Query<AddQuery>,
+    pins: web::Data<Pinset>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let reader = StreamReader::new(payload.map_err(std::io::Error::other));
+    // gc must not run between storing the dag and pinning it
+    let _guard = pins.pin_lock().await;
+    let cid = import_reader(reader, store.get_ref(), &ImportOptions::default())
+        .await
+        .map_err(import_error)?;
+    if query.pin {
+        pin_add(&cid, true, &pins, store.get_ref())
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "cid": cid.to_string() })))
+}
@@ -0,0 +1,19 @@
+use crate::storage::blockstore::Blockstore;
+use actix_web::{web, HttpResponse};
+use cid::Cid;
+
+// GET /block/<cid> -> the block exactly as stored, the bytes its cid hashes
+#[actix_web::get("/block/{cid}")]
+pub async fn block(
+    cid: web::Path<String>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let cid = Cid::try_from(cid.as_str()).map_err(actix_web::error::ErrorBadRequest)?;
+    match store.get_block(&cid).await {
+        Ok(Some(bytes)) => Ok(HttpResponse::Ok()
+            .content_type("application/vnd.ipld.raw")
+            .body(bytes)),
+        Ok(None) => Err(actix_web::error::ErrorNotFound(format!("{} is not stored here", cid))),
+        Err(err) => Err(actix_web::error::ErrorInternalServerError(err.to_string())),
+    }
+}
@@ -0,0 +1,89 @@
+use crate::pinning::pin::{pin_add, PinErrors, Pinset};
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::car::{export_car, import_car, CarErrors, CarVersion};
+use actix_web::{web, HttpResponse};
+use cid::Cid;
+use futures_util::TryStreamExt as _;
+use serde::Deserialize;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default = "version_default")]
+    version: u64,
+}
+
+fn version_default() -> u64 {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    // the roots, recursively
+    #[serde(default = "super::pin_default")]
+    pin: bool,
+}
+
+fn car_error(err: CarErrors) -> actix_web::error::Error {
+    match err {
+        CarErrors::StorageError(err @ BlockstoreErrors::QuotaExceededError { .. }) => {
+            actix_web::error::ErrorInsufficientStorage(err.to_string())
+        }
+        CarErrors::StorageError(err) => actix_web::error::ErrorInternalServerError(err.to_string()),
+        err => actix_web::error::ErrorBadRequest(err.to_string()),
+    }
+}
+
+// GET /car/<cid>?version=2 -> the whole dag under cid as a car, streamed as it is written
+#[actix_web::get("/car/{cid}")]
+pub async fn export(
+    cid: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let cid = Cid::try_from(cid.as_str()).map_err(actix_web::error::ErrorBadRequest)?;
+    let version = CarVersion::try_from(query.version).map_err(actix_web::error::ErrorBadRequest)?;
+    match store.has(&cid).await {
+        Ok(true) => {}
+        Ok(false) => return Err(actix_web::error::ErrorNotFound(format!("{} is not stored here", cid))),
+        Err(err) => return Err(actix_web::error::ErrorInternalServerError(err.to_string())),
+    }
+    let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+    let store = store.into_inner();
+    // the status is already out by the time a block turns up missing, the car just ends early
+    actix_web::rt::spawn(async move {
+        if let Err(err) = export_car(&cid, store.as_ref(), version, &mut writer).await {
+            eprintln!("Exporting {} stopped: {}", cid, err);
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type(format!("application/vnd.ipld.car; version={}", query.version))
+        .streaming(ReaderStream::new(reader)))
+}
+
+// POST /car?pin=false with a carv1 or carv2 as the body -> {roots: [cid], blocks}
+#[actix_web::post("/car")]
+pub async fn import(
+    payload: web::Payload,
+    query: web::Query<ImportQuery>,
+    pins: web::Data<Pinset>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let reader = StreamReader::new(payload.map_err(std::io::Error::other));
+    // gc must not run between storing the blocks and pinning the roots
+    let _guard = pins.pin_lock().await;
+    let imported = import_car(reader, store.get_ref()).await.map_err(car_error)?;
+    if query.pin {
+        for root in &imported.roots {
+            // a car that does not hold its whole dag can not be pinned
+            pin_add(root, true, &pins, store.get_ref())
+                .await
+                .map_err(|err| match err {
+                    PinErrors::NotFoundError(_) => actix_web::error::ErrorBadRequest(err.to_string()),
+                    err => actix_web::error::ErrorInternalServerError(err.to_string()),
+                })?;
+        }
+    }
+    let roots: Vec<String> = imported.roots.iter().map(Cid::to_string).collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "roots": roots, "blocks": imported.blocks })))
+}
@@ -0,0 +1,59 @@
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::cat::{cat as cat_file, CatErrors};
+use actix_web::http::header::{self, Header, Range};
+use actix_web::{web, HttpRequest, HttpResponse};
+use cid::Cid;
+use std::io::SeekFrom;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+// GET /cat/<cid> -> the file's bytes, streamed as they are read from the store.
+// a single "Range: bytes=..." gets a 206 with just that part (video, resumed downloads),
+// several ranges are answered with the whole file
+#[actix_web::get("/cat/{cid}")]
+pub async fn cat(
+    req: HttpRequest,
+    cid: web::Path<String>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let cid = Cid::try_from(cid.as_str()).map_err(actix_web::error::ErrorBadRequest)?;
+    let mut reader = match cat_file(&cid, store.into_inner()).await {
+        Ok(reader) => reader,
+        Err(err @ (CatErrors::StorageError(BlockstoreErrors::NotFoundError(_)) | CatErrors::NotAFileError(_))) => {
+            return Err(actix_web::error::ErrorNotFound(err.to_string()))
+        }
+        Err(err) => return Err(actix_web::error::ErrorInternalServerError(err.to_string())),
+    };
+    let size = reader.size();
+    let mime_type = reader
+        .root()
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.mime_type.clone())
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    let range = match Range::parse(&req) {
+        Ok(Range::Bytes(specs)) if specs.len() == 1 => Some(specs[0].to_satisfiable_range(size)),
+        _ => None,
+    };
+    match range {
+        None => Ok(HttpResponse::Ok()
+            .content_type(mime_type)
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .no_chunking(size)
+            .streaming(ReaderStream::new(reader))),
+        Some(None) => Ok(HttpResponse::RangeNotSatisfiable()
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+            .finish()),
+        Some(Some((start, end))) => {
+            reader.seek(SeekFrom::Start(start)).await?;
+            let len = end - start + 1;
+            Ok(HttpResponse::PartialContent()
+                .content_type(mime_type)
+                .insert_header((header::ACCEPT_RANGES, "bytes"))
+                .insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size)))
+                .no_chunking(len)
+                .streaming(ReaderStream::new(reader.take(len))))
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use crate::pinning::gc::collect_garbage;
+use crate::pinning::pin::Pinset;
+use crate::storage::blockstore::Blockstore;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct GcQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+// POST /gc?dry_run=true -> {removed, reclaimed_bytes, dry_run}
+#[actix_web::post("/gc")]
+pub async fn gc(
+    query: web::Query<GcQuery>,
+    pins: web::Data<Pinset>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    match collect_garbage(&pins, store.get_ref(), &[], query.dry_run).await {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(err) => Err(actix_web::error::ErrorInternalServerError(err.to_string())),
+    }
+}
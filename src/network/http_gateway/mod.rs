@@ -1,2 +1,17 @@
+pub mod add;
+pub mod block;
+pub mod car;
+pub mod cat;
+pub mod gc;
 pub mod health;
-pub mod upload;
\ No newline at end of file
+pub mod pin;
+pub mod resolve;
+pub mod stats;
+pub mod upload;
+pub mod verify;
+
+// what pin and recursive default to when a query leaves them out: a whole dag gets
+// pinned unless asked otherwise, like kubo
+fn pin_default() -> bool {
+    true
+}
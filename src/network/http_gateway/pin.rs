@@ -0,0 +1,82 @@
+use crate::pinning::pin::{pin_add, pin_ls, pin_rm, pin_verify, PinErrors, PinMode, Pinset};
+use crate::storage::blockstore::Blockstore;
+use actix_web::{web, HttpResponse};
+use cid::Cid;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct PinAddQuery {
+    #[serde(default = "super::pin_default")]
+    recursive: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PinLsQuery {
+    // direct, recursive or indirect, everything when left out
+    #[serde(rename = "type")]
+    mode: Option<PinMode>,
+}
+
+fn pin_error(err: PinErrors) -> actix_web::error::Error {
+    match err {
+        PinErrors::NotFoundError(_) | PinErrors::NotPinnedError(_) => {
+            actix_web::error::ErrorNotFound(err.to_string())
+        }
+        PinErrors::IndirectPinError(_) => actix_web::error::ErrorConflict(err.to_string()),
+        err => actix_web::error::ErrorInternalServerError(err.to_string()),
+    }
+}
+
+fn parse_cid(cid: &str) -> Result<Cid, actix_web::error::Error> {
+    Cid::try_from(cid).map_err(actix_web::error::ErrorBadRequest)
+}
+
+// POST /pin/add/<cid>?recursive=false -> pins content that is already stored here
+#[actix_web::post("/pin/add/{cid}")]
+pub async fn add(
+    cid: web::Path<String>,
+    query: web::Query<PinAddQuery>,
+    pins: web::Data<Pinset>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let cid = parse_cid(&cid)?;
+    // a gc running meanwhile could sweep blocks the walk has already seen
+    let _guard = pins.pin_lock().await;
+    pin_add(&cid, query.recursive, &pins, store.get_ref())
+        .await
+        .map_err(pin_error)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "pins": [cid.to_string()] })))
+}
+
+// POST /pin/rm/<cid>
+#[actix_web::post("/pin/rm/{cid}")]
+pub async fn rm(
+    cid: web::Path<String>,
+    pins: web::Data<Pinset>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let cid = parse_cid(&cid)?;
+    pin_rm(&cid, &pins, store.get_ref()).await.map_err(pin_error)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "pins": [cid.to_string()] })))
+}
+
+// GET /pin/ls?type=recursive -> [{cid, mode}]
+#[actix_web::get("/pin/ls")]
+pub async fn ls(
+    query: web::Query<PinLsQuery>,
+    pins: web::Data<Pinset>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let listed = pin_ls(query.mode, &pins, store.get_ref()).await.map_err(pin_error)?;
+    Ok(HttpResponse::Ok().json(listed))
+}
+
+// GET /pin/verify -> [{cid, mode, bad_nodes}] for every direct and recursive pin
+#[actix_web::get("/pin/verify")]
+pub async fn verify(
+    pins: web::Data<Pinset>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let statuses = pin_verify(&pins, store.get_ref()).await.map_err(pin_error)?;
+    Ok(HttpResponse::Ok().json(statuses))
+}
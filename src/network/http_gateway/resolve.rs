@@ -0,0 +1,33 @@
+use crate::cid::resolver::{resolve_cid_with_handle, ResolverErrors};
+use actix_web::{web, HttpResponse};
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::directory::DirectoryErrors;
+use crate::storage::hamt::HamtErrors;
+
+// GET /resolve/<cid>/sub/path -> the target's cid and file record
+#[actix_web::get("/resolve/{path:.*}")]
+pub async fn resolve(
+    path: web::Path<String>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    match resolve_cid_with_handle(&path.into_inner(), store.get_ref()).await {
+        Ok(resolved) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "cid": resolved.cid.to_string(),
+            "metadata": resolved.metadata,
+        }))),
+        Err(err @ (ResolverErrors::EmptyPathError | ResolverErrors::InvalidCidError(_))) => {
+            Err(actix_web::error::ErrorBadRequest(err.to_string()))
+        }
+        Err(
+            err @ (ResolverErrors::StorageError(BlockstoreErrors::NotFoundError(_))
+            | ResolverErrors::MissingSegmentError(_)
+            | ResolverErrors::NotADirectoryError(_)
+            | ResolverErrors::DirectoryError(
+                DirectoryErrors::StorageError(BlockstoreErrors::NotFoundError(_))
+                | DirectoryErrors::HamtError(HamtErrors::StorageError(BlockstoreErrors::NotFoundError(_))),
+            )),
+        ) => Err(actix_web::error::ErrorNotFound(err.to_string())),
+        // a store that fails or a block that went bad is not "no such path"
+        Err(err) => Err(actix_web::error::ErrorInternalServerError(err.to_string())),
+    }
+}
@@ -0,0 +1,8 @@
+use crate::storage::blockstore::CachedBlockstore;
+use actix_web::{web, HttpResponse};
+
+// GET /stats/cache -> {hits, misses, bloom_negatives, bloom_false_positives, cached_blocks, cached_bytes}
+#[actix_web::get("/stats/cache")]
+pub async fn cache(cache: web::Data<CachedBlockstore>) -> HttpResponse {
+    HttpResponse::Ok().json(cache.stats())
+}
@@ -29,7 +29,7 @@ pub async fn upload(
                     return Err(actix_web::error::ErrorBadRequest("File size exceeds limit".to_string()));
                 }
 
-                let _ = file.write_all(&chunk).await?;
+                file.write_all(&chunk).await?;
             }
         }
     }
@@ -0,0 +1,74 @@
+use crate::pinning::pin::Pinset;
+use crate::storage::blockstore::Blockstore;
+use crate::storage::scrub::{verify_all, verify_dag, ScrubErrors, ScrubOptions};
+use actix_web::{web, HttpResponse};
+use cid::Cid;
+use std::collections::BTreeSet;
+
+async fn store_report(
+    pins: &Pinset,
+    store: &dyn Blockstore,
+    options: &ScrubOptions,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let roots: BTreeSet<Cid> = pins
+        .list()
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .into_iter()
+        .map(|pin| pin.cid)
+        .collect();
+    match verify_all(store, &roots, options).await {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(err) => Err(actix_web::error::ErrorInternalServerError(err.to_string())),
+    }
+}
+
+async fn dag_report(
+    cid: &str,
+    store: &dyn Blockstore,
+    options: &ScrubOptions,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let cid = Cid::try_from(cid).map_err(actix_web::error::ErrorBadRequest)?;
+    match verify_dag(&cid, store, options).await {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(err @ ScrubErrors::NotFoundError(_)) => Err(actix_web::error::ErrorNotFound(err.to_string())),
+        Err(err) => Err(actix_web::error::ErrorInternalServerError(err.to_string())),
+    }
+}
+
+// GET /verify -> a report over every stored block, pins count as roots. nothing is moved
+#[actix_web::get("/verify")]
+pub async fn verify_store(
+    pins: web::Data<Pinset>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    store_report(&pins, store.get_ref(), &ScrubOptions::default()).await
+}
+
+// GET /verify/<cid> -> a report over one dag
+#[actix_web::get("/verify/{cid}")]
+pub async fn verify_cid(
+    cid: web::Path<String>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    dag_report(&cid, store.get_ref(), &ScrubOptions::default()).await
+}
+
+// POST /verify -> the same report, corrupt blocks are moved to the configured quarantine folder
+#[actix_web::post("/verify")]
+pub async fn quarantine_store(
+    configured: web::Data<ScrubOptions>,
+    pins: web::Data<Pinset>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    store_report(&pins, store.get_ref(), &configured).await
+}
+
+// POST /verify/<cid> -> the same for one dag
+#[actix_web::post("/verify/{cid}")]
+pub async fn quarantine_cid(
+    cid: web::Path<String>,
+    configured: web::Data<ScrubOptions>,
+    store: web::Data<dyn Blockstore>,
+) -> Result<HttpResponse, actix_web::error::Error> {
+    dag_report(&cid, store.get_ref(), &configured).await
+}
@@ -0,0 +1,224 @@
+use crate::pinning::pin::{descendants, PinErrors, PinMode, Pinset};
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors, QuotaBlockstore};
+use cid::Cid;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum GcErrors {
+    #[error("Error reading the pin set: {0}")]
+    PinError(#[from] PinErrors),
+    #[error("Error talking to the store: {0}")]
+    StorageError(#[from] BlockstoreErrors),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GcReport {
+    // what was deleted, or on a dry run what would be
+    pub removed: Vec<Cid>,
+    pub reclaimed_bytes: u64,
+    pub dry_run: bool,
+}
+
+/*
+tldr; how it works
+mark: every direct pin, every recursive pin and everything below it, plus the dags of
+any extra roots the caller keeps outside the pin set (an mfs root once there is one).
+sweep: every stored block that was not marked is deleted, block and record.
+if a block under a recursive pin is missing or does not decode the whole run stops
+before deleting anything, a mark that could not see all children would sweep them.
+the exclusive gc lock is held from mark to the end of sweep, imports holding the pin
+lock finish (and pin) first and new ones wait for the sweep to end.
+a dry run marks and adds up the same way but leaves the store alone.
+*/
+pub async fn collect_garbage(
+    pins: &Pinset,
+    store: &dyn Blockstore,
+    extra_roots: &[Cid],
+    dry_run: bool,
+) -> Result<GcReport, GcErrors> {
+    mark_and_sweep(pins, store, extra_roots, dry_run, None).await
+}
+
+// the same run, but the sweep stops as soon as at least `bytes` have been freed
+pub async fn reclaim(pins: &Pinset, store: &dyn Blockstore, bytes: u64) -> Result<GcReport, GcErrors> {
+    mark_and_sweep(pins, store, &[], false, Some(bytes)).await
+}
+
+async fn mark_and_sweep(
+    pins: &Pinset,
+    store: &dyn Blockstore,
+    extra_roots: &[Cid],
+    dry_run: bool,
+    budget: Option<u64>,
+) -> Result<GcReport, GcErrors> {
+    let _guard = pins.gc_lock().await;
+
+    let mut marked: BTreeSet<Cid> = BTreeSet::new();
+    let roots = pins
+        .list()?
+        .into_iter()
+        .map(|pin| (pin.cid, pin.mode))
+        .chain(extra_roots.iter().map(|cid| (*cid, PinMode::Recursive)));
+    for (cid, mode) in roots {
+        marked.insert(cid);
+        if mode == PinMode::Recursive {
+            marked.extend(descendants(&cid, store).await?);
+        }
+    }
+
+    let mut report = GcReport {
+        dry_run,
+        ..Default::default()
+    };
+    for cid in store.list().await? {
+        if budget.is_some_and(|budget| report.reclaimed_bytes >= budget) {
+            break;
+        }
+        if marked.contains(&cid) {
+            continue;
+        }
+        // as stored, the unit the quota counts in, and without reading the block
+        report.reclaimed_bytes += store.block_size(&cid).await?.unwrap_or(0);
+        if !dry_run {
+            store.delete(&cid).await?;
+        }
+        report.removed.push(cid);
+    }
+    Ok(report)
+}
+
+/*
+tldr; how it works
+runs for the life of the node next to a quota store: every time usage goes over the high
+watermark it reclaims down to the low one. when everything left is pinned there is
+nothing to free, that is logged and the node keeps going (new imports hit the hard limit).
+*/
+pub async fn gc_on_watermarks(store: Arc<QuotaBlockstore>, pins: Pinset) {
+    loop {
+        store.high_watermark_crossed().await;
+        let excess = store.usage().saturating_sub(store.low_watermark());
+        match reclaim(&pins, store.as_ref(), excess).await {
+            Ok(report) => println!(
+                "Storage over the high watermark, gc freed {} bytes in {} blocks",
+                report.reclaimed_bytes,
+                report.removed.len()
+            ),
+            Err(err) => eprintln!("Automatic gc failed: {:?}", err),
+        }
+        if store.usage() > store.low_watermark() {
+            eprintln!(
+                "Storage still at {} bytes after gc, over the low watermark of {}",
+                store.usage(),
+                store.low_watermark()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pinning::pin::pin_add;
+    use crate::repo::Repo;
+    use crate::storage::blockstore::MemoryBlockstore;
+    use crate::storage::import::file_dag;
+    use crate::storage::create_leaf;
+
+    #[tokio::test]
+    async fn test_sweeps_unpinned_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let pins = Pinset::new(&Repo::open(dir.path()).unwrap());
+        let store = MemoryBlockstore::default();
+        let kept = file_dag(&store, b"kept around").await;
+        let direct = create_leaf(b"direct");
+        store.put(&direct).await.unwrap();
+        let garbage = file_dag(&store, b"nobody wants this").await;
+        pin_add(&kept, true, &pins, &store).await.unwrap();
+        pin_add(&direct.cid, false, &pins, &store).await.unwrap();
+        let before = store.list().await.unwrap().len();
+
+        let dry = collect_garbage(&pins, &store, &[], true).await.unwrap();
+        assert!(dry.removed.contains(&garbage));
+        // 17 bytes of leaves plus the root
+        assert!(dry.reclaimed_bytes > 17);
+        assert_eq!(store.list().await.unwrap().len(), before);
+
+        let report = collect_garbage(&pins, &store, &[], false).await.unwrap();
+        assert_eq!(report.removed.len(), dry.removed.len());
+        assert_eq!(report.reclaimed_bytes, dry.reclaimed_bytes);
+        assert!(!store.has(&garbage).await.unwrap());
+        assert!(store.has(&direct.cid).await.unwrap());
+        for cid in descendants(&kept, &store).await.unwrap() {
+            assert!(store.has(&cid).await.unwrap());
+        }
+        // nothing left to collect
+        assert!(collect_garbage(&pins, &store, &[], false).await.unwrap().removed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_extra_roots_and_incomplete_pins() {
+        let dir = tempfile::tempdir().unwrap();
+        let pins = Pinset::new(&Repo::open(dir.path()).unwrap());
+        let store = MemoryBlockstore::default();
+        let root = file_dag(&store, b"an mfs style root").await;
+        assert!(collect_garbage(&pins, &store, &[root], false).await.unwrap().removed.is_empty());
+
+        pin_add(&root, true, &pins, &store).await.unwrap();
+        let child = store.get(&root).await.unwrap().unwrap().links[0].cid;
+        store.delete(&child).await.unwrap();
+        let stray = create_leaf(b"stray");
+        store.put(&stray).await.unwrap();
+        // a pinned dag with a hole stops the run before anything is deleted
+        assert!(collect_garbage(&pins, &store, &[], false).await.is_err());
+        assert!(store.has(&stray.cid).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_waits_for_pending_imports() {
+        let dir = tempfile::tempdir().unwrap();
+        let pins = Pinset::new(&Repo::open(dir.path()).unwrap());
+        let store = std::sync::Arc::new(MemoryBlockstore::default());
+
+        let guard = pins.pin_lock().await;
+        let root = file_dag(store.as_ref(), b"added, not pinned yet").await;
+        let gc = {
+            let (pins, store) = (pins.clone(), store.clone());
+            tokio::spawn(async move { collect_garbage(&pins, store.as_ref(), &[], false).await })
+        };
+        tokio::task::yield_now().await;
+        assert!(!gc.is_finished());
+        pin_add(&root, true, &pins, store.as_ref()).await.unwrap();
+        drop(guard);
+
+        assert!(gc.await.unwrap().unwrap().removed.is_empty());
+        assert!(store.has(&root).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_gc_on_watermarks() {
+        let dir = tempfile::tempdir().unwrap();
+        let pins = Pinset::new(&Repo::open(dir.path()).unwrap());
+        let inner: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        let store = Arc::new(QuotaBlockstore::new(inner, 1000, 50, 20).await.unwrap());
+        let pinned = create_leaf(&[1; 100]);
+        store.put(&pinned).await.unwrap();
+        pin_add(&pinned.cid, false, &pins, store.as_ref()).await.unwrap();
+        tokio::spawn(gc_on_watermarks(store.clone(), pins.clone()));
+
+        // 100 pinned + 5 * 100 unpinned goes over 500
+        let loose: Vec<_> = (2..7).map(|byte| create_leaf(&[byte; 100])).collect();
+        store.put_many(&loose).await.unwrap();
+        for _ in 0..100 {
+            if store.usage() <= 200 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        // back under the low watermark, the pinned block untouched
+        assert_eq!(store.usage(), 200);
+        assert!(store.has(&pinned.cid).await.unwrap());
+    }
+}
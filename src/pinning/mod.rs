@@ -0,0 +1,5 @@
+pub mod gc;
+pub mod pin;
+
+pub use gc::{collect_garbage, GcReport};
+pub use pin::{pin_add, pin_ls, pin_rm, pin_verify, PinMode, Pinset};
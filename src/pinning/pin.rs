@@ -0,0 +1,377 @@
+use crate::cid::builder::verify_cid;
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::MerkleNode;
+use cid::Cid;
+use crate::repo::Repo;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PinErrors {
+    #[error("Error talking to the pin set: {0}")]
+    StorageError(#[from] fjall::Error),
+    #[error("Error reading a pinned block: {0}")]
+    BlockstoreError(#[from] BlockstoreErrors),
+    #[error("The block {0} is not stored here")]
+    NotFoundError(Cid),
+    #[error("{0} is not pinned")]
+    NotPinnedError(Cid),
+    // holds the recursive pin it is under, the caller knows which cid it asked about
+    #[error("The block is pinned indirectly under {0}")]
+    IndirectPinError(Cid),
+    #[error("Unknown pin mode {0:?} in the pin set")]
+    InvalidModeError(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PinMode {
+    // only computed, never stored: a block under a recursive pin
+    Indirect,
+    Direct,
+    Recursive,
+}
+
+impl PinMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PinMode::Indirect => "indirect",
+            PinMode::Direct => "direct",
+            PinMode::Recursive => "recursive",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Pin {
+    pub cid: Cid,
+    pub mode: PinMode,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BadNode {
+    pub cid: Cid,
+    pub error: String,
+}
+
+// what pin_verify found for one pin, ok when bad_nodes is empty
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PinStatus {
+    pub cid: Cid,
+    pub mode: PinMode,
+    pub bad_nodes: Vec<BadNode>,
+}
+
+impl PinStatus {
+    pub fn ok(&self) -> bool {
+        self.bad_nodes.is_empty()
+    }
+}
+
+/*
+tldr; how it works
+the pins live in the repo's "pins" partition, keyed by the cid string with the mode as
+the value. only direct (that one block) and recursive (the block and every block below
+it) pins are stored, indirect ones are whatever the recursive pins reach, so they are
+worked out from the dags when asked for and never go stale.
+
+the set also carries the gc lock, one per repo and shared by every Pinset on it. gc holds
+it exclusively from mark to sweep, anything that adds blocks and pins them afterwards
+holds it shared across both steps (pin_lock), so a dag that is stored but not pinned yet
+is never swept from under it.
+*/
+#[derive(Clone)]
+pub struct Pinset {
+    repo: Repo,
+}
+
+impl Pinset {
+    pub fn new(repo: &Repo) -> Self {
+        Pinset { repo: repo.clone() }
+    }
+
+    // hold while adding content that is about to be pinned, gc waits until it is dropped.
+    // not reentrant, take it once and call pin_add under it
+    pub async fn pin_lock(&self) -> OwnedRwLockReadGuard<()> {
+        self.repo.gc_lock().read_owned().await
+    }
+
+    pub(crate) async fn gc_lock(&self) -> OwnedRwLockWriteGuard<()> {
+        self.repo.gc_lock().write_owned().await
+    }
+
+    // the stored pin for a cid, indirect pins are not stored
+    pub fn get(&self, cid: &Cid) -> Result<Option<PinMode>, PinErrors> {
+        match self.repo.pins().get(cid.to_string())? {
+            Some(value) => Ok(Some(parse_mode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // every stored pin, direct and recursive
+    pub fn list(&self) -> Result<Vec<Pin>, PinErrors> {
+        let mut pins = Vec::new();
+        for entry in self.repo.pins().iter() {
+            let (key, value) = entry?;
+            let Some(cid) = std::str::from_utf8(&key).ok().and_then(|key| Cid::try_from(key).ok())
+            else {
+                continue;
+            };
+            pins.push(Pin {
+                cid,
+                mode: parse_mode(&value)?,
+            });
+        }
+        Ok(pins)
+    }
+
+    fn insert(&self, cid: &Cid, mode: PinMode) -> Result<(), PinErrors> {
+        self.repo.pins().insert(cid.to_string(), mode.as_str())?;
+        Ok(())
+    }
+
+    fn remove(&self, cid: &Cid) -> Result<(), PinErrors> {
+        self.repo.pins().remove(cid.to_string())?;
+        Ok(())
+    }
+}
+
+fn parse_mode(value: &[u8]) -> Result<PinMode, PinErrors> {
+    match value {
+        b"direct" => Ok(PinMode::Direct),
+        b"recursive" => Ok(PinMode::Recursive),
+        other => Err(PinErrors::InvalidModeError(String::from_utf8_lossy(other).to_string())),
+    }
+}
+
+async fn load(cid: &Cid, store: &dyn Blockstore) -> Result<MerkleNode, PinErrors> {
+    store.get(cid).await?.ok_or(PinErrors::NotFoundError(*cid))
+}
+
+// every block below root (root excluded), each shared subtree walked once
+pub(crate) async fn descendants(root: &Cid, store: &dyn Blockstore) -> Result<BTreeSet<Cid>, PinErrors> {
+    let mut seen = BTreeSet::new();
+    let mut stack = vec![*root];
+    while let Some(cid) = stack.pop() {
+        for link in load(&cid, store).await?.links {
+            if seen.insert(link.cid) {
+                stack.push(link.cid);
+            }
+        }
+    }
+    Ok(seen)
+}
+
+/*
+tldr; how it works
+a pin only goes in once everything it covers is stored here (the root for a direct pin,
+the whole dag for a recursive one), so a pin is a promise the content is local.
+a recursive pin replaces a direct one on the same cid, a direct pin on a cid that is
+already pinned recursively changes nothing. content imported just before should be
+added and pinned under one Pinset::pin_lock so gc can not run in between.
+*/
+pub async fn pin_add(
+    cid: &Cid,
+    recursive: bool,
+    pins: &Pinset,
+    store: &dyn Blockstore,
+) -> Result<(), PinErrors> {
+    if recursive {
+        descendants(cid, store).await?;
+        pins.insert(cid, PinMode::Recursive)
+    } else {
+        if !store.has(cid).await? {
+            return Err(PinErrors::NotFoundError(*cid));
+        }
+        match pins.get(cid)? {
+            Some(PinMode::Recursive) => Ok(()),
+            _ => pins.insert(cid, PinMode::Direct),
+        }
+    }
+}
+
+// drops a direct or recursive pin, a block that is only pinned through a parent says which one
+pub async fn pin_rm(cid: &Cid, pins: &Pinset, store: &dyn Blockstore) -> Result<(), PinErrors> {
+    if pins.get(cid)?.is_some() {
+        return pins.remove(cid);
+    }
+    for pin in pins.list()? {
+        if pin.mode == PinMode::Recursive && descendants(&pin.cid, store).await?.contains(cid) {
+            return Err(PinErrors::IndirectPinError(pin.cid));
+        }
+    }
+    Err(PinErrors::NotPinnedError(*cid))
+}
+
+// each pinned cid once with its strongest mode (recursive, then direct, then indirect),
+// None lists all of them
+pub async fn pin_ls(
+    mode: Option<PinMode>,
+    pins: &Pinset,
+    store: &dyn Blockstore,
+) -> Result<Vec<Pin>, PinErrors> {
+    let mut all: BTreeMap<Cid, PinMode> = BTreeMap::new();
+    for pin in pins.list()? {
+        all.insert(pin.cid, pin.mode);
+    }
+    if matches!(mode, None | Some(PinMode::Indirect)) {
+        let recursive: Vec<Cid> = all
+            .iter()
+            .filter(|(_, mode)| **mode == PinMode::Recursive)
+            .map(|(cid, _)| *cid)
+            .collect();
+        for root in recursive {
+            for cid in descendants(&root, store).await? {
+                all.entry(cid).or_insert(PinMode::Indirect);
+            }
+        }
+    }
+    Ok(all
+        .into_iter()
+        .filter(|(_, pin_mode)| mode.is_none_or(|mode| mode == *pin_mode))
+        .map(|(cid, mode)| Pin { cid, mode })
+        .collect())
+}
+
+/*
+tldr; how it works
+walks every stored pin the way it was pinned and checks each block is there and still
+hashes to its cid. a block that is missing or damaged is reported and not walked into,
+the blocks below it are only reached through it.
+*/
+pub async fn pin_verify(pins: &Pinset, store: &dyn Blockstore) -> Result<Vec<PinStatus>, PinErrors> {
+    let mut statuses = Vec::new();
+    for pin in pins.list()? {
+        let mut bad_nodes = Vec::new();
+        let mut seen = BTreeSet::from([pin.cid]);
+        let mut stack = vec![pin.cid];
+        while let Some(cid) = stack.pop() {
+            let node = match check_block(&cid, store).await {
+                Ok(node) => node,
+                Err(error) => {
+                    bad_nodes.push(BadNode { cid, error });
+                    continue;
+                }
+            };
+            if pin.mode == PinMode::Recursive {
+                for link in node.links {
+                    if seen.insert(link.cid) {
+                        stack.push(link.cid);
+                    }
+                }
+            }
+        }
+        statuses.push(PinStatus {
+            cid: pin.cid,
+            mode: pin.mode,
+            bad_nodes,
+        });
+    }
+    Ok(statuses)
+}
+
+async fn check_block(cid: &Cid, store: &dyn Blockstore) -> Result<MerkleNode, String> {
+    let block = match store.get_block_uncached(cid).await {
+        Ok(Some(block)) => block,
+        Ok(None) => return Err("block is missing".to_string()),
+        Err(err) => return Err(err.to_string()),
+    };
+    match verify_cid(cid, &block) {
+        Ok(true) => {}
+        Ok(false) => return Err("block does not match its cid".to_string()),
+        Err(err) => return Err(err.to_string()),
+    }
+    MerkleNode::decode(*cid, &block).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::blockstore::MemoryBlockstore;
+    use crate::storage::import::file_dag;
+    use crate::storage::create_leaf;
+
+    #[tokio::test]
+    async fn test_pin_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let pins = Pinset::new(&Repo::open(dir.path()).unwrap());
+        let store = MemoryBlockstore::default();
+        let root = file_dag(&store, b"twelve bytes").await;
+        let node = store.get(&root).await.unwrap().unwrap();
+        let children: Vec<Cid> = node.links.iter().map(|link| link.cid).collect();
+        let lone = create_leaf(b"lone block");
+        store.put(&lone).await.unwrap();
+
+        pin_add(&root, true, &pins, &store).await.unwrap();
+        pin_add(&lone.cid, false, &pins, &store).await.unwrap();
+        // already recursive, stays recursive
+        pin_add(&root, false, &pins, &store).await.unwrap();
+
+        let listed = pin_ls(None, &pins, &store).await.unwrap();
+        assert_eq!(listed.len(), 2 + children.len());
+        assert!(listed.contains(&Pin { cid: root, mode: PinMode::Recursive }));
+        assert!(listed.contains(&Pin { cid: lone.cid, mode: PinMode::Direct }));
+        for child in &children {
+            assert!(listed.contains(&Pin { cid: *child, mode: PinMode::Indirect }));
+        }
+        assert_eq!(
+            pin_ls(Some(PinMode::Direct), &pins, &store).await.unwrap(),
+            vec![Pin { cid: lone.cid, mode: PinMode::Direct }]
+        );
+
+        assert!(matches!(
+            pin_rm(&children[0], &pins, &store).await,
+            Err(PinErrors::IndirectPinError(parent)) if parent == root
+        ));
+        pin_rm(&root, &pins, &store).await.unwrap();
+        assert!(matches!(pin_rm(&root, &pins, &store).await, Err(PinErrors::NotPinnedError(_))));
+        assert_eq!(pin_ls(Some(PinMode::Indirect), &pins, &store).await.unwrap(), vec![]);
+
+        // pins survive reopening
+        drop(pins);
+        let pins = Pinset::new(&Repo::open(dir.path()).unwrap());
+        assert_eq!(pins.get(&lone.cid).unwrap(), Some(PinMode::Direct));
+    }
+
+    #[tokio::test]
+    async fn test_pin_needs_local_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let pins = Pinset::new(&Repo::open(dir.path()).unwrap());
+        let store = MemoryBlockstore::default();
+        let root = file_dag(&store, b"twelve bytes").await;
+        let child = store.get(&root).await.unwrap().unwrap().links[1].cid;
+        store.delete(&child).await.unwrap();
+
+        assert!(matches!(
+            pin_add(&root, true, &pins, &store).await,
+            Err(PinErrors::NotFoundError(missing)) if missing == child
+        ));
+        // the root alone is here, a direct pin is fine
+        pin_add(&root, false, &pins, &store).await.unwrap();
+        assert!(pin_verify(&pins, &store).await.unwrap()[0].ok());
+    }
+
+    #[tokio::test]
+    async fn test_pin_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let pins = Pinset::new(&Repo::open(dir.path()).unwrap());
+        let store = MemoryBlockstore::default();
+        let root = file_dag(&store, b"twelve bytes").await;
+        pin_add(&root, true, &pins, &store).await.unwrap();
+        assert!(pin_verify(&pins, &store).await.unwrap()[0].ok());
+
+        let links = store.get(&root).await.unwrap().unwrap().links;
+        store.delete(&links[0].cid).await.unwrap();
+        store.put_block(&links[2].cid, b"not what was pinned").await.unwrap();
+        let statuses = pin_verify(&pins, &store).await.unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert!(!statuses[0].ok());
+        let mut bad: Vec<Cid> = statuses[0].bad_nodes.iter().map(|node| node.cid).collect();
+        bad.sort();
+        let mut expected = vec![links[0].cid, links[2].cid];
+        expected.sort();
+        assert_eq!(bad, expected);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::repo::migrations::migrate;
+use fjall::{Config, Keyspace, PartitionCreateOptions, PartitionHandle};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+const LOCK_FILE: &str = "repo.lock";
+
+#[derive(Debug, Error)]
+pub enum RepoErrors {
+    #[error("Error opening the repo keyspace: {0}")]
+    StorageError(#[from] fjall::Error),
+    #[error("Error creating the repo folder: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("The repo at {0:?} is in use by another process")]
+    LockedError(PathBuf),
+    #[error("The repo is at version {found}, this build only understands up to {supported}")]
+    TooNewError { found: u32, supported: u32 },
+    #[error("The repo version {0:?} is not a number")]
+    InvalidVersionError(String),
+    #[error("Error migrating a stored record: {0}")]
+    SerializationError(#[from] serde_json::Error),
+}
+
+/*
+tldr; how it works
+everything a node keeps on disk lives in one fjall keyspace under one folder, opened once
+here and handed to every subsystem (blockstore, pin set, ...) instead of each of them
+opening the path again. the partitions:
+    slices   block bytes, keyed by cid string (the name predates the repo)
+    metadata file records, keyed by cid string
+    pins     pin modes, keyed by cid string
+    peers    known peers
+    repo     the repo version
+repo.lock in the folder is locked exclusively for as long as a handle is alive, a second
+daemon (or anything else) opening the same folder gets LockedError. it is an os lock, not
+the file being there, so a crashed daemon leaves nothing to clean up.
+once locked the repo is brought up to date before anything else touches it (see migrations).
+handles are cheap clones of the same keyspace, the lock goes with the last one.
+*/
+#[derive(Clone)]
+pub struct Repo {
+    path: PathBuf,
+    keyspace: Keyspace,
+    blocks: PartitionHandle,
+    metadata: PartitionHandle,
+    pins: PartitionHandle,
+    peers: PartitionHandle,
+    // shared by every pin set on the repo, see Pinset
+    gc_lock: Arc<RwLock<()>>,
+    _lock: Arc<File>,
+}
+
+impl Repo {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, RepoErrors> {
+        let path = path.as_ref().to_path_buf();
+        std::fs::create_dir_all(&path)?;
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(LOCK_FILE))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(RepoErrors::LockedError(path)),
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+        let keyspace = Config::new(&path).open()?;
+        migrate(&keyspace)?;
+        let partition = |name| keyspace.open_partition(name, PartitionCreateOptions::default());
+        Ok(Repo {
+            blocks: partition("slices")?,
+            metadata: partition("metadata")?,
+            pins: partition("pins")?,
+            peers: partition("peers")?,
+            path,
+            keyspace,
+            gc_lock: Arc::new(RwLock::new(())),
+            _lock: Arc::new(lock),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // for batches that span partitions
+    pub fn keyspace(&self) -> &Keyspace {
+        &self.keyspace
+    }
+
+    pub fn blocks(&self) -> &PartitionHandle {
+        &self.blocks
+    }
+
+    pub fn metadata(&self) -> &PartitionHandle {
+        &self.metadata
+    }
+
+    pub fn pins(&self) -> &PartitionHandle {
+        &self.pins
+    }
+
+    pub fn peers(&self) -> &PartitionHandle {
+        &self.peers
+    }
+
+    pub(crate) fn gc_lock(&self) -> Arc<RwLock<()>> {
+        self.gc_lock.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pinning::{pin_add, Pinset};
+    use crate::storage::blockstore::{Blockstore, FjallBlockstore};
+    use crate::storage::create_leaf;
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repo::open(dir.path()).unwrap();
+        assert!(matches!(Repo::open(dir.path()), Err(RepoErrors::LockedError(_))));
+        // clones share the lock
+        let handle = repo.clone();
+        drop(repo);
+        assert!(matches!(Repo::open(dir.path()), Err(RepoErrors::LockedError(_))));
+        drop(handle);
+        Repo::open(dir.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_subsystems_share_the_keyspace() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repo::open(dir.path()).unwrap();
+        repo.blocks().insert("key", "value").unwrap();
+        assert_eq!(repo.blocks().get("key").unwrap().unwrap().to_vec(), b"value");
+
+        let store = FjallBlockstore::new(&repo);
+        let pins = Pinset::new(&repo);
+        let leaf = create_leaf(b"in the repo");
+        store.put(&leaf).await.unwrap();
+        pin_add(&leaf.cid, false, &pins, &store).await.unwrap();
+        assert!(repo.blocks().contains_key(leaf.cid.to_string()).unwrap());
+        assert!(repo.pins().contains_key(leaf.cid.to_string()).unwrap());
+        drop((store, pins, repo));
+
+        let repo = Repo::open(dir.path()).unwrap();
+        assert!(FjallBlockstore::new(&repo).has(&leaf.cid).await.unwrap());
+        assert!(Pinset::new(&repo).get(&leaf.cid).unwrap().is_some());
+    }
+}
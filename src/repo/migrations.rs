@@ -0,0 +1,264 @@
+use crate::repo::RepoErrors;
+use crate::storage::blockstore::{upgrade_legacy_record, LegacyRecord};
+use cid::Cid;
+use fjall::{Batch, Keyspace, PartitionCreateOptions, PartitionHandle};
+
+// partition and key the repo version is kept under
+const INFO_PARTITION: &str = "repo";
+const VERSION_KEY: &str = "version";
+// bytes json_to_binary lets pile up in a batch before committing it, a legacy repo can
+// be a lot bigger than memory
+const CONVERT_BATCH_BYTES: usize = 64 << 20;
+
+pub struct Migration {
+    pub description: &'static str,
+    pub run: fn(&Keyspace, &mut Batch) -> Result<(), RepoErrors>,
+}
+
+// MIGRATIONS[n] takes a repo from version n to n + 1, only ever append to it
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    description: "blocks stored as json records are rewritten to their canonical bytes",
+    run: json_to_binary,
+}];
+
+// the newest repo this binary can open
+pub const REPO_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/*
+tldr; how it works
+the repo version sits in the "repo" partition. a repo without one is version 0 (written
+before there were versions), unless it has no blocks partition at all, then it is new and
+starts at REPO_VERSION. every migration from the found version up runs in order, each in
+its own batch together with the bump to its version, so a crash leaves the repo at the
+last migration that finished and the next open picks up from there. a migration that can
+not put all its writes in the batch has to be safe to run again.
+a repo newer than REPO_VERSION is refused, this binary does not know what changed.
+*/
+pub(crate) fn migrate(keyspace: &Keyspace) -> Result<u32, RepoErrors> {
+    run_migrations(keyspace, MIGRATIONS)
+}
+
+fn run_migrations(keyspace: &Keyspace, migrations: &[Migration]) -> Result<u32, RepoErrors> {
+    let latest = migrations.len() as u32;
+    let fresh = !keyspace.partition_exists("slices");
+    let info = keyspace.open_partition(INFO_PARTITION, PartitionCreateOptions::default())?;
+    let found = match read_version(&info)? {
+        Some(version) => version,
+        None if fresh => {
+            info.insert(VERSION_KEY, latest.to_string())?;
+            return Ok(latest);
+        }
+        None => 0,
+    };
+    if found > latest {
+        return Err(RepoErrors::TooNewError {
+            found,
+            supported: latest,
+        });
+    }
+    for (from, migration) in migrations.iter().enumerate().skip(found as usize) {
+        let to = from as u32 + 1;
+        eprintln!("Migrating the repo to version {}: {}", to, migration.description);
+        let mut batch = keyspace.batch();
+        (migration.run)(keyspace, &mut batch)?;
+        batch.insert(&info, VERSION_KEY, to.to_string());
+        batch.commit()?;
+    }
+    Ok(latest)
+}
+
+fn read_version(info: &PartitionHandle) -> Result<Option<u32>, RepoErrors> {
+    let Some(value) = info.get(VERSION_KEY)? else {
+        return Ok(None);
+    };
+    let value = String::from_utf8_lossy(&value).to_string();
+    match value.parse() {
+        Ok(version) => Ok(Some(version)),
+        Err(_) => Err(RepoErrors::InvalidVersionError(value)),
+    }
+}
+
+// 0 -> 1: keys that are not cids and records that can not be turned into their block are
+// left untouched
+fn json_to_binary(keyspace: &Keyspace, batch: &mut Batch) -> Result<(), RepoErrors> {
+    convert_json_records(keyspace, batch, CONVERT_BATCH_BYTES)
+}
+
+// commits along the way every batch_bytes, the rest goes in with the version bump. a run
+// cut short is picked up by the next, converted blocks are canonical and left alone
+fn convert_json_records(keyspace: &Keyspace, batch: &mut Batch, batch_bytes: usize) -> Result<(), RepoErrors> {
+    let blocks = keyspace.open_partition("slices", PartitionCreateOptions::default())?;
+    let metadata = keyspace.open_partition("metadata", PartitionCreateOptions::default())?;
+    let mut unreadable = 0;
+    let mut pending = 0;
+    for entry in blocks.iter() {
+        let (key, value) = entry?;
+        let Some(cid) = std::str::from_utf8(&key).ok().and_then(|key| Cid::try_from(key).ok())
+        else {
+            continue;
+        };
+        match upgrade_legacy_record(&cid, &value) {
+            LegacyRecord::Canonical => {}
+            LegacyRecord::Upgraded(block, record) => {
+                pending += block.len();
+                batch.insert(&blocks, key.clone(), block);
+                if let Some(record) = record {
+                    batch.insert(&metadata, key, serde_json::to_vec(&record)?);
+                }
+                if pending >= batch_bytes {
+                    std::mem::replace(batch, keyspace.batch()).commit()?;
+                    pending = 0;
+                }
+            }
+            LegacyRecord::Unreadable => unreadable += 1,
+        }
+    }
+    if unreadable > 0 {
+        eprintln!("{} stored records could not be converted to blocks and were left as they are", unreadable);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::Repo;
+    use crate::storage::blockstore::{Blockstore, FjallBlockstore};
+    use crate::storage::dag::{create_leaf, generate_merkle_tree};
+    use crate::storage::metadata::FileMetadata;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn old_keyspace(path: &std::path::Path) -> (Keyspace, PartitionHandle) {
+        let keyspace = fjall::Config::new(path).open().unwrap();
+        let items = keyspace.open_partition("slices", PartitionCreateOptions::default()).unwrap();
+        (keyspace, items)
+    }
+
+    #[tokio::test]
+    async fn test_migrates_json_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let metadata = FileMetadata {
+            size: 16,
+            filename: Some("old.txt".to_string()),
+            ..Default::default()
+        };
+        let tree = generate_merkle_tree(
+            vec![create_leaf(b"old chunk 1"), create_leaf(b"old 2")],
+            metadata.clone(),
+        )
+        .unwrap();
+        // what store_file used to write, before there was a repo
+        {
+            let (_keyspace, items) = old_keyspace(dir.path());
+            for node in &tree {
+                items.insert(node.cid.to_string(), serde_json::to_string(node).unwrap()).unwrap();
+            }
+            items.insert("key", "value").unwrap();
+            // a parent from the first tree format, its cid was never the hash of a block
+            items
+                .insert(
+                    crate::cid::generate_cid(b"made up").to_string(),
+                    r#"{"cid":[1],"links":[[1]],"data":null,"is_dup":false}"#,
+                )
+                .unwrap();
+        }
+
+        let repo = Repo::open(dir.path()).unwrap();
+        let store = FjallBlockstore::new(&repo);
+        for node in &tree {
+            assert_eq!(store.get(&node.cid).await.unwrap().as_ref(), Some(node));
+            let block = store.get_block(&node.cid).await.unwrap().unwrap();
+            assert_eq!(block, node.encode().unwrap());
+        }
+        let root = tree.last().unwrap().cid;
+        assert_eq!(store.get_metadata(&root).await.unwrap(), Some(metadata));
+        assert_eq!(repo.blocks().get("key").unwrap().unwrap().to_vec(), b"value");
+        let info = repo.keyspace().open_partition(INFO_PARTITION, Default::default()).unwrap();
+        assert_eq!(read_version(&info).unwrap(), Some(REPO_VERSION));
+    }
+
+    #[tokio::test]
+    async fn test_conversion_commits_as_it_goes() {
+        let dir = tempfile::tempdir().unwrap();
+        let leaves: Vec<_> = (0..3u8).map(|i| create_leaf(&[i; 10])).collect();
+        {
+            let (keyspace, items) = old_keyspace(dir.path());
+            for leaf in &leaves {
+                items.insert(leaf.cid.to_string(), serde_json::to_string(leaf).unwrap()).unwrap();
+            }
+            // two blocks fill a batch, the third is left for the final one, which a crash
+            // (or here, a drop) never commits
+            let mut batch = keyspace.batch();
+            convert_json_records(&keyspace, &mut batch, 20).unwrap();
+            drop(batch);
+            let converted = leaves
+                .iter()
+                .filter(|leaf| items.get(leaf.cid.to_string()).unwrap().unwrap().len() == 10)
+                .count();
+            assert_eq!(converted, 2);
+        }
+        // the version was never bumped, opening finishes the job
+        let repo = Repo::open(dir.path()).unwrap();
+        let store = FjallBlockstore::new(&repo);
+        for leaf in &leaves {
+            assert_eq!(store.get(&leaf.cid).await.unwrap().as_ref(), Some(leaf));
+        }
+    }
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    fn first(keyspace: &Keyspace, batch: &mut Batch) -> Result<(), RepoErrors> {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+        batch.insert(&keyspace.open_partition("slices", Default::default())?, "first", "done");
+        Ok(())
+    }
+
+    fn broken(_: &Keyspace, _: &mut Batch) -> Result<(), RepoErrors> {
+        Err(RepoErrors::InvalidVersionError("broken".to_string()))
+    }
+
+    fn fixed(keyspace: &Keyspace, batch: &mut Batch) -> Result<(), RepoErrors> {
+        // sees what the migration before it wrote
+        let slices = keyspace.open_partition("slices", Default::default())?;
+        assert!(slices.contains_key("first")?);
+        batch.insert(&slices, "second", "done");
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrations_resume_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (keyspace, slices) = old_keyspace(dir.path());
+        let migration = |run| Migration { description: "test", run };
+        let interrupted = [migration(first), migration(broken)];
+        assert!(run_migrations(&keyspace, &interrupted).is_err());
+        // the first one stuck, the failed one left nothing
+        let info = keyspace.open_partition(INFO_PARTITION, Default::default()).unwrap();
+        assert_eq!(read_version(&info).unwrap(), Some(1));
+        assert!(!slices.contains_key("second").unwrap());
+
+        let fixed = [migration(first), migration(fixed)];
+        assert_eq!(run_migrations(&keyspace, &fixed).unwrap(), 2);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+        assert!(slices.contains_key("second").unwrap());
+        assert_eq!(run_migrations(&keyspace, &fixed).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_refuses_newer_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repo::open(dir.path()).unwrap();
+        let info = repo.keyspace().open_partition(INFO_PARTITION, Default::default()).unwrap();
+        // a new repo starts at the latest version without running anything
+        assert_eq!(read_version(&info).unwrap(), Some(REPO_VERSION));
+        info.insert(VERSION_KEY, (REPO_VERSION + 1).to_string()).unwrap();
+        drop((info, repo));
+        match Repo::open(dir.path()) {
+            Err(RepoErrors::TooNewError { found, supported }) => {
+                assert_eq!((found, supported), (REPO_VERSION + 1, REPO_VERSION))
+            }
+            Err(other) => panic!("unexpected {:?}", other),
+            Ok(_) => panic!("opened a repo from the future"),
+        }
+    }
+}
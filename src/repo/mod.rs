@@ -0,0 +1,5 @@
+pub mod fs_repo;
+pub mod migrations;
+
+pub use fs_repo::{Repo, RepoErrors};
+pub use migrations::REPO_VERSION;
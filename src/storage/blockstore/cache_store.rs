@@ -0,0 +1,370 @@
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors, EncodedBlock};
+use async_trait::async_trait;
+use cid::Cid;
+use lru::LruCache;
+use murmur3::murmur3_x64_128;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+// bits set per cid, what kubo uses for its bloom filter
+const BLOOM_HASHES: usize = 7;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CacheStats {
+    // get_block and has answered from memory
+    pub hits: u64,
+    // went down to the store
+    pub misses: u64,
+    // answered "not here" by the bloom filter without going down to the store
+    pub bloom_negatives: u64,
+    // the filter said maybe, the store said no
+    pub bloom_false_positives: u64,
+    pub cached_blocks: u64,
+    pub cached_bytes: u64,
+}
+
+// least recently used blocks go first once their bytes add up to more than capacity
+struct BlockCache {
+    blocks: LruCache<Cid, Vec<u8>>,
+    bytes: u64,
+    capacity: u64,
+    // bumped by every delete, see get_block
+    deletes: u64,
+}
+
+impl BlockCache {
+    // only if nothing was deleted since the read that fetched block started
+    fn insert_read(&mut self, deletes: u64, cid: Cid, block: &[u8]) {
+        if self.deletes == deletes {
+            self.insert(cid, block);
+        }
+    }
+
+    fn insert(&mut self, cid: Cid, block: &[u8]) {
+        // one block bigger than the whole cache would only flush it
+        if block.len() as u64 > self.capacity {
+            return;
+        }
+        if let Some(old) = self.blocks.put(cid, block.to_vec()) {
+            self.bytes -= old.len() as u64;
+        }
+        self.bytes += block.len() as u64;
+        while self.bytes > self.capacity {
+            let Some((_, evicted)) = self.blocks.pop_lru() else {
+                break;
+            };
+            self.bytes -= evicted.len() as u64;
+        }
+    }
+
+    fn remove(&mut self, cid: &Cid) {
+        self.deletes += 1;
+        if let Some(block) = self.blocks.pop(cid) {
+            self.bytes -= block.len() as u64;
+        }
+    }
+}
+
+struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    fn new(bytes: u64) -> Self {
+        BloomFilter {
+            bits: vec![0; bytes.div_ceil(8).max(1) as usize],
+        }
+    }
+
+    // double hashing over the two halves of one murmur3 hash
+    fn positions(&self, cid: &Cid) -> [usize; BLOOM_HASHES] {
+        let hash = murmur3_x64_128(&mut &cid.to_bytes()[..], 0).expect("hashing an in-memory cid");
+        let (first, second) = (hash as u64, (hash >> 64) as u64);
+        let len = self.bits.len() as u64 * 64;
+        std::array::from_fn(|i| (first.wrapping_add((i as u64).wrapping_mul(second)) % len) as usize)
+    }
+
+    fn insert(&mut self, cid: &Cid) {
+        for position in self.positions(cid) {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    fn may_contain(&self, cid: &Cid) -> bool {
+        self.positions(cid)
+            .iter()
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+}
+
+/*
+tldr; how it works
+sits in front of another store. blocks read through it are kept in memory, up to
+cache_size bytes, so a popular file served over and over is read from the store once.
+writes are not cached (an import would push out everything that is hot), deletes drop
+the cached copy, and a read that raced a delete does not put what it fetched back (the
+cache counts deletes, a read only caches if the count did not move while it was out).
+has() always asks the store, something about to be written must not be skipped over a
+cached copy. what is cached is what the store returned, checking it is still left to
+callers, and checks of the store itself (scrub, /verify) read past the cache with
+get_block_uncached so rot on disk under a hot block is still found.
+
+the bloom filter (bloom_size bytes) is filled with every cid in the store on open and
+every cid written after. a cid it has never seen is certainly not stored, so has() and
+get_block() for it answer without a lookup. deletes can not be taken out of a bloom
+filter, they just turn into false positives until the next open.
+either part is left out with a size of 0.
+*/
+pub struct CachedBlockstore {
+    inner: Arc<dyn Blockstore>,
+    cache: Option<Mutex<BlockCache>>,
+    bloom: Option<RwLock<BloomFilter>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bloom_negatives: AtomicU64,
+    bloom_false_positives: AtomicU64,
+}
+
+impl CachedBlockstore {
+    pub async fn new(
+        inner: Arc<dyn Blockstore>,
+        cache_size: u64,
+        bloom_size: u64,
+    ) -> Result<Self, BlockstoreErrors> {
+        let bloom = match bloom_size {
+            0 => None,
+            size => {
+                let mut bloom = BloomFilter::new(size);
+                for cid in inner.list().await? {
+                    bloom.insert(&cid);
+                }
+                Some(RwLock::new(bloom))
+            }
+        };
+        let cache = (cache_size > 0).then(|| {
+            Mutex::new(BlockCache {
+                blocks: LruCache::unbounded(),
+                bytes: 0,
+                capacity: cache_size,
+                deletes: 0,
+            })
+        });
+        Ok(CachedBlockstore {
+            inner,
+            cache,
+            bloom,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bloom_negatives: AtomicU64::new(0),
+            bloom_false_positives: AtomicU64::new(0),
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (cached_blocks, cached_bytes) = match &self.cache {
+            Some(cache) => {
+                let cache = cache.lock().unwrap();
+                (cache.blocks.len() as u64, cache.bytes)
+            }
+            None => (0, 0),
+        };
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bloom_negatives: self.bloom_negatives.load(Ordering::Relaxed),
+            bloom_false_positives: self.bloom_false_positives.load(Ordering::Relaxed),
+            cached_blocks,
+            cached_bytes,
+        }
+    }
+
+    // false when the bloom filter is sure the cid is not stored
+    fn may_have(&self, cid: &Cid) -> bool {
+        let Some(bloom) = &self.bloom else {
+            return true;
+        };
+        let maybe = bloom.read().unwrap().may_contain(cid);
+        if !maybe {
+            self.bloom_negatives.fetch_add(1, Ordering::Relaxed);
+        }
+        maybe
+    }
+
+    fn missed(&self, found: bool) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if !found && self.bloom.is_some() {
+            self.bloom_false_positives.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn remember(&self, cid: &Cid) {
+        if let Some(bloom) = &self.bloom {
+            bloom.write().unwrap().insert(cid);
+        }
+    }
+}
+
+#[async_trait]
+impl Blockstore for CachedBlockstore {
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        let mut deletes = 0;
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap();
+            if let Some(block) = cache.blocks.get(cid) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(block.clone()));
+            }
+            deletes = cache.deletes;
+        }
+        if !self.may_have(cid) {
+            return Ok(None);
+        }
+        let block = self.inner.get_block(cid).await?;
+        self.missed(block.is_some());
+        if let (Some(cache), Some(block)) = (&self.cache, &block) {
+            cache.lock().unwrap().insert_read(deletes, *cid, block);
+        }
+        Ok(block)
+    }
+
+    async fn get_block_uncached(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        self.inner.get_block_uncached(cid).await
+    }
+
+    // in the filter before it is in the store, never the other way round
+    async fn put_block(&self, cid: &Cid, block: &[u8]) -> Result<(), BlockstoreErrors> {
+        self.remember(cid);
+        self.inner.put_block(cid, block).await
+    }
+
+    async fn get_record(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        self.inner.get_record(cid).await
+    }
+
+    async fn put_record(&self, cid: &Cid, record: &[u8]) -> Result<(), BlockstoreErrors> {
+        self.inner.put_record(cid, record).await
+    }
+
+    async fn has(&self, cid: &Cid) -> Result<bool, BlockstoreErrors> {
+        if !self.may_have(cid) {
+            return Ok(false);
+        }
+        let found = self.inner.has(cid).await?;
+        self.missed(found);
+        Ok(found)
+    }
+
+    async fn delete(&self, cid: &Cid) -> Result<(), BlockstoreErrors> {
+        self.inner.delete(cid).await?;
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().remove(cid);
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Cid>, BlockstoreErrors> {
+        self.inner.list().await
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreErrors> {
+        self.inner.block_size(cid).await
+    }
+
+    fn stored_len(&self, len: usize) -> u64 {
+        self.inner.stored_len(len)
+    }
+
+    async fn size(&self) -> Result<u64, BlockstoreErrors> {
+        self.inner.size().await
+    }
+
+    async fn put_blocks(&self, blocks: &[EncodedBlock]) -> Result<(), BlockstoreErrors> {
+        for block in blocks {
+            self.remember(&block.cid);
+        }
+        self.inner.put_blocks(blocks).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::blockstore::MemoryBlockstore;
+    use crate::storage::create_leaf;
+
+    #[tokio::test]
+    async fn test_cache_and_bloom() {
+        let inner: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        let old = create_leaf(&[1; 40]);
+        inner.put(&old).await.unwrap();
+        let store = CachedBlockstore::new(inner.clone(), 100, 1024).await.unwrap();
+
+        // read once from the store, then from memory. has() always asks the store
+        assert_eq!(store.get(&old.cid).await.unwrap().as_ref(), Some(&old));
+        assert_eq!(store.get(&old.cid).await.unwrap().as_ref(), Some(&old));
+        assert!(store.has(&old.cid).await.unwrap());
+        let stats = store.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert_eq!((stats.cached_blocks, stats.cached_bytes), (1, 40));
+
+        // never stored, the filter answers
+        let unknown = create_leaf(b"never stored");
+        assert!(!store.has(&unknown.cid).await.unwrap());
+        assert_eq!(store.get_block(&unknown.cid).await.unwrap(), None);
+        assert_eq!(store.stats().bloom_negatives, 2);
+
+        // written through the cache, so the filter knows it
+        let new = create_leaf(&[2; 40]);
+        store.put(&new).await.unwrap();
+        assert!(store.has(&new.cid).await.unwrap());
+        store.get_block(&new.cid).await.unwrap();
+        // a third block of 40 pushes the least recently used one out
+        let third = create_leaf(&[3; 40]);
+        store.put(&third).await.unwrap();
+        store.get_block(&third.cid).await.unwrap();
+        let stats = store.stats();
+        assert_eq!((stats.cached_blocks, stats.cached_bytes), (2, 80));
+
+        // checks see what is on disk, not the good copy in memory
+        inner.put_block(&new.cid, b"rotted").await.unwrap();
+        assert_eq!(store.get_block(&new.cid).await.unwrap(), Some(vec![2; 40]));
+        assert_eq!(store.get_block_uncached(&new.cid).await.unwrap(), Some(b"rotted".to_vec()));
+
+        // deleted blocks are not served from memory
+        store.delete(&third.cid).await.unwrap();
+        assert!(!store.has(&third.cid).await.unwrap());
+        assert_eq!(store.get_block(&third.cid).await.unwrap(), None);
+        assert_eq!(store.stats().bloom_false_positives, 2);
+    }
+
+    #[test]
+    fn test_read_racing_a_delete_is_not_cached() {
+        let mut cache = BlockCache {
+            blocks: LruCache::unbounded(),
+            bytes: 0,
+            capacity: 100,
+            deletes: 0,
+        };
+        let leaf = create_leaf(b"about to go");
+        // a read starts, the block is deleted while it is out, it comes back
+        let deletes = cache.deletes;
+        cache.remove(&leaf.cid);
+        cache.insert_read(deletes, leaf.cid, b"about to go");
+        assert!(!cache.blocks.contains(&leaf.cid));
+        cache.insert_read(cache.deletes, leaf.cid, b"about to go");
+        assert!(cache.blocks.contains(&leaf.cid));
+    }
+
+    #[tokio::test]
+    async fn test_turned_off() {
+        let inner: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        let store = CachedBlockstore::new(inner, 0, 0).await.unwrap();
+        let leaf = create_leaf(b"straight through");
+        store.put(&leaf).await.unwrap();
+        assert!(store.has(&leaf.cid).await.unwrap());
+        assert!(store.has(&leaf.cid).await.unwrap());
+        let stats = store.stats();
+        assert_eq!((stats.hits, stats.misses, stats.cached_blocks), (0, 2, 0));
+    }
+}
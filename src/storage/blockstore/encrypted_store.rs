@@ -0,0 +1,501 @@
+use crate::cid::builder::verify_cid;
+use crate::pinning::pin::Pinset;
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors, EncodedBlock};
+use aes_gcm::Aes256Gcm;
+use async_trait::async_trait;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::XChaCha20Poly1305;
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+use zeroize::Zeroize;
+
+pub const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 8;
+// format version, cipher, key id
+const HEADER_LEN: usize = 2 + KEY_ID_LEN;
+const FORMAT: u8 = 1;
+const TAG_LEN: usize = 16;
+// in front of a record's cid in its associated data, a sealed record never opens as a
+// block or the other way round
+const RECORD_AAD: &[u8] = b"record";
+// pbkdf2-hmac-sha256 rounds for passphrases, owasp's current advice
+pub const PASSPHRASE_ROUNDS: u32 = 600_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cipher {
+    // 24 byte nonces, random ones never realistically repeat
+    #[default]
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+    // 12 byte random nonces, fine for a few billion blocks per key
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+}
+
+impl Cipher {
+    fn tag(self) -> u8 {
+        match self {
+            Cipher::XChaCha20Poly1305 => 1,
+            Cipher::Aes256Gcm => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Cipher::XChaCha20Poly1305),
+            2 => Some(Cipher::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    fn nonce_len(self) -> usize {
+        match self {
+            Cipher::XChaCha20Poly1305 => 24,
+            Cipher::Aes256Gcm => 12,
+        }
+    }
+
+    fn seal(self, key: &EncryptionKey, nonce: &[u8], payload: Payload) -> Option<Vec<u8>> {
+        match self {
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(&key.bytes.into()).encrypt(nonce.into(), payload),
+            Cipher::Aes256Gcm => Aes256Gcm::new(&key.bytes.into()).encrypt(nonce.into(), payload),
+        }
+        .ok()
+    }
+
+    fn open(self, key: &EncryptionKey, nonce: &[u8], payload: Payload) -> Option<Vec<u8>> {
+        match self {
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(&key.bytes.into()).decrypt(nonce.into(), payload),
+            Cipher::Aes256Gcm => Aes256Gcm::new(&key.bytes.into()).decrypt(nonce.into(), payload),
+        }
+        .ok()
+    }
+}
+
+// wiped from memory when dropped
+pub struct EncryptionKey {
+    bytes: [u8; KEY_LEN],
+    id: [u8; KEY_ID_LEN],
+}
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; KEY_LEN]) -> Self {
+        // what blocks name their key by, a hash so the key itself is never on disk
+        let hash = Sha256::new().chain_update(b"ipfs-rust block key").chain_update(bytes).finalize();
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&hash[..KEY_ID_LEN]);
+        EncryptionKey { bytes, id }
+    }
+
+    pub fn generate() -> Self {
+        let mut bytes = [0; KEY_LEN];
+        OsRng.fill_bytes(&mut bytes);
+        EncryptionKey::new(bytes)
+    }
+
+    pub fn from_passphrase(passphrase: &[u8], salt: &[u8]) -> Self {
+        EncryptionKey::new(pbkdf2(passphrase, salt, PASSPHRASE_ROUNDS))
+    }
+
+    pub fn id(&self) -> String {
+        hex::encode(self.id)
+    }
+}
+
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+fn pbkdf2(passphrase: &[u8], salt: &[u8], rounds: u32) -> [u8; KEY_LEN] {
+    let mut key = [0; KEY_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, rounds, &mut key);
+    key
+}
+
+// what a stored value turned out to be
+enum Stored {
+    // under the current key and cipher
+    Current(Vec<u8>),
+    // under an old key or cipher, or written before encryption was turned on
+    Stale(Vec<u8>),
+    // does not authenticate, handed up as it is so it fails its hash check like any bad block
+    Corrupt(Vec<u8>),
+}
+
+/*
+tldr; how it works
+sits between the node and the store that writes to disk, so block bytes only ever reach
+the disk encrypted. cids are still the hash of the plaintext, dedup and addressing do not
+change, and nothing above this layer (the cache included) sees ciphertext. on disk a
+block is
+    format (1) | cipher (1) | key id (8) | nonce | ciphertext and tag
+with the cid as associated data, so a block copied under another cid does not decrypt.
+file records (names, mime types, modes, times) are sealed the same way with "record" and
+the cid as associated data. cids and sizes are not encrypted.
+
+a read looks at what is stored: bytes that hash to the cid are a block from before
+encryption was turned on and come back as they are, anything else is decrypted with the
+key its id names, the current one or one of old_keys. a block whose key is not configured
+fails the read with UnknownKeyError instead of passing for corrupt, scrub would otherwise
+quarantine a whole store over a missing keyfile. records read the same way, json ones are
+from before encryption, and one that does not authenticate fails with CorruptedRecordError.
+
+rotating: configure the new key, move the old one to old_keys and restart.
+reencrypt_in_background rewrites every block and record not under the current key and
+cipher (and encrypts the plaintext ones), once it has finished the old keys can go. the rewrites go
+straight to the store under this one, a quota above only sees their few bytes of
+difference in size on the next start.
+*/
+pub struct EncryptedBlockstore {
+    inner: Arc<dyn Blockstore>,
+    cipher: Cipher,
+    key: EncryptionKey,
+    old_keys: Vec<EncryptionKey>,
+}
+
+impl EncryptedBlockstore {
+    pub fn new(inner: Arc<dyn Blockstore>, cipher: Cipher, key: EncryptionKey, old_keys: Vec<EncryptionKey>) -> Self {
+        EncryptedBlockstore {
+            inner,
+            cipher,
+            key,
+            old_keys,
+        }
+    }
+
+    fn seal(&self, cid: &Cid, aad: &[u8], value: &[u8]) -> Result<Vec<u8>, BlockstoreErrors> {
+        let mut sealed = Vec::with_capacity(HEADER_LEN + self.cipher.nonce_len() + value.len() + TAG_LEN);
+        sealed.extend([FORMAT, self.cipher.tag()]);
+        sealed.extend(self.key.id);
+        let mut nonce = vec![0; self.cipher.nonce_len()];
+        OsRng.fill_bytes(&mut nonce);
+        let payload = Payload { msg: value, aad };
+        let ciphertext = self
+            .cipher
+            .seal(&self.key, &nonce, payload)
+            .ok_or(BlockstoreErrors::EncryptionError(*cid))?;
+        sealed.extend(nonce);
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    fn seal_block(&self, cid: &Cid, block: &[u8]) -> Result<Vec<u8>, BlockstoreErrors> {
+        self.seal(cid, &cid.to_bytes(), block)
+    }
+
+    fn seal_record(&self, cid: &Cid, record: &[u8]) -> Result<Vec<u8>, BlockstoreErrors> {
+        self.seal(cid, &record_aad(cid), record)
+    }
+
+    fn open(&self, cid: &Cid, aad: &[u8], stored: Vec<u8>) -> Result<Stored, BlockstoreErrors> {
+        let cipher = match stored.get(..2) {
+            Some([FORMAT, tag]) => Cipher::from_tag(*tag),
+            _ => None,
+        };
+        let Some(cipher) = cipher.filter(|cipher| stored.len() >= HEADER_LEN + cipher.nonce_len() + TAG_LEN) else {
+            return Ok(Stored::Corrupt(stored));
+        };
+        let id = &stored[2..HEADER_LEN];
+        let Some(key) = std::iter::once(&self.key).chain(&self.old_keys).find(|key| key.id == id) else {
+            return Err(BlockstoreErrors::UnknownKeyError {
+                cid: *cid,
+                key_id: hex::encode(id),
+            });
+        };
+        let (nonce, ciphertext) = stored[HEADER_LEN..].split_at(cipher.nonce_len());
+        let payload = Payload { msg: ciphertext, aad };
+        Ok(match cipher.open(key, nonce, payload) {
+            Some(value) if cipher == self.cipher && key.id == self.key.id => Stored::Current(value),
+            Some(value) => Stored::Stale(value),
+            None => Stored::Corrupt(stored),
+        })
+    }
+
+    fn open_block(&self, cid: &Cid, stored: Vec<u8>) -> Result<Stored, BlockstoreErrors> {
+        if verify_cid(cid, &stored).unwrap_or(false) {
+            return Ok(Stored::Stale(stored));
+        }
+        self.open(cid, &cid.to_bytes(), stored)
+    }
+
+    // a sealed record starts with the format byte, a plaintext one is a json object
+    fn open_record(&self, cid: &Cid, stored: Vec<u8>) -> Result<Stored, BlockstoreErrors> {
+        if stored.first() == Some(&b'{') {
+            return Ok(Stored::Stale(stored));
+        }
+        self.open(cid, &record_aad(cid), stored)
+    }
+
+    fn plaintext(&self, cid: &Cid, stored: Vec<u8>) -> Result<Vec<u8>, BlockstoreErrors> {
+        Ok(match self.open_block(cid, stored)? {
+            Stored::Current(block) | Stored::Stale(block) | Stored::Corrupt(block) => block,
+        })
+    }
+
+    // rewrites the blocks and records of cids that are not under the current key and
+    // cipher, returns for how many cids
+    pub async fn reencrypt(&self, cids: &[Cid]) -> Result<usize, BlockstoreErrors> {
+        let mut rewritten = 0;
+        for cid in cids {
+            // deleted since it was listed
+            let Some(stored) = self.inner.get_block(cid).await? else {
+                continue;
+            };
+            let mut stale = false;
+            if let Stored::Stale(block) = self.open_block(cid, stored)? {
+                self.inner.put_block(cid, &self.seal_block(cid, &block)?).await?;
+                stale = true;
+            }
+            if let Some(stored) = self.inner.get_record(cid).await? {
+                if let Stored::Stale(record) = self.open_record(cid, stored)? {
+                    self.inner.put_record(cid, &self.seal_record(cid, &record)?).await?;
+                    stale = true;
+                }
+            }
+            rewritten += usize::from(stale);
+        }
+        Ok(rewritten)
+    }
+}
+
+#[async_trait]
+impl Blockstore for EncryptedBlockstore {
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        let stored = self.inner.get_block(cid).await?;
+        stored.map(|stored| self.plaintext(cid, stored)).transpose()
+    }
+
+    async fn get_block_uncached(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        let stored = self.inner.get_block_uncached(cid).await?;
+        stored.map(|stored| self.plaintext(cid, stored)).transpose()
+    }
+
+    async fn put_block(&self, cid: &Cid, block: &[u8]) -> Result<(), BlockstoreErrors> {
+        self.inner.put_block(cid, &self.seal_block(cid, block)?).await
+    }
+
+    async fn get_record(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        let Some(stored) = self.inner.get_record(cid).await? else {
+            return Ok(None);
+        };
+        match self.open_record(cid, stored)? {
+            Stored::Current(record) | Stored::Stale(record) => Ok(Some(record)),
+            Stored::Corrupt(_) => Err(BlockstoreErrors::CorruptedRecordError(*cid)),
+        }
+    }
+
+    async fn put_record(&self, cid: &Cid, record: &[u8]) -> Result<(), BlockstoreErrors> {
+        self.inner.put_record(cid, &self.seal_record(cid, record)?).await
+    }
+
+    async fn has(&self, cid: &Cid) -> Result<bool, BlockstoreErrors> {
+        self.inner.has(cid).await
+    }
+
+    async fn delete(&self, cid: &Cid) -> Result<(), BlockstoreErrors> {
+        self.inner.delete(cid).await
+    }
+
+    async fn list(&self) -> Result<Vec<Cid>, BlockstoreErrors> {
+        self.inner.list().await
+    }
+
+    // sizes are what is on disk, a few dozen bytes a block more than the plaintext, so
+    // nothing has to be read or decrypted to count them
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreErrors> {
+        self.inner.block_size(cid).await
+    }
+
+    fn stored_len(&self, len: usize) -> u64 {
+        self.inner.stored_len(HEADER_LEN + self.cipher.nonce_len() + len + TAG_LEN)
+    }
+
+    async fn size(&self) -> Result<u64, BlockstoreErrors> {
+        self.inner.size().await
+    }
+
+    async fn put_blocks(&self, blocks: &[EncodedBlock]) -> Result<(), BlockstoreErrors> {
+        let sealed = blocks
+            .iter()
+            .map(|block| {
+                Ok(EncodedBlock {
+                    cid: block.cid,
+                    block: self.seal_block(&block.cid, &block.block)?,
+                    record: block
+                        .record
+                        .as_ref()
+                        .map(|record| self.seal_record(&block.cid, record))
+                        .transpose()?,
+                })
+            })
+            .collect::<Result<Vec<_>, BlockstoreErrors>>()?;
+        self.inner.put_blocks(&sealed).await
+    }
+}
+
+fn record_aad(cid: &Cid) -> Vec<u8> {
+    [RECORD_AAD, &cid.to_bytes()].concat()
+}
+
+// one pass over the store, a batch every interval. gc is held off for each batch so a
+// block it frees is not written back
+pub async fn reencrypt_in_background(store: Arc<EncryptedBlockstore>, pins: Pinset, batch: usize, interval: Duration) {
+    let cids = match store.list().await {
+        Ok(cids) => cids,
+        Err(err) => {
+            eprintln!("Re-encryption could not list the store: {:?}", err);
+            return;
+        }
+    };
+    let mut rewritten = 0;
+    for cids in cids.chunks(batch.max(1)) {
+        tokio::time::sleep(interval).await;
+        let _guard = pins.pin_lock().await;
+        match store.reencrypt(cids).await {
+            Ok(count) => rewritten += count,
+            Err(err) => {
+                eprintln!("Re-encryption stopped after {} blocks: {}", rewritten, err);
+                return;
+            }
+        }
+    }
+    if rewritten > 0 {
+        eprintln!("Re-encrypted {} blocks, every block is now under key {}", rewritten, store.key.id());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::metadata::FileMetadata;
+    use crate::storage::blockstore::MemoryBlockstore;
+    use crate::storage::dag::{create_leaf, generate_merkle_tree};
+
+    fn store(inner: &Arc<dyn Blockstore>, cipher: Cipher, key: [u8; KEY_LEN], old: &[[u8; KEY_LEN]]) -> EncryptedBlockstore {
+        let old = old.iter().map(|key| EncryptionKey::new(*key)).collect();
+        EncryptedBlockstore::new(inner.clone(), cipher, EncryptionKey::new(key), old)
+    }
+
+    #[tokio::test]
+    async fn test_blocks_are_encrypted_at_rest() {
+        let inner: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        let encrypted = store(&inner, Cipher::XChaCha20Poly1305, [1; KEY_LEN], &[]);
+        let tree = generate_merkle_tree(
+            vec![create_leaf(b"customer data one"), create_leaf(b"customer data two")],
+            FileMetadata::default(),
+        )
+        .unwrap();
+        encrypted.put_many(&tree).await.unwrap();
+        for node in &tree {
+            assert_eq!(encrypted.get(&node.cid).await.unwrap().as_ref(), Some(node));
+            let stored = inner.get_block(&node.cid).await.unwrap().unwrap();
+            assert!(!verify_cid(&node.cid, &stored).unwrap());
+            assert!(!stored.windows(13).any(|window| window == b"customer data"));
+        }
+
+        // the same key with the other cipher still reads them, another key does not
+        assert!(store(&inner, Cipher::Aes256Gcm, [1; KEY_LEN], &[]).get_block(&tree[0].cid).await.is_ok());
+        let other = store(&inner, Cipher::XChaCha20Poly1305, [2; KEY_LEN], &[]);
+        assert!(matches!(
+            other.get_block(&tree[0].cid).await,
+            Err(BlockstoreErrors::UnknownKeyError { .. })
+        ));
+
+        // tampered or moved under another cid, it comes back as bytes that fail their hash
+        let mut stored = inner.get_block(&tree[0].cid).await.unwrap().unwrap();
+        inner.put_block(&tree[1].cid, &stored).await.unwrap();
+        *stored.last_mut().unwrap() ^= 1;
+        inner.put_block(&tree[0].cid, &stored).await.unwrap();
+        for node in &tree[..2] {
+            let block = encrypted.get_block(&node.cid).await.unwrap().unwrap();
+            assert!(!verify_cid(&node.cid, &block).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_records_are_encrypted_at_rest() {
+        let inner: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        let encrypted = store(&inner, Cipher::XChaCha20Poly1305, [1; KEY_LEN], &[]);
+        let metadata = FileMetadata {
+            size: 34,
+            filename: Some("customer-report.pdf".to_string()),
+            ..Default::default()
+        };
+        let tree = generate_merkle_tree(
+            vec![create_leaf(b"customer data one"), create_leaf(b"customer data two")],
+            metadata.clone(),
+        )
+        .unwrap();
+        encrypted.put_many(&tree).await.unwrap();
+        let root = tree.iter().find(|node| node.metadata.is_some()).unwrap().cid;
+        assert_eq!(encrypted.get_metadata(&root).await.unwrap(), Some(metadata.clone()));
+        let stored = inner.get_record(&root).await.unwrap().unwrap();
+        assert!(!stored.windows(15).any(|window| window == b"customer-report"));
+
+        // a sealed record does not open under another cid or as a block
+        let leaf = tree[0].cid;
+        inner.put_record(&leaf, &stored).await.unwrap();
+        assert!(matches!(
+            encrypted.get_metadata(&leaf).await,
+            Err(BlockstoreErrors::CorruptedRecordError(cid)) if cid == leaf
+        ));
+        inner.put_block(&root, &stored).await.unwrap();
+        let block = encrypted.get_block(&root).await.unwrap().unwrap();
+        assert!(!verify_cid(&root, &block).unwrap());
+
+        // one from before encryption is read as it is and sealed by a rotation pass
+        inner.put_metadata(&leaf, &metadata).await.unwrap();
+        assert_eq!(encrypted.get_metadata(&leaf).await.unwrap(), Some(metadata.clone()));
+        assert_eq!(encrypted.reencrypt(&[leaf]).await.unwrap(), 1);
+        assert_eq!(encrypted.reencrypt(&[leaf]).await.unwrap(), 0);
+        assert_ne!(inner.get_record(&leaf).await.unwrap().unwrap().first(), Some(&b'{'));
+        assert_eq!(encrypted.get_metadata(&leaf).await.unwrap(), Some(metadata));
+    }
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let inner: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        // written before encryption, then under the first key
+        let plain = create_leaf(b"plaintext from before");
+        inner.put(&plain).await.unwrap();
+        let old = create_leaf(b"under the old key");
+        store(&inner, Cipher::Aes256Gcm, [1; KEY_LEN], &[]).put(&old).await.unwrap();
+
+        let rotated = store(&inner, Cipher::XChaCha20Poly1305, [2; KEY_LEN], &[[1; KEY_LEN]]);
+        let current = create_leaf(b"under the new key");
+        rotated.put(&current).await.unwrap();
+        for node in [&plain, &old, &current] {
+            assert_eq!(rotated.get(&node.cid).await.unwrap().as_ref(), Some(node));
+        }
+        let cids = rotated.list().await.unwrap();
+        assert_eq!(rotated.reencrypt(&cids).await.unwrap(), 2);
+        assert_eq!(rotated.reencrypt(&cids).await.unwrap(), 0);
+
+        // the old key is no longer needed
+        let new_only = store(&inner, Cipher::XChaCha20Poly1305, [2; KEY_LEN], &[]);
+        for node in [&plain, &old, &current] {
+            assert_eq!(new_only.get(&node.cid).await.unwrap().as_ref(), Some(node));
+            let stored = inner.get_block(&node.cid).await.unwrap().unwrap();
+            assert!(!verify_cid(&node.cid, &stored).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_pbkdf2() {
+        // rfc 6070 style vectors for hmac-sha256
+        assert_eq!(
+            hex::encode(pbkdf2(b"password", b"salt", 1)),
+            "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"
+        );
+        assert_eq!(
+            hex::encode(pbkdf2(b"password", b"salt", 4096)),
+            "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use crate::repo::Repo;
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors, EncodedBlock};
+use async_trait::async_trait;
+use cid::Cid;
+use fjall::PartitionHandle;
+
+// the on-disk store, block bytes in the repo's "slices" and file records (json) in its
+// "metadata", both keyed by the cid string
+#[derive(Clone)]
+pub struct FjallBlockstore {
+    repo: Repo,
+}
+
+impl FjallBlockstore {
+    // blocks written in older formats are converted when the repo is opened
+    pub fn new(repo: &Repo) -> Self {
+        FjallBlockstore { repo: repo.clone() }
+    }
+
+    fn blocks(&self) -> &PartitionHandle {
+        self.repo.blocks()
+    }
+
+    fn metadata(&self) -> &PartitionHandle {
+        self.repo.metadata()
+    }
+}
+
+#[async_trait]
+impl Blockstore for FjallBlockstore {
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        Ok(self.blocks().get(cid.to_string())?.map(|value| value.to_vec()))
+    }
+
+    async fn put_block(&self, cid: &Cid, block: &[u8]) -> Result<(), BlockstoreErrors> {
+        self.blocks().insert(cid.to_string(), block)?;
+        Ok(())
+    }
+
+    async fn get_record(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        Ok(self.metadata().get(cid.to_string())?.map(|value| value.to_vec()))
+    }
+
+    async fn put_record(&self, cid: &Cid, record: &[u8]) -> Result<(), BlockstoreErrors> {
+        self.metadata().insert(cid.to_string(), record)?;
+        Ok(())
+    }
+
+    async fn has(&self, cid: &Cid) -> Result<bool, BlockstoreErrors> {
+        Ok(self.blocks().contains_key(cid.to_string())?)
+    }
+
+    async fn delete(&self, cid: &Cid) -> Result<(), BlockstoreErrors> {
+        self.blocks().remove(cid.to_string())?;
+        self.metadata().remove(cid.to_string())?;
+        Ok(())
+    }
+
+    // one batch for the whole dag, a crash before commit leaves none of it behind
+    async fn put_blocks(&self, blocks: &[EncodedBlock]) -> Result<(), BlockstoreErrors> {
+        let mut batch = self.repo.keyspace().batch();
+        for block in blocks {
+            let key = block.cid.to_string();
+            if let Some(record) = &block.record {
+                batch.insert(self.metadata(), key.clone(), record.as_slice());
+            }
+            batch.insert(self.blocks(), key, block.block.as_slice());
+        }
+        batch.commit()?;
+        Ok(())
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreErrors> {
+        Ok(self.blocks().size_of(cid.to_string())?.map(u64::from))
+    }
+
+    // keys that are not cids (left there by older code) are skipped
+    async fn list(&self) -> Result<Vec<Cid>, BlockstoreErrors> {
+        let mut cids = Vec::new();
+        for key in self.blocks().keys() {
+            let key = key?;
+            if let Some(cid) = std::str::from_utf8(&key).ok().and_then(|key| Cid::try_from(key).ok()) {
+                cids.push(cid);
+            }
+        }
+        Ok(cids)
+    }
+}
@@ -0,0 +1,219 @@
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use async_trait::async_trait;
+use cid::multibase::{self, Base};
+use cid::Cid;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+const EXTENSION: &str = "data";
+const METADATA_EXTENSION: &str = "meta";
+// what kubo writes to describe the layout, tools that understand flatfs read it
+const SHARDING: &str = "/repo/flatfs/shard/v1/next-to-last/2\n";
+// the folder's version, the blocks here are not in the repo so the repo version says
+// nothing about them
+const VERSION_FILE: &str = "VERSION";
+
+// the newest block folder this binary can open
+pub const FLATFS_VERSION: u32 = 1;
+
+/*
+tldr; how it works
+one file per block, the same layout kubo's flatfs uses with its default next-to-last/2 shard:
+the key is the cid bytes in lower case base32 (no multibase prefix, so it is safe on case
+insensitive filesystems and works for v0 and v1 alike) and a block lives at
+    <root>/<second and third to last chars of the key>/<key>.data
+the file holds the canonical block bytes, a file record sits beside it in <key>.meta (json).
+writes go to a temp file in the same shard, get fsynced and are then renamed over the
+target, so a reader (or an rsync) never sees half a block. there is nothing to commit
+several files at once, a dag is written block by block children first (see put_many).
+the folder is versioned like the repo, in <root>/VERSION, written when the folder is
+created. version 1 is the layout above, a folder newer than FLATFS_VERSION is refused. a
+later layout change adds its migration here the way the repo does.
+*/
+#[derive(Debug, Clone)]
+pub struct FlatfsBlockstore {
+    root: PathBuf,
+}
+
+impl FlatfsBlockstore {
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self, BlockstoreErrors> {
+        std::fs::create_dir_all(root.as_ref())?;
+        let store = FlatfsBlockstore {
+            root: root.as_ref().to_path_buf(),
+        };
+        if !store.root.join("SHARDING").exists() {
+            write_atomic(&store.root, &store.root.join("SHARDING"), SHARDING.as_bytes())?;
+        }
+        store.migrate()?;
+        Ok(store)
+    }
+
+    fn migrate(&self) -> Result<(), BlockstoreErrors> {
+        match self.read_version()? {
+            None => self.write_version(FLATFS_VERSION),
+            Some(found) if found > FLATFS_VERSION => Err(BlockstoreErrors::TooNewError {
+                found,
+                supported: FLATFS_VERSION,
+            }),
+            Some(_) => Ok(()),
+        }
+    }
+
+    fn read_version(&self) -> Result<Option<u32>, BlockstoreErrors> {
+        let value = match std::fs::read_to_string(self.root.join(VERSION_FILE)) {
+            Ok(value) => value,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        match value.trim().parse() {
+            Ok(version) => Ok(Some(version)),
+            Err(_) => Err(BlockstoreErrors::InvalidVersionError(value)),
+        }
+    }
+
+    fn write_version(&self, version: u32) -> Result<(), BlockstoreErrors> {
+        write_atomic(&self.root, &self.root.join(VERSION_FILE), format!("{}\n", version).as_bytes())
+    }
+
+    fn key(cid: &Cid) -> String {
+        // drop the leading 'b' multibase prefix
+        multibase::encode(Base::Base32Lower, cid.to_bytes())[1..].to_string()
+    }
+
+    fn cid_from_key(key: &str) -> Option<Cid> {
+        let (_, bytes) = multibase::decode(format!("b{}", key)).ok()?;
+        Cid::try_from(bytes).ok()
+    }
+
+    fn shard_dir(&self, key: &str) -> PathBuf {
+        let len = key.len();
+        self.root.join(&key[len - 3..len - 1])
+    }
+
+    fn block_path(&self, cid: &Cid) -> PathBuf {
+        let key = FlatfsBlockstore::key(cid);
+        self.shard_dir(&key).join(format!("{}.{}", key, EXTENSION))
+    }
+
+    fn metadata_path(&self, cid: &Cid) -> PathBuf {
+        let key = FlatfsBlockstore::key(cid);
+        self.shard_dir(&key).join(format!("{}.{}", key, METADATA_EXTENSION))
+    }
+
+    fn write_block(&self, cid: &Cid, block: &[u8]) -> Result<(), BlockstoreErrors> {
+        let shard_dir = self.shard_dir(&FlatfsBlockstore::key(cid));
+        std::fs::create_dir_all(&shard_dir)?;
+        write_atomic(&shard_dir, &self.block_path(cid), block)
+    }
+
+    fn write_record(&self, cid: &Cid, record: &[u8]) -> Result<(), BlockstoreErrors> {
+        let shard_dir = self.shard_dir(&FlatfsBlockstore::key(cid));
+        std::fs::create_dir_all(&shard_dir)?;
+        write_atomic(&shard_dir, &self.metadata_path(cid), record)
+    }
+
+    // temp files, records and anything else that is not <key>.data is skipped
+    fn block_cids(&self) -> Result<Vec<Cid>, BlockstoreErrors> {
+        let mut cids = Vec::new();
+        for shard in std::fs::read_dir(&self.root)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for block in std::fs::read_dir(shard.path())? {
+                let name = block?.file_name();
+                let cid = name
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".data"))
+                    .and_then(FlatfsBlockstore::cid_from_key);
+                if let Some(cid) = cid {
+                    cids.push(cid);
+                }
+            }
+        }
+        Ok(cids)
+    }
+}
+
+// temp file in the target's directory, fsync, rename over the target
+pub(crate) fn write_atomic(dir: &Path, target: &Path, bytes: &[u8]) -> Result<(), BlockstoreErrors> {
+    let mut temp = tempfile::Builder::new().prefix(".put-").tempfile_in(dir)?;
+    temp.write_all(bytes)?;
+    temp.as_file().sync_all()?;
+    temp.persist(target).map_err(|err| err.error)?;
+    Ok(())
+}
+
+#[async_trait]
+impl Blockstore for FlatfsBlockstore {
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        match std::fs::read(self.block_path(cid)) {
+            Ok(block) => Ok(Some(block)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put_block(&self, cid: &Cid, block: &[u8]) -> Result<(), BlockstoreErrors> {
+        self.write_block(cid, block)
+    }
+
+    async fn get_record(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        match std::fs::read(self.metadata_path(cid)) {
+            Ok(record) => Ok(Some(record)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put_record(&self, cid: &Cid, record: &[u8]) -> Result<(), BlockstoreErrors> {
+        self.write_record(cid, record)
+    }
+
+    async fn has(&self, cid: &Cid) -> Result<bool, BlockstoreErrors> {
+        Ok(self.block_path(cid).try_exists()?)
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreErrors> {
+        match std::fs::metadata(self.block_path(cid)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete(&self, cid: &Cid) -> Result<(), BlockstoreErrors> {
+        for path in [self.block_path(cid), self.metadata_path(cid)] {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Cid>, BlockstoreErrors> {
+        self.block_cids()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FlatfsBlockstore::open(dir.path()).unwrap();
+        assert!(dir.path().join("SHARDING").is_file());
+        assert_eq!(store.read_version().unwrap(), Some(FLATFS_VERSION));
+
+        store.write_version(FLATFS_VERSION + 1).unwrap();
+        match FlatfsBlockstore::open(dir.path()) {
+            Err(BlockstoreErrors::TooNewError { found, supported }) => {
+                assert_eq!((found, supported), (FLATFS_VERSION + 1, FLATFS_VERSION))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
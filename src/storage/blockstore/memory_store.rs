@@ -0,0 +1,60 @@
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors, EncodedBlock};
+use async_trait::async_trait;
+use cid::Cid;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+// nothing touches the disk, for tests and short lived nodes
+#[derive(Debug, Default)]
+pub struct MemoryBlockstore {
+    blocks: RwLock<BTreeMap<Cid, Vec<u8>>>,
+    records: RwLock<BTreeMap<Cid, Vec<u8>>>,
+}
+
+#[async_trait]
+impl Blockstore for MemoryBlockstore {
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        Ok(self.blocks.read().unwrap().get(cid).cloned())
+    }
+
+    async fn put_block(&self, cid: &Cid, block: &[u8]) -> Result<(), BlockstoreErrors> {
+        self.blocks.write().unwrap().insert(*cid, block.to_vec());
+        Ok(())
+    }
+
+    async fn get_record(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        Ok(self.records.read().unwrap().get(cid).cloned())
+    }
+
+    async fn put_record(&self, cid: &Cid, record: &[u8]) -> Result<(), BlockstoreErrors> {
+        self.records.write().unwrap().insert(*cid, record.to_vec());
+        Ok(())
+    }
+
+    async fn has(&self, cid: &Cid) -> Result<bool, BlockstoreErrors> {
+        Ok(self.blocks.read().unwrap().contains_key(cid))
+    }
+
+    async fn delete(&self, cid: &Cid) -> Result<(), BlockstoreErrors> {
+        self.blocks.write().unwrap().remove(cid);
+        self.records.write().unwrap().remove(cid);
+        Ok(())
+    }
+
+    // both maps stay locked until the last node is in, no reader sees half a dag
+    async fn put_blocks(&self, encoded: &[EncodedBlock]) -> Result<(), BlockstoreErrors> {
+        let mut blocks = self.blocks.write().unwrap();
+        let mut records = self.records.write().unwrap();
+        for block in encoded {
+            blocks.insert(block.cid, block.block.clone());
+            if let Some(record) = &block.record {
+                records.insert(block.cid, record.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Cid>, BlockstoreErrors> {
+        Ok(self.blocks.read().unwrap().keys().copied().collect())
+    }
+}
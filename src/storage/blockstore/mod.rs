@@ -0,0 +1,340 @@
+pub mod cache_store;
+pub mod encrypted_store;
+pub mod fjall_store;
+pub mod flatfs_store;
+pub mod memory_store;
+pub mod quota_store;
+
+use crate::cid::builder::verify_cid;
+use crate::storage::dag::DagErrors;
+use crate::storage::metadata::FileMetadata;
+use crate::storage::MerkleNode;
+use async_trait::async_trait;
+use cid::Cid;
+use thiserror::Error;
+
+pub use cache_store::{CacheStats, CachedBlockstore};
+pub use encrypted_store::{reencrypt_in_background, Cipher, EncryptedBlockstore, EncryptionKey};
+pub use fjall_store::FjallBlockstore;
+pub use flatfs_store::FlatfsBlockstore;
+pub use memory_store::MemoryBlockstore;
+pub use quota_store::QuotaBlockstore;
+
+#[derive(Debug, Error)]
+pub enum BlockstoreErrors {
+    #[error("The block {0} is not stored here")]
+    NotFoundError(Cid),
+    #[error("The block {0} does not match its hash")]
+    CorruptedError(Cid),
+    #[error("Error talking to the store: {0}")]
+    StorageError(#[from] fjall::Error),
+    #[error("Error (de)serializing a record: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Error reading or writing a block file: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Error decoding a block: {0}")]
+    DecodeError(#[from] DagErrors),
+    #[error("Storing {needed} more bytes would go over the storage limit, {available} bytes are left")]
+    QuotaExceededError { needed: u64, available: u64 },
+    #[error("Block {cid} is encrypted with key {key_id}, which is not configured")]
+    UnknownKeyError { cid: Cid, key_id: String },
+    #[error("Error encrypting block {0}")]
+    EncryptionError(Cid),
+    #[error("The record of {0} does not decrypt, it was changed on disk")]
+    CorruptedRecordError(Cid),
+    #[error("The block folder is at version {found}, this build only understands up to {supported}")]
+    TooNewError { found: u32, supported: u32 },
+    #[error("The block folder version {0:?} is not a number")]
+    InvalidVersionError(String),
+}
+
+/*
+tldr; how it works
+everything above the store (import, reassembly, directories, the resolver, the gateway)
+gets one of these by handle instead of opening the repo itself, so a process can run
+several nodes side by side and tests can use a throwaway in-memory store.
+
+blocks are kept in their canonical encoding, exactly the bytes the cid hashes (raw chunk
+bytes, dag-pb protobuf), so they can be sent to peers and checked as they are.
+a file record is not part of any block and sits next to it under the same cid.
+backends only implement the byte level calls, records as json bytes, get/put of whole
+nodes and decoded records is built on top.
+checking blocks against their cid is left to callers.
+*/
+#[async_trait]
+pub trait Blockstore: Send + Sync {
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors>;
+    async fn put_block(&self, cid: &Cid, block: &[u8]) -> Result<(), BlockstoreErrors>;
+    // a file record as its stored bytes, see get_metadata for the decoded one
+    async fn get_record(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors>;
+    async fn put_record(&self, cid: &Cid, record: &[u8]) -> Result<(), BlockstoreErrors>;
+    async fn has(&self, cid: &Cid) -> Result<bool, BlockstoreErrors>;
+    // drops the block and its record
+    async fn delete(&self, cid: &Cid) -> Result<(), BlockstoreErrors>;
+    async fn list(&self) -> Result<Vec<Cid>, BlockstoreErrors>;
+
+    // what is on disk right now, past any cache on the way, for checks like scrub
+    async fn get_block_uncached(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        self.get_block(cid).await
+    }
+
+    // bytes the block takes in the store, backends that can tell without reading it
+    // (value lengths, file sizes) override this
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreErrors> {
+        Ok(self.get_block(cid).await?.map(|block| block.len() as u64))
+    }
+
+    // bytes a block of len bytes will take in the store once written, more than len for
+    // layers that add to it (encryption)
+    fn stored_len(&self, len: usize) -> u64 {
+        len as u64
+    }
+
+    // bytes of block data held as stored, records not counted
+    async fn size(&self) -> Result<u64, BlockstoreErrors> {
+        let mut size = 0;
+        for cid in self.list().await? {
+            size += self.block_size(&cid).await?.unwrap_or(0);
+        }
+        Ok(size)
+    }
+
+    async fn get_metadata(&self, cid: &Cid) -> Result<Option<FileMetadata>, BlockstoreErrors> {
+        match self.get_record(cid).await? {
+            Some(record) => Ok(Some(serde_json::from_slice(&record)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_metadata(&self, cid: &Cid, metadata: &FileMetadata) -> Result<(), BlockstoreErrors> {
+        self.put_record(cid, &serde_json::to_vec(metadata)?).await
+    }
+
+    async fn get(&self, cid: &Cid) -> Result<Option<MerkleNode>, BlockstoreErrors> {
+        let Some(block) = self.get_block(cid).await? else {
+            return Ok(None);
+        };
+        let mut node = MerkleNode::decode(*cid, &block)?;
+        node.metadata = self.get_metadata(cid).await?;
+        Ok(Some(node))
+    }
+
+    // a node without a record leaves any record already stored for the cid alone
+    async fn put(&self, node: &MerkleNode) -> Result<(), BlockstoreErrors> {
+        self.put_block(&node.cid, &node.encode()?).await?;
+        if let Some(metadata) = &node.metadata {
+            self.put_metadata(&node.cid, metadata).await?;
+        }
+        Ok(())
+    }
+
+    /*
+    tldr; how it works
+    a whole dag goes in with one call and is meant to land all-or-nothing. every node is
+    encoded before anything is written, so a bad node fails the call with the store
+    untouched, and the encoded blocks go on to put_blocks. backends that can commit many
+    writes at once (fjall batches, a lock over the maps) and wrappers override that one,
+    the rest write in the order given, so passing children before parents means an
+    interrupted write leaves loose blocks but never a root.
+    */
+    async fn put_many(&self, nodes: &[MerkleNode]) -> Result<(), BlockstoreErrors> {
+        self.put_blocks(&encode_all(nodes)?).await
+    }
+
+    async fn put_blocks(&self, blocks: &[EncodedBlock]) -> Result<(), BlockstoreErrors> {
+        for block in blocks {
+            self.put_block(&block.cid, &block.block).await?;
+            if let Some(record) = &block.record {
+                self.put_record(&block.cid, record).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// a node of a put_many, encoded and ready to be written
+pub struct EncodedBlock {
+    pub cid: Cid,
+    pub block: Vec<u8>,
+    pub record: Option<Vec<u8>>,
+}
+
+pub(crate) fn encode_all(nodes: &[MerkleNode]) -> Result<Vec<EncodedBlock>, BlockstoreErrors> {
+    nodes
+        .iter()
+        .map(|node| {
+            Ok(EncodedBlock {
+                cid: node.cid,
+                block: node.encode()?,
+                record: node.metadata.as_ref().map(serde_json::to_vec).transpose()?,
+            })
+        })
+        .collect()
+}
+
+pub(crate) enum LegacyRecord {
+    Canonical,
+    Upgraded(Vec<u8>, Option<FileMetadata>),
+    // not a record we can turn into the block its cid names, e.g. the made up parent
+    // cids of the very first tree format. left where it is, reads of it fail to decode
+    Unreadable,
+}
+
+/*
+tldr; how it works
+stores written before blocks were kept in binary hold one serde_json MerkleNode per cid.
+a value that already hashes to its cid is a canonical block and left alone, anything else
+is parsed as one of those old records and split into block bytes and file record, but
+only if the bytes it encodes to really hash to the cid.
+*/
+pub(crate) fn upgrade_legacy_record(cid: &Cid, value: &[u8]) -> LegacyRecord {
+    if verify_cid(cid, value).unwrap_or(false) {
+        return LegacyRecord::Canonical;
+    }
+    let Ok(node) = serde_json::from_slice::<MerkleNode>(value) else {
+        return LegacyRecord::Unreadable;
+    };
+    match node.encode() {
+        Ok(block) if verify_cid(cid, &block).unwrap_or(false) => LegacyRecord::Upgraded(block, node.metadata),
+        _ => LegacyRecord::Unreadable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::create_leaf;
+
+    // the same behaviour is expected from every backend
+    async fn exercise(store: &dyn Blockstore) {
+        let first = create_leaf(b"first block");
+        let second = create_leaf(b"second block");
+
+        assert_eq!(store.get(&first.cid).await.unwrap(), None);
+        assert!(!store.has(&first.cid).await.unwrap());
+
+        store.put(&first).await.unwrap();
+        assert!(store.has(&first.cid).await.unwrap());
+        assert_eq!(store.get(&first.cid).await.unwrap(), Some(first.clone()));
+        // a raw leaf is stored as nothing but its bytes
+        assert_eq!(store.get_block(&first.cid).await.unwrap(), Some(b"first block".to_vec()));
+
+        // a record survives the same block being put again without one
+        let mut with_record = first.clone();
+        with_record.metadata = Some(FileMetadata {
+            size: 11,
+            ..Default::default()
+        });
+        store.put(&with_record).await.unwrap();
+        store.put(&first).await.unwrap();
+        assert_eq!(store.get(&first.cid).await.unwrap(), Some(with_record.clone()));
+
+        // nothing is written when one node of the batch can not be encoded
+        let mut unsupported = create_leaf(b"third block");
+        unsupported.cid = Cid::new_v1(0x71, *unsupported.cid.hash());
+        assert!(store.put_many(&[second.clone(), unsupported]).await.is_err());
+        assert!(!store.has(&second.cid).await.unwrap());
+
+        store.put_many(&[first.clone(), second.clone()]).await.unwrap();
+        let mut listed = store.list().await.unwrap();
+        listed.sort();
+        let mut expected = vec![first.cid, second.cid];
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        store.delete(&first.cid).await.unwrap();
+        assert!(!store.has(&first.cid).await.unwrap());
+        assert_eq!(store.get_metadata(&first.cid).await.unwrap(), None);
+        assert_eq!(store.list().await.unwrap(), vec![second.cid]);
+        // deleting what is not there is fine
+        store.delete(&first.cid).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_blockstore() {
+        exercise(&MemoryBlockstore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_fjall_blockstore() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = crate::repo::Repo::open(dir.path()).unwrap();
+        let store = FjallBlockstore::new(&repo);
+        exercise(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_blockstore() {
+        let inner = std::sync::Arc::new(MemoryBlockstore::default());
+        let store = EncryptedBlockstore::new(inner, Cipher::default(), EncryptionKey::generate(), Vec::new());
+        exercise(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_flatfs_blockstore() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FlatfsBlockstore::open(dir.path()).unwrap();
+        exercise(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_flatfs_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FlatfsBlockstore::open(dir.path()).unwrap();
+        let leaf = create_leaf(b"on disk");
+        store.put(&leaf).await.unwrap();
+        // v0 cids are keyed the same way and come back as v0
+        let v0 = crate::storage::dag::leaf_of(b"old style".to_vec(), &crate::cid::CidBuilder::v0()).unwrap();
+        store.put(&v0).await.unwrap();
+
+        let key = &cid::multibase::encode(cid::multibase::Base::Base32Lower, leaf.cid.to_bytes())[1..];
+        let shard = &key[key.len() - 3..key.len() - 1];
+        let path = dir.path().join(shard).join(format!("{}.data", key));
+        assert!(path.is_file());
+        // a leftover temp file is not a block
+        std::fs::write(dir.path().join(shard).join(".put-leftover"), b"junk").unwrap();
+
+        let mut listed = store.list().await.unwrap();
+        listed.sort();
+        let mut expected = vec![leaf.cid, v0.cid];
+        expected.sort();
+        assert_eq!(listed, expected);
+        assert_eq!(store.get(&v0.cid).await.unwrap(), Some(v0));
+    }
+
+    #[test]
+    fn test_upgrade_legacy_record() {
+        let mut node = create_leaf(b"legacy");
+        node.metadata = Some(FileMetadata {
+            size: 6,
+            ..Default::default()
+        });
+        let legacy = serde_json::to_vec(&node).unwrap();
+        match upgrade_legacy_record(&node.cid, &legacy) {
+            LegacyRecord::Upgraded(block, metadata) => {
+                assert_eq!(block, b"legacy");
+                assert_eq!(metadata, node.metadata);
+            }
+            _ => panic!("record was not upgraded"),
+        }
+        // canonical blocks are left alone, even ones that happen to look like json
+        assert!(matches!(upgrade_legacy_record(&node.cid, b"legacy"), LegacyRecord::Canonical));
+        let json_leaf = create_leaf(b"{}");
+        assert!(matches!(upgrade_legacy_record(&json_leaf.cid, b"{}"), LegacyRecord::Canonical));
+        // a record that does not encode to its cid stays unreadable
+        let mut forged = node.clone();
+        forged.data = Some(b"something else".to_vec());
+        let forged = serde_json::to_vec(&forged).unwrap();
+        assert!(matches!(upgrade_legacy_record(&node.cid, &forged), LegacyRecord::Unreadable));
+    }
+
+    #[tokio::test]
+    async fn test_stores_are_independent() {
+        let one = MemoryBlockstore::default();
+        let two = MemoryBlockstore::default();
+        let leaf = create_leaf(b"only in one");
+        one.put(&leaf).await.unwrap();
+        assert!(one.has(&leaf.cid).await.unwrap());
+        assert!(!two.has(&leaf.cid).await.unwrap());
+    }
+}
@@ -0,0 +1,240 @@
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors, EncodedBlock};
+use async_trait::async_trait;
+use cid::Cid;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{Mutex, Notify};
+
+/*
+tldr; how it works
+wraps any other store and keeps a running count of the block bytes in it (counted once
+on open, then kept up to date by every put and delete), as stored: with encryption
+under it that is the ciphertext, and sizes come from the store without reading blocks.
+a write reserves its bytes first and is refused with QuotaExceededError if that would go
+over storage_max, a put_many reserves the whole dag so a file is either stored or
+rejected as a whole. writes and deletes go one at a time, so two puts of the same cid
+can not both find it missing and both pay for it.
+going over the high watermark wakes whoever waits in high_watermark_crossed (the gc
+task), which frees blocks until usage is under the low watermark again.
+*/
+pub struct QuotaBlockstore {
+    inner: Arc<dyn Blockstore>,
+    storage_max: u64,
+    high_watermark: u64,
+    low_watermark: u64,
+    used: AtomicU64,
+    crossed: Notify,
+    writes: Mutex<()>,
+}
+
+impl QuotaBlockstore {
+    // watermarks are percentages of storage_max
+    pub async fn new(
+        inner: Arc<dyn Blockstore>,
+        storage_max: u64,
+        high_watermark: u8,
+        low_watermark: u8,
+    ) -> Result<Self, BlockstoreErrors> {
+        let used = inner.size().await?;
+        let store = QuotaBlockstore {
+            inner,
+            storage_max,
+            high_watermark: percent_of(storage_max, high_watermark),
+            low_watermark: percent_of(storage_max, low_watermark),
+            used: AtomicU64::new(used),
+            crossed: Notify::new(),
+            writes: Mutex::new(()),
+        };
+        if used > store.high_watermark {
+            store.crossed.notify_one();
+        }
+        Ok(store)
+    }
+
+    pub fn usage(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    pub fn low_watermark(&self) -> u64 {
+        self.low_watermark
+    }
+
+    // resolves once usage has gone over the high watermark since the last call
+    pub async fn high_watermark_crossed(&self) {
+        self.crossed.notified().await
+    }
+
+    fn reserve(&self, needed: u64) -> Result<(), BlockstoreErrors> {
+        let before = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                (used + needed <= self.storage_max).then_some(used + needed)
+            })
+            .map_err(|used| BlockstoreErrors::QuotaExceededError {
+                needed,
+                available: self.storage_max.saturating_sub(used),
+            })?;
+        if before <= self.high_watermark && before + needed > self.high_watermark {
+            self.crossed.notify_one();
+        }
+        Ok(())
+    }
+
+    fn release(&self, bytes: u64) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| Some(used.saturating_sub(bytes)));
+    }
+
+    // blocks already stored cost nothing
+    async fn new_bytes(&self, cid: &Cid, block: &[u8]) -> Result<u64, BlockstoreErrors> {
+        Ok(match self.inner.has(cid).await? {
+            true => 0,
+            false => self.inner.stored_len(block.len()),
+        })
+    }
+}
+
+fn percent_of(bytes: u64, percent: u8) -> u64 {
+    (bytes as u128 * percent as u128 / 100) as u64
+}
+
+#[async_trait]
+impl Blockstore for QuotaBlockstore {
+    async fn get_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        self.inner.get_block(cid).await
+    }
+
+    async fn get_block_uncached(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        self.inner.get_block_uncached(cid).await
+    }
+
+    async fn put_block(&self, cid: &Cid, block: &[u8]) -> Result<(), BlockstoreErrors> {
+        let _writing = self.writes.lock().await;
+        let needed = self.new_bytes(cid, block).await?;
+        self.reserve(needed)?;
+        let result = self.inner.put_block(cid, block).await;
+        if result.is_err() {
+            self.release(needed);
+        }
+        result
+    }
+
+    async fn get_record(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+        self.inner.get_record(cid).await
+    }
+
+    async fn put_record(&self, cid: &Cid, record: &[u8]) -> Result<(), BlockstoreErrors> {
+        self.inner.put_record(cid, record).await
+    }
+
+    async fn has(&self, cid: &Cid) -> Result<bool, BlockstoreErrors> {
+        self.inner.has(cid).await
+    }
+
+    async fn delete(&self, cid: &Cid) -> Result<(), BlockstoreErrors> {
+        let _writing = self.writes.lock().await;
+        let freed = self.inner.block_size(cid).await?.unwrap_or(0);
+        self.inner.delete(cid).await?;
+        self.release(freed);
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Cid>, BlockstoreErrors> {
+        self.inner.list().await
+    }
+
+    async fn block_size(&self, cid: &Cid) -> Result<Option<u64>, BlockstoreErrors> {
+        self.inner.block_size(cid).await
+    }
+
+    fn stored_len(&self, len: usize) -> u64 {
+        self.inner.stored_len(len)
+    }
+
+    async fn size(&self) -> Result<u64, BlockstoreErrors> {
+        Ok(self.usage())
+    }
+
+    async fn put_blocks(&self, blocks: &[EncodedBlock]) -> Result<(), BlockstoreErrors> {
+        let _writing = self.writes.lock().await;
+        let mut needed = 0;
+        // a chunk repeated in the file is stored once, so it is paid for once
+        let mut seen = HashSet::new();
+        for block in blocks {
+            if seen.insert(block.cid) {
+                needed += self.new_bytes(&block.cid, &block.block).await?;
+            }
+        }
+        self.reserve(needed)?;
+        let result = self.inner.put_blocks(blocks).await;
+        if result.is_err() {
+            self.release(needed);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::blockstore::{Cipher, EncryptedBlockstore, EncryptionKey, MemoryBlockstore};
+    use crate::storage::create_leaf;
+
+    #[tokio::test]
+    async fn test_quota() {
+        let inner: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        inner.put(&create_leaf(b"already here")).await.unwrap();
+        let store = QuotaBlockstore::new(inner, 100, 50, 20).await.unwrap();
+        assert_eq!(store.usage(), 12);
+
+        let fill = create_leaf(&[7; 40]);
+        store.put(&fill).await.unwrap();
+        // the same block again costs nothing
+        store.put(&fill).await.unwrap();
+        assert_eq!(store.usage(), 52);
+        // 52 went over the high watermark of 50
+        tokio::time::timeout(std::time::Duration::from_secs(1), store.high_watermark_crossed())
+            .await
+            .unwrap();
+
+        let too_big = [create_leaf(&[1; 30]), create_leaf(&[2; 30])];
+        match store.put_many(&too_big).await {
+            Err(BlockstoreErrors::QuotaExceededError { needed, available }) => {
+                assert_eq!((needed, available), (60, 48))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // none of the rejected dag was written
+        assert!(!store.has(&too_big[0].cid).await.unwrap());
+        assert_eq!(store.usage(), 52);
+
+        store.delete(&fill.cid).await.unwrap();
+        assert_eq!(store.usage(), 12);
+        store.put_many(&too_big).await.unwrap();
+        assert_eq!(store.usage(), 72);
+
+        // a chunk that shows up twice in one dag is paid for, and given back, once
+        let repeated = create_leaf(&[0; 10]);
+        store.put_many(&[repeated.clone(), repeated.clone()]).await.unwrap();
+        assert_eq!(store.usage(), 82);
+        store.delete(&repeated.cid).await.unwrap();
+        assert_eq!(store.usage(), 72);
+    }
+
+    #[tokio::test]
+    async fn test_counts_encrypted_bytes() {
+        let disk: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        let encrypted = EncryptedBlockstore::new(disk.clone(), Cipher::default(), EncryptionKey::generate(), Vec::new());
+        let encrypted: Arc<dyn Blockstore> = Arc::new(encrypted);
+        encrypted.put(&create_leaf(&[1; 100])).await.unwrap();
+        let store = QuotaBlockstore::new(encrypted, 10_000, 90, 70).await.unwrap();
+        store.put(&create_leaf(&[2; 100])).await.unwrap();
+        // what was counted on open and what was added since are the same unit
+        assert_eq!(store.usage(), disk.size().await.unwrap());
+        assert!(store.usage() > 200);
+        store.delete(&create_leaf(&[1; 100]).cid).await.unwrap();
+        assert_eq!(store.usage(), disk.size().await.unwrap());
+    }
+}
@@ -0,0 +1,486 @@
+use crate::cid::builder::verify_cid;
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::dag::DagErrors;
+use crate::storage::dag_pb::write_varint;
+use crate::storage::MerkleNode;
+use cid::Cid;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Cursor;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// the cbor header {"version": 2} every carv2 starts with, length prefix included
+const V2_PRAGMA: [u8; 11] = [0x0a, 0xa1, 0x67, b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x02];
+// characteristics (16 bytes), data offset, data size, index offset
+const V2_HEADER_LEN: usize = 40;
+const MULTIHASH_INDEX_SORTED: u64 = 0x0401;
+// way past any real block or header, a bigger length is a broken (or hostile) file
+const MAX_SECTION: u64 = 8 << 20;
+const MAX_HEADER: u64 = 1 << 20;
+
+// multihash code -> entry width (digest length + 8) -> (digest, section offset)
+type Index = BTreeMap<u64, BTreeMap<usize, Vec<(Vec<u8>, u64)>>>;
+
+#[derive(Debug, Error)]
+pub enum CarErrors {
+    #[error("Error talking to the store: {0}")]
+    StorageError(#[from] BlockstoreErrors),
+    #[error("Error reading or writing the car: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Error decoding a block: {0}")]
+    DecodeError(#[from] DagErrors),
+    #[error("The block {0} is not stored here")]
+    NotFoundError(Cid),
+    #[error("The block {0} does not match its hash")]
+    CorruptedBlockError(Cid),
+    #[error("Invalid car header: {0}")]
+    InvalidHeaderError(String),
+    #[error("Invalid car section: {0}")]
+    InvalidSectionError(String),
+    #[error("Car version {0} is not supported")]
+    UnsupportedVersionError(u64),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CarVersion {
+    #[default]
+    V1,
+    // v1 wrapped with a header and followed by an index, so readers can seek to a block
+    V2,
+}
+
+impl TryFrom<u64> for CarVersion {
+    type Error = CarErrors;
+
+    fn try_from(version: u64) -> Result<Self, CarErrors> {
+        match version {
+            1 => Ok(CarVersion::V1),
+            2 => Ok(CarVersion::V2),
+            other => Err(CarErrors::UnsupportedVersionError(other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CarImport {
+    pub roots: Vec<Cid>,
+    pub blocks: usize,
+}
+
+/*
+tldr; how it works
+a carv1 is a length prefixed dag-cbor header {"roots": [cid], "version": 1} followed by
+one section per block, each length prefixed and holding the cid bytes then the block.
+blocks go out root first, depth first with children left to right, each one once however
+many times it is linked. every block is checked against its cid on the way out.
+
+a carv2 puts an 11 byte pragma and a 40 byte header in front of that same v1 payload and
+an index behind it (MultihashIndexSorted, what go-car writes: per multihash code and
+digest length, digests sorted with the offset of their section in the payload). the
+header has to know how long the payload is, so the dag is walked twice, once to lay
+the sections out and once to write them, memory stays at one entry per block.
+*/
+pub async fn export_car<W: AsyncWrite + Unpin>(
+    root: &Cid,
+    store: &dyn Blockstore,
+    version: CarVersion,
+    out: &mut W,
+) -> Result<u64, CarErrors> {
+    let written = match version {
+        CarVersion::V1 => write_payload(root, store, out).await?,
+        CarVersion::V2 => {
+            let mut index = Index::new();
+            let mut data_size = prefixed(&header(root)).len() as u64;
+            let mut blocks = DagBlocks::new(*root);
+            while let Some((cid, block)) = blocks.next(store).await? {
+                let digest = cid.hash().digest().to_vec();
+                let by_width = index.entry(cid.hash().code()).or_default();
+                by_width.entry(digest.len() + 8).or_default().push((digest, data_size));
+                data_size += section(&cid, &block).len() as u64;
+            }
+            let data_offset = (V2_PRAGMA.len() + V2_HEADER_LEN) as u64;
+            let mut start = V2_PRAGMA.to_vec();
+            start.extend([0; 16]);
+            start.extend(data_offset.to_le_bytes());
+            start.extend(data_size.to_le_bytes());
+            start.extend((data_offset + data_size).to_le_bytes());
+            out.write_all(&start).await?;
+            write_payload(root, store, out).await?;
+            let index = encode_index(index);
+            out.write_all(&index).await?;
+            data_offset + data_size + index.len() as u64
+        }
+    };
+    out.flush().await?;
+    Ok(written)
+}
+
+async fn write_payload<W: AsyncWrite + Unpin>(
+    root: &Cid,
+    store: &dyn Blockstore,
+    out: &mut W,
+) -> Result<u64, CarErrors> {
+    let header = prefixed(&header(root));
+    out.write_all(&header).await?;
+    let mut written = header.len() as u64;
+    let mut blocks = DagBlocks::new(*root);
+    while let Some((cid, block)) = blocks.next(store).await? {
+        let section = section(&cid, &block);
+        out.write_all(&section).await?;
+        written += section.len() as u64;
+    }
+    Ok(written)
+}
+
+// pre order over the dag, a cid already handed out (or about to be) is not visited again
+struct DagBlocks {
+    stack: Vec<Cid>,
+    seen: BTreeSet<Cid>,
+}
+
+impl DagBlocks {
+    fn new(root: Cid) -> Self {
+        DagBlocks {
+            stack: vec![root],
+            seen: BTreeSet::from([root]),
+        }
+    }
+
+    async fn next(&mut self, store: &dyn Blockstore) -> Result<Option<(Cid, Vec<u8>)>, CarErrors> {
+        let Some(cid) = self.stack.pop() else {
+            return Ok(None);
+        };
+        let block = store.get_block(&cid).await?.ok_or(CarErrors::NotFoundError(cid))?;
+        if !verify_cid(&cid, &block).unwrap_or(false) {
+            return Err(CarErrors::CorruptedBlockError(cid));
+        }
+        let node = MerkleNode::decode(cid, &block)?;
+        let children: Vec<Cid> = node
+            .links
+            .iter()
+            .map(|link| link.cid)
+            .filter(|child| self.seen.insert(*child))
+            .collect();
+        self.stack.extend(children.into_iter().rev());
+        Ok(Some((cid, block)))
+    }
+}
+
+fn prefixed(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len() + 4);
+    write_varint(&mut out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+    out
+}
+
+fn section(cid: &Cid, block: &[u8]) -> Vec<u8> {
+    let mut body = cid.to_bytes();
+    body.extend_from_slice(block);
+    prefixed(&body)
+}
+
+fn encode_index(index: Index) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, MULTIHASH_INDEX_SORTED);
+    out.extend((index.len() as i32).to_le_bytes());
+    for (code, by_width) in index {
+        out.extend(code.to_le_bytes());
+        out.extend((by_width.len() as i32).to_le_bytes());
+        for (width, mut entries) in by_width {
+            entries.sort();
+            out.extend((width as u32).to_le_bytes());
+            out.extend(((entries.len() * width) as u64).to_le_bytes());
+            for (digest, offset) in entries {
+                out.extend(digest);
+                out.extend(offset.to_le_bytes());
+            }
+        }
+    }
+    out
+}
+
+// the few bits of dag-cbor a car header needs
+fn cbor_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => out.push(major | value as u8),
+        24..=0xff => out.extend([major | 24, value as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((value as u16).to_be_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((value as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(value.to_be_bytes());
+        }
+    }
+}
+
+fn cbor_text(out: &mut Vec<u8>, text: &str) {
+    cbor_head(out, 3, text.len() as u64);
+    out.extend_from_slice(text.as_bytes());
+}
+
+// {"roots": [root], "version": 1}, keys in dag-cbor order
+fn header(root: &Cid) -> Vec<u8> {
+    let mut out = Vec::new();
+    cbor_head(&mut out, 5, 2);
+    cbor_text(&mut out, "roots");
+    cbor_head(&mut out, 4, 1);
+    // a cid is tag 42 over its bytes behind a 0x00 (identity multibase)
+    let cid = root.to_bytes();
+    cbor_head(&mut out, 6, 42);
+    cbor_head(&mut out, 2, cid.len() as u64 + 1);
+    out.push(0);
+    out.extend(cid);
+    cbor_text(&mut out, "version");
+    cbor_head(&mut out, 0, 1);
+    out
+}
+
+struct CborReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> CborReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CarErrors> {
+        if self.bytes.len() < len {
+            return Err(CarErrors::InvalidHeaderError("cut short".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn head(&mut self, major: u8) -> Result<u64, CarErrors> {
+        let first = self.take(1)?[0];
+        if first >> 5 != major {
+            return Err(CarErrors::InvalidHeaderError(format!("expected major type {}", major)));
+        }
+        let value = match first & 0x1f {
+            small @ 0..=23 => small as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            _ => return Err(CarErrors::InvalidHeaderError("indefinite lengths".to_string())),
+        };
+        Ok(value)
+    }
+
+    fn text(&mut self) -> Result<&'a str, CarErrors> {
+        let len = self.head(3)? as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|err| CarErrors::InvalidHeaderError(err.to_string()))
+    }
+
+    fn cid(&mut self) -> Result<Cid, CarErrors> {
+        if self.head(6)? != 42 {
+            return Err(CarErrors::InvalidHeaderError("a root is not a cid".to_string()));
+        }
+        let len = self.head(2)? as usize;
+        match self.take(len)? {
+            [0, cid @ ..] => Cid::try_from(cid).map_err(|err| CarErrors::InvalidHeaderError(err.to_string())),
+            _ => Err(CarErrors::InvalidHeaderError("a root is not a cid".to_string())),
+        }
+    }
+}
+
+// the version and, for a v1 header, the roots
+fn decode_header(bytes: &[u8]) -> Result<(u64, Option<Vec<Cid>>), CarErrors> {
+    let mut reader = CborReader { bytes };
+    let mut version = None;
+    let mut roots = None;
+    for _ in 0..reader.head(5)? {
+        match reader.text()? {
+            "version" => version = Some(reader.head(0)?),
+            "roots" => {
+                let count = reader.head(4)?;
+                roots = Some((0..count).map(|_| reader.cid()).collect::<Result<Vec<_>, _>>()?);
+            }
+            other => return Err(CarErrors::InvalidHeaderError(format!("unknown key {:?}", other))),
+        }
+    }
+    let version = version.ok_or_else(|| CarErrors::InvalidHeaderError("no version".to_string()))?;
+    Ok((version, roots))
+}
+
+// None on a clean end of input
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<u64>, CarErrors> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        if reader.read(&mut byte).await? == 0 {
+            return match shift {
+                0 => Ok(None),
+                _ => Err(CarErrors::InvalidSectionError("cut short in a length".to_string())),
+            };
+        }
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(CarErrors::InvalidSectionError("a length does not fit in 64 bits".to_string()))
+}
+
+async fn read_header<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<(u64, Option<Vec<Cid>>), CarErrors> {
+    let len = read_varint(reader)
+        .await?
+        .ok_or_else(|| CarErrors::InvalidHeaderError("empty car".to_string()))?;
+    if len > MAX_HEADER {
+        return Err(CarErrors::InvalidHeaderError(format!("{} bytes long", len)));
+    }
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes).await?;
+    decode_header(&bytes)
+}
+
+/*
+tldr; how it works
+reads a carv1 or carv2 (told apart by the first header) front to back, so it works on a
+socket as well as a file. a v2's index is not needed to read it in order and is skipped.
+every block is hashed and checked against its cid, and must be a codec this node can
+read, before it is written. a bad block stops the import, blocks written before it stay
+(they are valid, and unpinned until the caller pins the roots).
+*/
+pub async fn import_car<R: AsyncRead + Unpin>(
+    mut reader: R,
+    store: &dyn Blockstore,
+) -> Result<CarImport, CarErrors> {
+    let (roots, mut payload): (_, Box<dyn AsyncRead + Unpin + '_>) = match read_header(&mut reader).await? {
+        (1, Some(roots)) => (roots, Box::new(reader)),
+        (2, _) => {
+            let mut header = [0u8; V2_HEADER_LEN];
+            reader.read_exact(&mut header).await?;
+            let data_offset = u64::from_le_bytes(header[16..24].try_into().unwrap());
+            let data_size = u64::from_le_bytes(header[24..32].try_into().unwrap());
+            let padding = data_offset
+                .checked_sub((V2_PRAGMA.len() + V2_HEADER_LEN) as u64)
+                .ok_or_else(|| CarErrors::InvalidHeaderError("data offset inside the header".to_string()))?;
+            tokio::io::copy(&mut (&mut reader).take(padding), &mut tokio::io::sink()).await?;
+            let mut payload = reader.take(data_size);
+            match read_header(&mut payload).await? {
+                (1, Some(roots)) => (roots, Box::new(payload)),
+                (1, None) => return Err(CarErrors::InvalidHeaderError("no roots".to_string())),
+                (other, _) => return Err(CarErrors::UnsupportedVersionError(other)),
+            }
+        }
+        (1, None) => return Err(CarErrors::InvalidHeaderError("no roots".to_string())),
+        (other, _) => return Err(CarErrors::UnsupportedVersionError(other)),
+    };
+
+    let mut blocks = 0;
+    while let Some(len) = read_varint(&mut payload).await? {
+        if len > MAX_SECTION {
+            return Err(CarErrors::InvalidSectionError(format!("{} bytes long", len)));
+        }
+        let mut section = vec![0; len as usize];
+        payload.read_exact(&mut section).await?;
+        let mut cursor = Cursor::new(&section[..]);
+        let cid = Cid::read_bytes(&mut cursor).map_err(|err| CarErrors::InvalidSectionError(err.to_string()))?;
+        let block = &section[cursor.position() as usize..];
+        if !verify_cid(&cid, block).unwrap_or(false) {
+            return Err(CarErrors::CorruptedBlockError(cid));
+        }
+        MerkleNode::decode(cid, block)?;
+        if !store.has(&cid).await? {
+            store.put_block(&cid, block).await?;
+        }
+        blocks += 1;
+    }
+    Ok(CarImport { roots, blocks })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::blockstore::MemoryBlockstore;
+    use crate::storage::import::file_dag;
+    use crate::storage::reassemble::read_range_with_handle;
+
+    async fn export(root: &Cid, store: &dyn Blockstore, version: CarVersion) -> Vec<u8> {
+        let mut out = Vec::new();
+        let written = export_car(root, store, version, &mut out).await.unwrap();
+        assert_eq!(written, out.len() as u64);
+        out
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let store = MemoryBlockstore::default();
+        // repeated chunks, exported once each
+        let data = b"abcdabcdabcd and then some more bytes to chunk".to_vec();
+        let root = file_dag(&store, &data).await;
+        for version in [CarVersion::V1, CarVersion::V2] {
+            let car = export(&root, &store, version).await;
+            let copy = MemoryBlockstore::default();
+            let imported = import_car(&car[..], &copy).await.unwrap();
+            assert_eq!(imported.roots, vec![root]);
+            assert_eq!(imported.blocks, copy.list().await.unwrap().len());
+            let len = data.len() as u64;
+            assert_eq!(read_range_with_handle(root.to_string(), 0, len, &copy).await.unwrap(), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_v2_layout_and_index() {
+        let store = MemoryBlockstore::default();
+        let root = file_dag(&store, b"indexed by digest").await;
+        let car = export(&root, &store, CarVersion::V2).await;
+        assert_eq!(car[..11], V2_PRAGMA);
+        let field = |at: usize| u64::from_le_bytes(car[at..at + 8].try_into().unwrap()) as usize;
+        let (data_offset, data_size, index_offset) = (field(27), field(35), field(43));
+        assert_eq!(data_offset, 51);
+        assert_eq!(index_offset, data_offset + data_size);
+        // the payload is the v1 export, byte for byte
+        assert_eq!(car[data_offset..index_offset], export(&root, &store, CarVersion::V1).await);
+
+        // one sha2-256 bucket of 32 byte digests, each pointing at its own section
+        let index = &car[index_offset..];
+        assert_eq!(index[..2], [0x81, 0x08]);
+        assert_eq!(i32::from_le_bytes(index[2..6].try_into().unwrap()), 1);
+        assert_eq!(u64::from_le_bytes(index[6..14].try_into().unwrap()), 0x12);
+        assert_eq!(i32::from_le_bytes(index[14..18].try_into().unwrap()), 1);
+        assert_eq!(u32::from_le_bytes(index[18..22].try_into().unwrap()), 40);
+        let entries = &index[30..];
+        assert_eq!(entries.len() as u64, u64::from_le_bytes(index[22..30].try_into().unwrap()));
+        assert_eq!(entries.len() / 40, store.list().await.unwrap().len());
+        for entry in entries.chunks(40) {
+            let offset = u64::from_le_bytes(entry[32..].try_into().unwrap()) as usize;
+            let mut section = &car[data_offset + offset..];
+            read_varint(&mut section).await.unwrap();
+            let cid = Cid::read_bytes(section).unwrap();
+            assert_eq!(cid.hash().digest(), &entry[..32]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_rejects_bad_blocks() {
+        let store = MemoryBlockstore::default();
+        let root = file_dag(&store, b"twelve bytes").await;
+        let mut car = export(&root, &store, CarVersion::V1).await;
+        let copy = MemoryBlockstore::default();
+
+        // the last byte belongs to the last block
+        *car.last_mut().unwrap() ^= 1;
+        assert!(matches!(import_car(&car[..], &copy).await, Err(CarErrors::CorruptedBlockError(_))));
+        let cut = &car[..car.len() - 3];
+        assert!(matches!(import_car(cut, &copy).await, Err(CarErrors::IoError(_))));
+        assert!(matches!(import_car(&b""[..], &copy).await, Err(CarErrors::InvalidHeaderError(_))));
+
+        let mut v3 = Vec::new();
+        cbor_head(&mut v3, 5, 1);
+        cbor_text(&mut v3, "version");
+        cbor_head(&mut v3, 0, 3);
+        assert!(matches!(
+            import_car(&prefixed(&v3)[..], &copy).await,
+            Err(CarErrors::UnsupportedVersionError(3))
+        ));
+    }
+}
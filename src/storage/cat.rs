@@ -0,0 +1,409 @@
+use crate::cid::builder::RAW_CODEC;
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::load::{load_block, load_node};
+use crate::storage::unixfs::DataType;
+use crate::storage::MerkleNode;
+use cid::Cid;
+use futures::{Stream, StreamExt};
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// blocks fetched ahead of the one being read
+pub const DEFAULT_PREFETCH: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum CatErrors {
+    #[error(transparent)]
+    StorageError(#[from] BlockstoreErrors),
+    #[error("{0} is not a file")]
+    NotAFileError(Cid),
+}
+
+// bytes that are already in hand, or a raw leaf still to be fetched
+enum Piece {
+    Ready(Vec<u8>),
+    Fetch(Cid),
+}
+
+struct Frame {
+    node: MerkleNode,
+    next: usize,
+    content_done: bool,
+}
+
+/*
+tldr; how it works
+a depth first walk over the file dag, in order, that only keeps the path from the root to
+where it is. it hands out pieces: bytes held by a node it had to load anyway (inner
+nodes, unixfs leaves) or the cid of a raw leaf, which is fetched further down the line so
+several can be in flight at once. starting somewhere in the middle, a child whose size is
+known from the parent's blocksizes (child_sizes) and lies wholly before the offset is
+skipped unread, so getting to any offset loads one path down the tree, not every leaf.
+once `remaining` bytes have been handed out the walk stops, the last piece may run over.
+*/
+pub(crate) struct Walk<'a> {
+    store: &'a dyn Blockstore,
+    stack: Vec<Frame>,
+    skip: u64,
+    remaining: u64,
+}
+
+impl<'a> Walk<'a> {
+    pub(crate) fn new(root: MerkleNode, offset: u64, len: u64, store: &'a dyn Blockstore) -> Self {
+        Walk {
+            store,
+            stack: vec![Frame {
+                node: root,
+                next: 0,
+                content_done: false,
+            }],
+            skip: offset,
+            remaining: len,
+        }
+    }
+
+    async fn next_piece(&mut self) -> Result<Option<Piece>, CatErrors> {
+        loop {
+            if self.remaining == 0 {
+                return Ok(None);
+            }
+            let Some(frame) = self.stack.last_mut() else {
+                return Ok(None);
+            };
+            if !frame.content_done {
+                frame.content_done = true;
+                let content = frame.node.content().unwrap_or_default();
+                if self.skip >= content.len() as u64 {
+                    self.skip -= content.len() as u64;
+                } else {
+                    let start = self.skip as usize;
+                    let take = ((content.len() - start) as u64).min(self.remaining);
+                    self.skip = 0;
+                    self.remaining -= take;
+                    return Ok(Some(Piece::Ready(content[start..start + take as usize].to_vec())));
+                }
+            }
+            let Some(link) = frame.node.links.get(frame.next).cloned() else {
+                self.stack.pop();
+                continue;
+            };
+            let index = frame.next;
+            frame.next += 1;
+            let known_size = frame.node.child_sizes().map(|sizes| sizes[index]);
+            if let Some(size) = known_size.filter(|size| self.skip >= *size) {
+                self.skip -= size;
+                continue;
+            }
+            // a raw leaf that is wanted from its first byte, read whole and cut to size later
+            if link.cid.codec() == RAW_CODEC && self.skip == 0 {
+                self.remaining = self.remaining.saturating_sub(known_size.unwrap_or(0));
+                return Ok(Some(Piece::Fetch(link.cid)));
+            }
+            let child = load_node(&link.cid, self.store).await?;
+            if known_size.is_none() && self.skip >= child.file_size() {
+                self.skip -= child.file_size();
+                continue;
+            }
+            self.stack.push(Frame {
+                node: child,
+                next: 0,
+                content_done: false,
+            });
+        }
+    }
+}
+
+// the walk's bytes in order, up to `window` raw leaves fetched ahead of the one handed out
+pub(crate) fn walk_bytes<'a>(
+    walk: Walk<'a>,
+    window: usize,
+) -> impl Stream<Item = Result<Vec<u8>, CatErrors>> + 'a {
+    let store = walk.store;
+    futures::stream::unfold(Some(walk), |walk| async move {
+        let mut walk = walk?;
+        match walk.next_piece().await {
+            Ok(Some(piece)) => Some((Ok(piece), Some(walk))),
+            Ok(None) => None,
+            // nothing after an error
+            Err(err) => Some((Err(err), None)),
+        }
+    })
+    .map(move |piece| async move {
+        match piece? {
+            Piece::Ready(bytes) => Ok(bytes),
+            Piece::Fetch(cid) => Ok(load_block(&cid, store).await?),
+        }
+    })
+    .buffered(window.max(1))
+}
+
+// loads the root of a file, anything else (a directory, a shard) is refused
+pub(crate) async fn load_file_root(cid: &Cid, store: &dyn Blockstore) -> Result<MerkleNode, CatErrors> {
+    let mut root = load_node(cid, store).await?;
+    let data_type = root.unixfs.as_ref().map(|unixfs| unixfs.data_type);
+    if !matches!(data_type, None | Some(DataType::File) | Some(DataType::Raw)) {
+        return Err(CatErrors::NotAFileError(*cid));
+    }
+    root.metadata = store.get_metadata(cid).await?;
+    Ok(root)
+}
+
+// fills the channel from `offset` to the end of the file
+async fn produce(
+    root: MerkleNode,
+    offset: u64,
+    store: Arc<dyn Blockstore>,
+    window: usize,
+    sender: mpsc::Sender<Result<Vec<u8>, CatErrors>>,
+) {
+    let bytes = walk_bytes(Walk::new(root, offset, u64::MAX, store.as_ref()), window);
+    futures::pin_mut!(bytes);
+    while let Some(item) = bytes.next().await {
+        let failed = item.is_err();
+        // the reader went away or seeked elsewhere
+        if sender.send(item).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/*
+tldr; how it works
+the bytes of a file, read straight from the store. a task walks the dag from the current
+position and sends the pieces over a channel with room for one, so at most the prefetch
+window plus two pieces are held at any time, never the whole file. seeking drops that
+task and the next read starts a new one at the new position.
+*/
+pub struct CatReader {
+    root: MerkleNode,
+    store: Arc<dyn Blockstore>,
+    window: usize,
+    size: u64,
+    position: u64,
+    chunk: Vec<u8>,
+    chunk_pos: usize,
+    receiver: Option<mpsc::Receiver<Result<Vec<u8>, CatErrors>>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl CatReader {
+    // size of the whole file, from the root
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn root(&self) -> &MerkleNode {
+        &self.root
+    }
+
+    fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        self.receiver = None;
+        self.chunk.clear();
+        self.chunk_pos = 0;
+    }
+
+    fn start(&mut self) -> &mut mpsc::Receiver<Result<Vec<u8>, CatErrors>> {
+        let (sender, receiver) = mpsc::channel(1);
+        let producer = produce(self.root.clone(), self.position, self.store.clone(), self.window, sender);
+        self.task = Some(tokio::spawn(producer));
+        self.receiver.insert(receiver)
+    }
+}
+
+impl Drop for CatReader {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl AsyncRead for CatReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.chunk_pos < this.chunk.len() {
+                let n = buf.remaining().min(this.chunk.len() - this.chunk_pos);
+                buf.put_slice(&this.chunk[this.chunk_pos..this.chunk_pos + n]);
+                this.chunk_pos += n;
+                this.position += n as u64;
+                return Poll::Ready(Ok(()));
+            }
+            let receiver = match this.receiver.as_mut() {
+                Some(receiver) => receiver,
+                None => this.start(),
+            };
+            match receiver.poll_recv(cx) {
+                Poll::Ready(Some(Ok(bytes))) => {
+                    this.chunk = bytes;
+                    this.chunk_pos = 0;
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(std::io::Error::other(err))),
+                // end of file, nothing is written to buf
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncSeek for CatReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => this.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => this.position.checked_add_signed(delta),
+        };
+        let target = target.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        if target != this.position {
+            this.stop();
+            this.position = target;
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+pub async fn cat(cid: &Cid, store: Arc<dyn Blockstore>) -> Result<CatReader, CatErrors> {
+    cat_with_prefetch(cid, store, DEFAULT_PREFETCH).await
+}
+
+// only the root is loaded here, nothing else is read until the first poll
+pub async fn cat_with_prefetch(
+    cid: &Cid,
+    store: Arc<dyn Blockstore>,
+    window: usize,
+) -> Result<CatReader, CatErrors> {
+    let root = load_file_root(cid, store.as_ref()).await?;
+    Ok(CatReader {
+        size: root.file_size(),
+        root,
+        store,
+        window,
+        position: 0,
+        chunk: Vec::new(),
+        chunk_pos: 0,
+        receiver: None,
+        task: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cid::chunker::ChunkerType;
+    use crate::storage::blockstore::MemoryBlockstore;
+    use crate::storage::directory::import_directory;
+    use crate::storage::import::{import_reader, ImportOptions};
+    use crate::storage::layout::DagLayout;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_cat_every_layout() {
+        let store: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        let data = sample(1000);
+        for layout in [
+            DagLayout::Binary,
+            DagLayout::Balanced { max_links: 3 },
+            DagLayout::Trickle {
+                max_links: 3,
+                layer_repeat: 2,
+            },
+        ] {
+            let options = ImportOptions {
+                chunker: ChunkerType::FixedSize(7),
+                layout,
+                ..Default::default()
+            };
+            let root = import_reader(data.as_slice(), store.as_ref(), &options).await.unwrap();
+            for window in [1, 4] {
+                let mut reader = cat_with_prefetch(&root, store.clone(), window).await.unwrap();
+                assert_eq!(reader.size(), 1000);
+                let mut out = Vec::new();
+                reader.read_to_end(&mut out).await.unwrap();
+                assert_eq!(out, data, "{:?}", layout);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cat_seek() {
+        let store: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        let data = sample(500);
+        let options = ImportOptions {
+            chunker: ChunkerType::FixedSize(16),
+            layout: DagLayout::Balanced { max_links: 4 },
+            ..Default::default()
+        };
+        let root = import_reader(data.as_slice(), store.as_ref(), &options).await.unwrap();
+        let mut reader = cat(&root, store.clone()).await.unwrap();
+
+        for offset in [0, 1, 15, 16, 17, 64, 255, 499, 500, 600] {
+            reader.seek(SeekFrom::Start(offset)).await.unwrap();
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out).await.unwrap();
+            assert_eq!(out, data[(offset as usize).min(500)..], "offset {}", offset);
+        }
+
+        reader.seek(SeekFrom::Start(100)).await.unwrap();
+        let mut first = [0u8; 10];
+        reader.read_exact(&mut first).await.unwrap();
+        assert_eq!(first, data[100..110]);
+        assert_eq!(reader.seek(SeekFrom::Current(-5)).await.unwrap(), 105);
+        let mut again = [0u8; 10];
+        reader.read_exact(&mut again).await.unwrap();
+        assert_eq!(again, data[105..115]);
+        assert_eq!(reader.seek(SeekFrom::End(-3)).await.unwrap(), 497);
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).await.unwrap();
+        assert_eq!(tail, data[497..]);
+        assert!(reader.seek(SeekFrom::Current(-1000)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cat_errors() {
+        let store: Arc<dyn Blockstore> = Arc::new(MemoryBlockstore::default());
+        let options = ImportOptions {
+            chunker: ChunkerType::FixedSize(4),
+            ..Default::default()
+        };
+        let root = import_reader(&b"a few small chunks"[..], store.as_ref(), &options).await.unwrap();
+        let links = store.get(&root).await.unwrap().unwrap().links;
+        store.delete(&links[2].cid).await.unwrap();
+        let mut reader = cat(&root, store.clone()).await.unwrap();
+        let mut out = Vec::new();
+        assert!(reader.read_to_end(&mut out).await.is_err());
+        // everything before the hole made it through
+        assert_eq!(out, b"a few sm");
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("folder")).unwrap();
+        let folder = import_directory(dir.path().join("folder"), store.as_ref(), &options)
+            .await
+            .unwrap();
+        assert!(matches!(cat(&folder, store.clone()).await, Err(CatErrors::NotAFileError(_))));
+        let missing = crate::cid::generate_cid(b"never stored");
+        assert!(matches!(cat(&missing, store).await, Err(CatErrors::StorageError(BlockstoreErrors::NotFoundError(_)))));
+    }
+}
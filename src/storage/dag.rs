@@ -1,16 +1,51 @@
 use cid::Cid;
 use serde::{Deserialize, Serialize};
+use crate::cid::builder::{verify_cid, CidBuilder, CidErrors, DAG_PB_CODEC, RAW_CODEC};
 use crate::cid::generator::generate_cid;
+use crate::storage::dag_pb::{DagPbErrors, PbLink, PbNode};
+use crate::storage::metadata::FileMetadata;
+use crate::storage::unixfs::UnixFsData;
+use cid::Version;
 use thiserror::Error;
 use derive_more::Display;
-use crate::helpers::helper::convert_file_extension_to_raw;
 // Custom error definitions
 #[derive(Debug, Error)]
 pub enum DagErrors {
     #[error("The vector of leaves was empty")]
     EmptyError,
-    #[error("The file extension is invalid")]
-    InvalidFileExtensionError
+    #[error("Blocks with codec {0:#x} are not supported")]
+    UnsupportedCodecError(u64),
+    #[error(transparent)]
+    CidError(#[from] CidErrors),
+    #[error(transparent)]
+    DecodeError(#[from] DagPbErrors),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    pub cid: Cid,
+    pub name: String, // empty for file chunks
+    pub tsize: u64,   // encoded size of the whole subtree behind the link
+}
+
+impl From<&Link> for PbLink {
+    fn from(link: &Link) -> Self {
+        PbLink {
+            hash: link.cid,
+            name: link.name.clone(),
+            tsize: link.tsize,
+        }
+    }
+}
+
+impl From<PbLink> for Link {
+    fn from(link: PbLink) -> Self {
+        Link {
+            cid: link.hash,
+            name: link.name,
+            tsize: link.tsize,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -20,22 +55,24 @@ pub enum DagErrors {
 #[derive(PartialEq)]
 pub struct MerkleNode {
     pub cid: Cid,             // Hash of this node
-    pub links: Vec<Cid>,      // Child nodes
-    pub data: Option<Vec<u8>>, // File data (only for leaves)
-    pub is_dup:bool
+    pub links: Vec<Link>,     // Child nodes
+    pub data: Option<Vec<u8>>, // File data (only for raw leaves)
+    #[serde(default)]
+    pub unixfs: Option<UnixFsData>, // Data field of dag-pb nodes
+    #[serde(default)]
+    pub metadata: Option<FileMetadata>, // File record (only for roots, not part of the block)
 }
 
 
 pub fn generate_merkle_tree(
     leaves: Vec<MerkleNode>,
-    file_extension: &str,
+    metadata: FileMetadata,
 ) -> Result<Vec<MerkleNode>, DagErrors> {
     if leaves.is_empty() {
         return Err(DagErrors::EmptyError);
     }
-    if file_extension.is_empty() {
-        return Err(DagErrors::InvalidFileExtensionError);
-    }
+
+    let builder = CidBuilder::default();
 
     // Start with the initial leaves in the tree.
     let mut tree: Vec<MerkleNode> = leaves.clone();
@@ -48,38 +85,11 @@ pub fn generate_merkle_tree(
         while i < current_level.len() {
             if i + 1 < current_level.len() {
                 // Standard pairing: two nodes available.
-                let left = &current_level[i];
-                let right = &current_level[i + 1];
-
-                let combined_data: Vec<u8> = left.cid.to_bytes()
-                    .into_iter()
-                    .chain(right.cid.to_bytes())
-                    .collect();
-                let new_cid = generate_cid(&combined_data);
-
-                next_level.push(MerkleNode {
-                    cid: new_cid,
-                    links: vec![left.cid.clone(), right.cid.clone()],
-                    data: None,
-                    is_dup: false,
-                });
+                next_level.push(parent_of_pair(&current_level[i], &current_level[i + 1], &builder)?);
                 i += 2;
             } else {
-                // Odd node: hash the node with itself.
-                let node = &current_level[i];
-
-                let combined_data: Vec<u8> = node.cid.to_bytes()
-                    .into_iter()
-                    .chain(node.cid.to_bytes())
-                    .collect();
-                let new_cid = generate_cid(&combined_data);
-
-                next_level.push(MerkleNode {
-                    cid: new_cid,
-                    links: vec![node.cid.clone()],
-                    data: None,
-                    is_dup: false,
-                });
+                // Odd node: gets a parent of its own.
+                next_level.push(parent_of_single(&current_level[i], &builder)?);
                 i += 1;
             }
         }
@@ -89,25 +99,168 @@ pub fn generate_merkle_tree(
         current_level = next_level;
     }
 
-    // Attach file_extension data to the root node.
+    // Attach the file record to the root node, its data stays untouched.
     if let Some(last_node) = tree.last_mut() {
-        last_node.data = convert_file_extension_to_raw(file_extension);
+        last_node.metadata = Some(metadata);
     }
     Ok(tree)
 }
 
+/*
+tldr; how it works
+parents are dag-pb nodes holding a unixfs File message, the same thing go-unixfs builds:
+one link per child carrying the child's cumulative block size (Tsize), and a blocksizes
+entry per child with the number of file bytes under it. the cid is taken over the dag-pb
+bytes and tagged with codec 0x70, so a file imported here names the same blocks kubo's would.
+*/
+pub(crate) fn parent_of(children: &[&MerkleNode], builder: &CidBuilder) -> Result<MerkleNode, DagErrors> {
+    let mut links = Vec::with_capacity(children.len());
+    for child in children {
+        links.push(Link {
+            cid: child.cid,
+            name: String::new(),
+            tsize: child.tree_size()?,
+        });
+    }
+    let unixfs = UnixFsData::file(children.iter().map(|child| child.file_size()).collect());
+    dag_pb_node(links, unixfs, builder)
+}
+
+pub(crate) fn parent_of_pair(
+    left: &MerkleNode,
+    right: &MerkleNode,
+    builder: &CidBuilder,
+) -> Result<MerkleNode, DagErrors> {
+    parent_of(&[left, right], builder)
+}
+
+pub(crate) fn parent_of_single(node: &MerkleNode, builder: &CidBuilder) -> Result<MerkleNode, DagErrors> {
+    parent_of(&[node], builder)
+}
+
+// a unixfs directory, links are named after the entries and kept sorted by name like go-merkledag
+pub(crate) fn directory_of(mut links: Vec<Link>, builder: &CidBuilder) -> Result<MerkleNode, DagErrors> {
+    links.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+    dag_pb_node(links, UnixFsData::directory(), builder)
+}
+
+// any unixfs node: links first, the unixfs message as Data, cid tagged dag-pb
+pub(crate) fn dag_pb_node(
+    links: Vec<Link>,
+    unixfs: UnixFsData,
+    builder: &CidBuilder,
+) -> Result<MerkleNode, DagErrors> {
+    let pb_node = PbNode {
+        links: links.iter().map(PbLink::from).collect(),
+        data: Some(unixfs.encode()),
+    };
+    Ok(MerkleNode {
+        cid: builder.clone().codec(DAG_PB_CODEC).build(&pb_node.encode())?,
+        links,
+        data: None,
+        unixfs: Some(unixfs),
+        metadata: None,
+    })
+}
+
+// CIDv1 imports use raw leaves, CIDv0 can only name dag-pb so the chunk gets wrapped in unixfs
+pub(crate) fn leaf_of(chunk: Vec<u8>, builder: &CidBuilder) -> Result<MerkleNode, DagErrors> {
+    if builder.get_version() == Version::V1 {
+        return Ok(MerkleNode {
+            cid: builder.clone().codec(RAW_CODEC).build(&chunk)?,
+            links: vec![],
+            data: Some(chunk),
+            unixfs: None,
+            metadata: None,
+        });
+    }
+    dag_pb_node(vec![], UnixFsData::file_leaf(chunk), builder)
+}
+
+impl MerkleNode {
+    // the block as it is hashed and exchanged: the bytes themselves for raw, protobuf for dag-pb
+    pub fn encode(&self) -> Result<Vec<u8>, DagErrors> {
+        match self.cid.codec() {
+            RAW_CODEC => Ok(self.data.clone().unwrap_or_default()),
+            DAG_PB_CODEC => Ok(PbNode {
+                links: self.links.iter().map(PbLink::from).collect(),
+                data: self.unixfs.as_ref().map(UnixFsData::encode),
+            }
+            .encode()),
+            other => Err(DagErrors::UnsupportedCodecError(other)),
+        }
+    }
+
+    pub fn decode(cid: Cid, bytes: &[u8]) -> Result<MerkleNode, DagErrors> {
+        match cid.codec() {
+            RAW_CODEC => Ok(MerkleNode {
+                cid,
+                links: vec![],
+                data: Some(bytes.to_vec()),
+                unixfs: None,
+                metadata: None,
+            }),
+            DAG_PB_CODEC => {
+                let pb_node = PbNode::decode(bytes)?;
+                Ok(MerkleNode {
+                    cid,
+                    links: pb_node.links.into_iter().map(Link::from).collect(),
+                    data: None,
+                    unixfs: pb_node.data.as_deref().map(UnixFsData::decode).transpose()?,
+                    metadata: None,
+                })
+            }
+            other => Err(DagErrors::UnsupportedCodecError(other)),
+        }
+    }
+
+    // file bytes held directly by this node, raw leaves keep them in data, unixfs leaves in unixfs.data
+    pub fn content(&self) -> Option<&[u8]> {
+        match &self.unixfs {
+            Some(unixfs) => unixfs.data.as_deref(),
+            None => self.data.as_deref(),
+        }
+    }
+
+    // bytes of file content below this node
+    pub fn file_size(&self) -> u64 {
+        match &self.unixfs {
+            Some(unixfs) => unixfs.file_size(),
+            None => self.data.as_ref().map_or(0, |data| data.len() as u64),
+        }
+    }
+
+    // file bytes below each link, in link order, None unless recorded for every link
+    pub fn child_sizes(&self) -> Option<&[u64]> {
+        self.unixfs
+            .as_ref()
+            .map(|unixfs| unixfs.blocksizes.as_slice())
+            .filter(|sizes| sizes.len() == self.links.len())
+    }
+
+    // encoded size of this block plus everything it links to, what parents record as Tsize
+    pub fn tree_size(&self) -> Result<u64, DagErrors> {
+        let own = self.encode()?.len() as u64;
+        Ok(own + self.links.iter().map(|link| link.tsize).sum::<u64>())
+    }
+
+    // recomputes the cid over the encoded block with the hash function the cid names
+    pub fn verify(&self) -> Result<bool, DagErrors> {
+        Ok(verify_cid(&self.cid, &self.encode()?)?)
+    }
+}
+
 pub fn create_leaf(data: &[u8]) -> MerkleNode {
     MerkleNode {
         cid: generate_cid(data),
         links: vec![],
         data: Some(data.to_vec()),
-        is_dup:false
+        unixfs: None,
+        metadata: None,
     }
 }
 #[cfg(test)]
 mod tests {
-    use crate::helpers::helper::convert_raw_to_file_extension;
-
     use super::*;
     // use multihash::Multihash;
 
@@ -121,14 +274,80 @@ mod tests {
             create_leaf(b"File Chunk 42"),
         ];
 
-        let tree = generate_merkle_tree(leaves.clone(),"png").unwrap();
-        for x in 0..tree.len() {
-            println!("{:?}",tree[x].links);
+        let metadata = FileMetadata {
+            size: 65,
+            mime_type: Some("image/png".to_string()),
+            ..Default::default()
+        };
+        let tree = generate_merkle_tree(leaves.clone(), metadata.clone()).unwrap();
+        for node in &tree {
+            println!("{:?}",node.links);
         }
         assert!(!tree.is_empty());
-        assert_eq!(
-            convert_raw_to_file_extension(tree.last().unwrap().data.as_ref().unwrap().clone()).unwrap(),
-            "png".to_string()
-        );
+        let root = tree.last().unwrap();
+        assert_eq!(root.metadata, Some(metadata));
+        assert_eq!(root.data, None);
+    }
+
+    #[test]
+    fn test_single_chunk_root_keeps_its_data() {
+        let tree = generate_merkle_tree(vec![create_leaf(b"only chunk")], FileMetadata::default()).unwrap();
+        let root = tree.last().unwrap();
+        assert_eq!(root.content(), Some(&b"only chunk"[..]));
+        assert!(root.metadata.is_some());
+        assert!(root.verify().unwrap());
+    }
+
+    #[test]
+    fn test_verify_merkle_nodes() {
+        let leaves = vec![
+            create_leaf(b"File Chunk 1"),
+            create_leaf(b"File Chunk 2"),
+            create_leaf(b"File Chunk 3"),
+        ];
+        let tree = generate_merkle_tree(leaves, FileMetadata::default()).unwrap();
+        for node in &tree {
+            assert!(node.verify().unwrap());
+        }
+
+        let mut tampered = tree[0].clone();
+        tampered.data = Some(b"File Chunk 9".to_vec());
+        assert!(!tampered.verify().unwrap());
+    }
+
+    #[test]
+    fn test_parents_are_unixfs_dag_pb() {
+        let leaves = vec![create_leaf(b"abc"), create_leaf(b"defgh")];
+        let tree = generate_merkle_tree(leaves, FileMetadata::default()).unwrap();
+        let root = tree.last().unwrap();
+
+        assert_eq!(root.cid.codec(), DAG_PB_CODEC);
+        let unixfs = root.unixfs.as_ref().unwrap();
+        assert_eq!(unixfs.filesize, Some(8));
+        assert_eq!(unixfs.blocksizes, vec![3, 5]);
+        assert_eq!(root.links[0].tsize, 3);
+        assert_eq!(root.links[1].tsize, 5);
+
+        let decoded = MerkleNode::decode(root.cid, &root.encode().unwrap()).unwrap();
+        assert_eq!(decoded.links, root.links);
+        assert_eq!(decoded.unixfs, root.unixfs);
+        assert!(decoded.verify().unwrap());
+    }
+
+    #[test]
+    fn test_empty_directory_matches_kubo() {
+        // kubo: ipfs add -r on an empty folder
+        let dir = directory_of(vec![], &CidBuilder::v0()).unwrap();
+        assert_eq!(dir.cid.to_string(), "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn");
+        let dir = directory_of(vec![], &CidBuilder::default()).unwrap();
+        assert_eq!(dir.cid.to_string(), "bafybeiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354");
+    }
+
+    #[test]
+    fn test_cid_v0_leaf_matches_kubo() {
+        // kubo: echo "hello world" | ipfs add
+        let leaf = leaf_of(b"hello world\n".to_vec(), &CidBuilder::v0()).unwrap();
+        assert_eq!(leaf.cid.to_string(), "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o");
+        assert_eq!(leaf.content(), Some(&b"hello world\n"[..]));
     }
 }
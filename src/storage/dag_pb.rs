@@ -0,0 +1,234 @@
+use cid::Cid;
+use thiserror::Error;
+
+/*
+tldr; how it works
+dag-pb is a two message protobuf schema:
+    PBLink { Hash = 1 (bytes), Name = 2 (string), Tsize = 3 (uint64) }
+    PBNode { Data = 1 (bytes), Links = 2 (repeated PBLink) }
+the canonical form writes every link before the data field even though data has the lower
+field number, and go-ipfs always writes Name and Tsize on links, so we do the same or the
+bytes (and therefore the cids) would not match what kubo produces.
+only the handful of protobuf wire types these schemas use are implemented here.
+*/
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_BYTES: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DagPbErrors {
+    #[error("The protobuf message ended unexpectedly")]
+    TruncatedError,
+    #[error("Unsupported protobuf wire type {0}")]
+    WireTypeError(u64),
+    #[error("A link holds an invalid cid")]
+    InvalidCidError,
+    #[error("A link name is not valid utf-8")]
+    InvalidNameError,
+    #[error("Required field {0} is missing")]
+    MissingFieldError(&'static str),
+    #[error("Unknown unixfs data type {0}")]
+    UnknownDataTypeError(u64),
+}
+
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+pub(crate) fn write_key(out: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(out, (field << 3) | wire_type);
+}
+
+pub(crate) fn write_varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    write_key(out, field, WIRE_VARINT);
+    write_varint(out, value);
+}
+
+pub(crate) fn write_bytes_field(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    write_key(out, field, WIRE_BYTES);
+    write_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+pub(crate) enum FieldValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    // fixed width values, none of the fields read here use them
+    Fixed,
+}
+
+// walks the fields of one protobuf message in wire order
+pub(crate) struct FieldReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> FieldReader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        FieldReader { buf }
+    }
+
+    fn read_varint(&mut self) -> Result<u64, DagPbErrors> {
+        let mut value: u64 = 0;
+        for (i, byte) in self.buf.iter().enumerate().take(10) {
+            value |= ((byte & 0x7F) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(DagPbErrors::TruncatedError)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DagPbErrors> {
+        if self.buf.len() < len {
+            return Err(DagPbErrors::TruncatedError);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    // for packed repeated fields, which are just varints back to back
+    pub(crate) fn next_varint(&mut self) -> Result<Option<u64>, DagPbErrors> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        self.read_varint().map(Some)
+    }
+
+    pub(crate) fn next_field(&mut self) -> Result<Option<(u64, FieldValue<'a>)>, DagPbErrors> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let key = self.read_varint()?;
+        let value = match key & 0x7 {
+            WIRE_VARINT => FieldValue::Varint(self.read_varint()?),
+            WIRE_BYTES => {
+                let len = self.read_varint()? as usize;
+                FieldValue::Bytes(self.take(len)?)
+            }
+            WIRE_FIXED32 => {
+                self.take(4)?;
+                FieldValue::Fixed
+            }
+            WIRE_FIXED64 => {
+                self.take(8)?;
+                FieldValue::Fixed
+            }
+            other => return Err(DagPbErrors::WireTypeError(other)),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PbLink {
+    pub hash: Cid,
+    pub name: String,
+    pub tsize: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PbNode {
+    pub links: Vec<PbLink>,
+    pub data: Option<Vec<u8>>,
+}
+
+impl PbNode {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for link in &self.links {
+            let mut encoded_link = Vec::new();
+            write_bytes_field(&mut encoded_link, 1, &link.hash.to_bytes());
+            write_bytes_field(&mut encoded_link, 2, link.name.as_bytes());
+            write_varint_field(&mut encoded_link, 3, link.tsize);
+            write_bytes_field(&mut out, 2, &encoded_link);
+        }
+        if let Some(data) = &self.data {
+            write_bytes_field(&mut out, 1, data);
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<PbNode, DagPbErrors> {
+        let mut node = PbNode::default();
+        let mut reader = FieldReader::new(bytes);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (1, FieldValue::Bytes(data)) => node.data = Some(data.to_vec()),
+                (2, FieldValue::Bytes(link)) => node.links.push(decode_link(link)?),
+                _ => {}
+            }
+        }
+        Ok(node)
+    }
+}
+
+fn decode_link(bytes: &[u8]) -> Result<PbLink, DagPbErrors> {
+    let mut hash = None;
+    let mut name = String::new();
+    let mut tsize = 0;
+    let mut reader = FieldReader::new(bytes);
+    while let Some((field, value)) = reader.next_field()? {
+        match (field, value) {
+            (1, FieldValue::Bytes(raw)) => {
+                hash = Some(Cid::try_from(raw).map_err(|_| DagPbErrors::InvalidCidError)?)
+            }
+            (2, FieldValue::Bytes(raw)) => {
+                name = String::from_utf8(raw.to_vec()).map_err(|_| DagPbErrors::InvalidNameError)?
+            }
+            (3, FieldValue::Varint(size)) => tsize = size,
+            _ => {}
+        }
+    }
+    Ok(PbLink {
+        hash: hash.ok_or(DagPbErrors::MissingFieldError("Hash"))?,
+        name,
+        tsize,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cid::generate_cid;
+
+    #[test]
+    fn test_pb_node_round_trip() {
+        let node = PbNode {
+            links: vec![
+                PbLink {
+                    hash: generate_cid(b"left"),
+                    name: String::new(),
+                    tsize: 4,
+                },
+                PbLink {
+                    hash: generate_cid(b"right"),
+                    name: "right.txt".to_string(),
+                    tsize: 300,
+                },
+            ],
+            data: Some(vec![0x08, 0x02]),
+        };
+        let encoded = node.encode();
+        // links are written before data
+        assert_eq!(encoded[0], 0x12);
+        assert_eq!(PbNode::decode(&encoded).unwrap(), node);
+    }
+
+    #[test]
+    fn test_pb_node_decode_errors() {
+        assert_eq!(PbNode::decode(&[0x0a, 0x05, 0x01]), Err(DagPbErrors::TruncatedError));
+        assert_eq!(PbNode::decode(&[0x0b]), Err(DagPbErrors::WireTypeError(3)));
+        assert_eq!(
+            PbNode::decode(&[0x12, 0x02, 0x18, 0x01]),
+            Err(DagPbErrors::MissingFieldError("Hash"))
+        );
+    }
+}
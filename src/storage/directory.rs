@@ -0,0 +1,446 @@
+use crate::storage::dag::{directory_of, DagErrors};
+use crate::storage::hamt::{build_shard, hamt_entries, hamt_insert, hamt_lookup, hamt_remove, HamtErrors};
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::import::{import_file_root, ImportErrors, ImportOptions};
+use crate::storage::load::load_node_with_record;
+use crate::storage::metadata::FileMetadata;
+use crate::storage::unixfs::DataType;
+use crate::storage::{Link, MerkleNode};
+use cid::Cid;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+#[derive(Debug, Error)]
+pub enum DirectoryErrors {
+    #[error("Error reading or writing the directory: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    ImportError(#[from] ImportErrors),
+    #[error(transparent)]
+    StorageError(#[from] BlockstoreErrors),
+    #[error(transparent)]
+    DagError(#[from] DagErrors),
+    #[error(transparent)]
+    HamtError(#[from] HamtErrors),
+    #[error("The entry {0:?} is not in this directory")]
+    EntryNotFoundError(String),
+    #[error("{0} is neither a regular file nor a directory")]
+    UnsupportedEntryError(PathBuf),
+    #[error("The node {0} is not a file or a directory")]
+    UnsupportedNodeError(Cid),
+    #[error("{0:?} is not a valid entry name")]
+    InvalidNameError(String),
+}
+
+type BoxedResult<'a, T> = Pin<Box<dyn Future<Output = Result<T, DirectoryErrors>> + Send + 'a>>;
+
+/*
+tldr; how it works
+files are imported as usual, every folder becomes a unixfs Directory node whose links are
+named after its entries and point at their roots (files or other folders), Tsize being the
+whole subtree. children go in first so a folder only ever links to blocks already stored.
+past options.shard_threshold entries the folder is written as a hamt shard tree instead.
+the folder gets a record too, its size is the sum of the file bytes under it.
+*/
+pub async fn import_directory<P: AsRef<Path>>(
+    path: P,
+    store: &dyn Blockstore,
+    options: &ImportOptions,
+) -> Result<Cid, DirectoryErrors> {
+    let root = import_entry(path.as_ref().to_path_buf(), store, options).await?;
+    Ok(root.cid)
+}
+
+fn import_entry<'a>(
+    path: PathBuf,
+    store: &'a dyn Blockstore,
+    options: &'a ImportOptions,
+) -> BoxedResult<'a, MerkleNode> {
+    Box::pin(async move {
+        let file_type = tokio::fs::symlink_metadata(&path).await?.file_type();
+        if file_type.is_file() {
+            return Ok(import_file_root(&path, store, options).await?);
+        }
+        if !file_type.is_dir() {
+            return Err(DirectoryErrors::UnsupportedEntryError(path));
+        }
+
+        let mut links = Vec::new();
+        let mut size = 0;
+        let mut entries = tokio::fs::read_dir(&path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|name| DirectoryErrors::InvalidNameError(name.to_string_lossy().to_string()))?;
+            let child = import_entry(entry.path(), store, options).await?;
+            size += child.metadata.as_ref().map_or(0, |metadata| metadata.size);
+            links.push(Link {
+                cid: child.cid,
+                name,
+                tsize: child.tree_size()?,
+            });
+        }
+
+        let mut node = directory_node(links, store, options).await?;
+        let mut metadata = FileMetadata::from_path(&path)?;
+        metadata.size = size;
+        node.metadata = Some(metadata);
+        store.put(&node).await?;
+        Ok(node)
+    })
+}
+
+async fn directory_node(
+    links: Vec<Link>,
+    store: &dyn Blockstore,
+    options: &ImportOptions,
+) -> Result<MerkleNode, DirectoryErrors> {
+    if links.len() > options.shard_threshold {
+        return Ok(build_shard(links, options.hamt_fanout, &options.cid_builder, store).await?);
+    }
+    Ok(directory_of(links, &options.cid_builder)?)
+}
+
+fn data_type(node: &MerkleNode) -> Option<DataType> {
+    node.unixfs.as_ref().map(|unixfs| unixfs.data_type)
+}
+
+// the link a directory (plain or sharded) holds for name
+pub async fn find_entry(
+    dir: &MerkleNode,
+    name: &str,
+    store: &dyn Blockstore,
+) -> Result<Option<Link>, DirectoryErrors> {
+    match data_type(dir) {
+        Some(DataType::Directory) => Ok(dir.links.iter().find(|link| link.name == name).cloned()),
+        Some(DataType::HamtShard) => Ok(hamt_lookup(&dir.cid, name, store).await?),
+        _ => Err(DirectoryErrors::UnsupportedNodeError(dir.cid)),
+    }
+}
+
+// bytes a record reports for an entry, what the parent's size moves by
+fn recorded_size(node: &MerkleNode) -> u64 {
+    node.metadata.as_ref().map_or_else(|| node.file_size(), |metadata| metadata.size)
+}
+
+/*
+tldr; how it works
+adds (or replaces) one named entry pointing at an already stored child and returns the
+new directory cid. sharded directories only rewrite the shards on the entry's path, a plain
+one is rebuilt and turns into a shard once it grows past options.shard_threshold.
+the directory's record is carried over with its size adjusted.
+*/
+pub async fn add_entry(
+    dir: &Cid,
+    name: &str,
+    child: &Cid,
+    store: &dyn Blockstore,
+    options: &ImportOptions,
+) -> Result<Cid, DirectoryErrors> {
+    validate_name(name)?;
+    let node = load_node_with_record(dir, store).await?;
+    let child = load_node_with_record(child, store).await?;
+    let link = Link {
+        cid: child.cid,
+        name: name.to_string(),
+        tsize: child.tree_size()?,
+    };
+    let replaced = find_entry(&node, name, store).await?;
+    let mut metadata = node.metadata.clone();
+    if let Some(metadata) = &mut metadata {
+        if let Some(replaced) = &replaced {
+            let old = load_node_with_record(&replaced.cid, store).await?;
+            metadata.size = metadata.size.saturating_sub(recorded_size(&old));
+        }
+        metadata.size += recorded_size(&child);
+    }
+
+    let mut updated = match data_type(&node) {
+        Some(DataType::HamtShard) => hamt_insert(dir, link, &options.cid_builder, store).await?,
+        _ => {
+            let mut links: Vec<Link> = node.links.into_iter().filter(|entry| entry.name != name).collect();
+            links.push(link);
+            directory_node(links, store, options).await?
+        }
+    };
+    updated.metadata = metadata;
+    store.put(&updated).await?;
+    Ok(updated.cid)
+}
+
+pub async fn remove_entry(
+    dir: &Cid,
+    name: &str,
+    store: &dyn Blockstore,
+    options: &ImportOptions,
+) -> Result<Cid, DirectoryErrors> {
+    let node = load_node_with_record(dir, store).await?;
+    let removed = find_entry(&node, name, store)
+        .await?
+        .ok_or_else(|| DirectoryErrors::EntryNotFoundError(name.to_string()))?;
+    let mut metadata = node.metadata.clone();
+    if let Some(metadata) = &mut metadata {
+        let old = load_node_with_record(&removed.cid, store).await?;
+        metadata.size = metadata.size.saturating_sub(recorded_size(&old));
+    }
+
+    let mut updated = match data_type(&node) {
+        Some(DataType::HamtShard) => hamt_remove(dir, name, &options.cid_builder, store).await?,
+        _ => {
+            let links = node.links.into_iter().filter(|entry| entry.name != name).collect();
+            directory_of(links, &options.cid_builder)?
+        }
+    };
+    updated.metadata = metadata;
+    store.put(&updated).await?;
+    Ok(updated.cid)
+}
+
+/*
+tldr; how it works
+the other way around: a directory node becomes a folder and each link a child named after
+it, anything else is a file whose blocks get written out depth first, which is file order.
+link names come from the dag, so anything that could step outside dest is refused.
+*/
+pub async fn export_directory<P: AsRef<Path>>(
+    cid: &Cid,
+    dest: P,
+    store: &dyn Blockstore,
+) -> Result<(), DirectoryErrors> {
+    export_entry(*cid, dest.as_ref().to_path_buf(), store).await
+}
+
+fn export_entry(cid: Cid, dest: PathBuf, store: &dyn Blockstore) -> BoxedResult<'_, ()> {
+    Box::pin(async move {
+        let node = load_node_with_record(&cid, store).await?;
+        match data_type(&node) {
+            Some(DataType::Directory) => {
+                tokio::fs::create_dir_all(&dest).await?;
+                for link in &node.links {
+                    validate_name(&link.name)?;
+                    export_entry(link.cid, dest.join(&link.name), store).await?;
+                }
+            }
+            Some(DataType::HamtShard) => {
+                tokio::fs::create_dir_all(&dest).await?;
+                for link in hamt_entries(&cid, store).await? {
+                    validate_name(&link.name)?;
+                    export_entry(link.cid, dest.join(&link.name), store).await?;
+                }
+            }
+            // raw leaves carry no unixfs at all
+            None | Some(DataType::File) | Some(DataType::Raw) => {
+                let mut file = tokio::fs::File::create(&dest).await?;
+                write_file(&node, &mut file, store).await?;
+                file.flush().await?;
+            }
+            Some(_) => return Err(DirectoryErrors::UnsupportedNodeError(cid)),
+        }
+        if let Some(metadata) = &node.metadata {
+            metadata.apply_to_path(&dest)?;
+        }
+        Ok(())
+    })
+}
+
+fn write_file<'a>(
+    node: &'a MerkleNode,
+    file: &'a mut tokio::fs::File,
+    store: &'a dyn Blockstore,
+) -> BoxedResult<'a, ()> {
+    Box::pin(async move {
+        if let Some(content) = node.content() {
+            file.write_all(content).await?;
+        }
+        for link in &node.links {
+            let child = load_node_with_record(&link.cid, store).await?;
+            write_file(&child, file, store).await?;
+        }
+        Ok(())
+    })
+}
+
+fn validate_name(name: &str) -> Result<(), DirectoryErrors> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(DirectoryErrors::InvalidNameError(name.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cid::chunker::ChunkerType;
+    use crate::storage::blockstore::MemoryBlockstore;
+    use crate::storage::metadata::stat_with_handle;
+    use std::fs;
+
+    // every path under root with the file contents, None for folders
+    fn snapshot(root: &Path) -> Vec<(PathBuf, Option<Vec<u8>>)> {
+        let mut out = Vec::new();
+        let mut pending = vec![root.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                let relative = path.strip_prefix(root).unwrap().to_path_buf();
+                if path.is_dir() {
+                    out.push((relative, None));
+                    pending.push(path);
+                } else {
+                    out.push((relative, Some(fs::read(&path).unwrap())));
+                }
+            }
+        }
+        out.sort();
+        out
+    }
+
+    #[tokio::test]
+    async fn test_directory_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemoryBlockstore::default();
+        let src = dir.path().join("dataset");
+        fs::create_dir_all(src.join("a")).unwrap();
+        fs::create_dir_all(src.join("c/d")).unwrap();
+        fs::create_dir_all(src.join("empty")).unwrap();
+        fs::write(src.join("top.txt"), b"top level file").unwrap();
+        fs::write(src.join("a/b.txt"), b"nested").unwrap();
+        fs::write(src.join("a/nothing.txt"), b"").unwrap();
+        let big: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(src.join("c/d/big.bin"), &big).unwrap();
+
+        let options = ImportOptions {
+            chunker: ChunkerType::FixedSize(16),
+            ..Default::default()
+        };
+        let root = import_directory(&src, &store, &options).await.unwrap();
+
+        let node = load_node_with_record(&root, &store).await.unwrap();
+        let names: Vec<&str> = node.links.iter().map(|link| link.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "c", "empty", "top.txt"]);
+        assert!(node.verify().unwrap());
+        let metadata = stat_with_handle(root.to_string(), &store).await.unwrap();
+        assert_eq!(metadata.size, 14 + 6 + 1000);
+        assert_eq!(metadata.filename.as_deref(), Some("dataset"));
+
+        let dest = dir.path().join("restored");
+        export_directory(&root, &dest, &store).await.unwrap();
+        assert_eq!(snapshot(&dest), snapshot(&src));
+
+        // same folder, same cid
+        assert_eq!(import_directory(&src, &store, &options).await.unwrap(), root);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_export_restores_mode_and_mtime() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemoryBlockstore::default();
+        let src = dir.path().join("src");
+        fs::create_dir_all(&src).unwrap();
+        let script = src.join("run.sh");
+        fs::write(&script, b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o750)).unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs::File::open(&script).unwrap().set_modified(mtime).unwrap();
+
+        let root = import_directory(&src, &store, &ImportOptions::default()).await.unwrap();
+        let dest = dir.path().join("dest");
+        export_directory(&root, &dest, &store).await.unwrap();
+
+        let restored = fs::metadata(dest.join("run.sh")).unwrap();
+        assert_eq!(restored.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(restored.modified().unwrap(), mtime);
+    }
+
+    #[tokio::test]
+    async fn test_large_directories_are_sharded() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemoryBlockstore::default();
+        let src = dir.path().join("many");
+        fs::create_dir_all(&src).unwrap();
+        for i in 0..40 {
+            fs::write(src.join(format!("part-{:03}.csv", i)), format!("row {}\n", i)).unwrap();
+        }
+        let options = ImportOptions {
+            shard_threshold: 32,
+            hamt_fanout: 8,
+            ..Default::default()
+        };
+
+        let root = import_directory(&src, &store, &options).await.unwrap();
+        let node = load_node_with_record(&root, &store).await.unwrap();
+        assert_eq!(data_type(&node), Some(DataType::HamtShard));
+        let found = find_entry(&node, "part-017.csv", &store).await.unwrap().unwrap();
+        assert_eq!(load_node_with_record(&found.cid, &store).await.unwrap().content(), Some(&b"row 17\n"[..]));
+
+        let dest = dir.path().join("restored");
+        export_directory(&root, &dest, &store).await.unwrap();
+        assert_eq!(snapshot(&dest), snapshot(&src));
+    }
+
+    #[tokio::test]
+    async fn test_add_and_remove_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemoryBlockstore::default();
+        let options = ImportOptions {
+            shard_threshold: 3,
+            ..Default::default()
+        };
+        let src = dir.path().join("folder");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("one"), b"1").unwrap();
+        let mut root = import_directory(&src, &store, &options).await.unwrap();
+
+        let mut files = Vec::new();
+        for name in ["two", "three", "four"] {
+            let file = crate::storage::import_reader(name.as_bytes(), &store, &options).await.unwrap();
+            root = add_entry(&root, name, &file, &store, &options).await.unwrap();
+            files.push(file);
+        }
+        // past the threshold the folder got sharded
+        let node = load_node_with_record(&root, &store).await.unwrap();
+        assert_eq!(data_type(&node), Some(DataType::HamtShard));
+        assert_eq!(find_entry(&node, "three", &store).await.unwrap().unwrap().cid, files[1]);
+        assert_eq!(node.metadata.as_ref().unwrap().size, 1 + 3 + 5 + 4);
+
+        root = remove_entry(&root, "two", &store, &options).await.unwrap();
+        let node = load_node_with_record(&root, &store).await.unwrap();
+        assert_eq!(find_entry(&node, "two", &store).await.unwrap(), None);
+        assert_eq!(node.metadata.as_ref().unwrap().size, 1 + 5 + 4);
+        assert!(matches!(
+            remove_entry(&root, "two", &store, &options).await,
+            Err(DirectoryErrors::EntryNotFoundError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_export_refuses_escaping_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemoryBlockstore::default();
+        let options = ImportOptions::default();
+
+        let file = crate::storage::import_reader(&b"payload"[..], &store, &options).await.unwrap();
+        let node = directory_of(
+            vec![Link {
+                cid: file,
+                name: "../escape".to_string(),
+                tsize: 7,
+            }],
+            &options.cid_builder,
+        )
+        .unwrap();
+        store.put(&node).await.unwrap();
+
+        let result = export_directory(&node.cid, dir.path().join("out"), &store).await;
+        assert!(matches!(result, Err(DirectoryErrors::InvalidNameError(_))));
+        assert!(!dir.path().join("escape").exists());
+    }
+}
@@ -0,0 +1,441 @@
+use crate::cid::builder::CidBuilder;
+use crate::storage::dag::{dag_pb_node, DagErrors};
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::load::load_node;
+use crate::storage::unixfs::{DataType, UnixFsData};
+use crate::storage::{Link, MerkleNode};
+use cid::Cid;
+use murmur3::murmur3_x64_128;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+// multicodec of murmur3-x64-64, the only hash kubo shards with
+pub const HAMT_HASH_MURMUR3: u64 = 0x22;
+pub const DEFAULT_FANOUT: u64 = 256;
+// directories with more entries than this get sharded on import
+pub const DEFAULT_SHARD_THRESHOLD: usize = 1000;
+
+#[derive(Debug, Error)]
+pub enum HamtErrors {
+    #[error("The node {0} is not a hamt shard")]
+    NotAShardError(Cid),
+    #[error("Fanout {0} is not a power of two between 8 and 65536")]
+    InvalidFanoutError(u64),
+    #[error("Shards hashed with {0:#x} are not supported")]
+    UnsupportedHashError(u64),
+    #[error("The shard link {0:?} has no valid slot prefix")]
+    InvalidLinkNameError(String),
+    #[error("The entry {0:?} is not in this directory")]
+    EntryNotFoundError(String),
+    #[error("Ran out of hash bits while placing {0:?}")]
+    HashExhaustedError(String),
+    #[error(transparent)]
+    DagError(#[from] DagErrors),
+    #[error(transparent)]
+    StorageError(#[from] BlockstoreErrors),
+}
+
+/*
+tldr; how it works
+a sharded directory is a tree of HAMTShard nodes, the same layout go-unixfs writes.
+an entry name is hashed with murmur3 (first 64 bits, big endian) and every level eats
+log2(fanout) bits of that hash, most significant first, to pick one of fanout slots.
+a slot holds either one entry or a child shard, shards only appear once two names collide.
+links are stored in slot order and named with the slot as zero padded upper case hex,
+followed by the entry name for entries and nothing for child shards.
+the node's Data is a bitfield of the used slots, bit i in byte len-1-i/8.
+
+lookup, insert and remove walk one path from the root, and only the shards on that path
+are rewritten. a shard left holding a single entry collapses into its parent, so the tree
+only depends on the set of entries and not on the order they were added in.
+*/
+
+enum Slot {
+    Entry(Link),
+    Shard(Link),
+}
+
+struct Shard {
+    fanout: u64,
+    slots: BTreeMap<u64, Slot>,
+}
+
+impl Shard {
+    fn bits(&self) -> u32 {
+        self.fanout.trailing_zeros()
+    }
+
+    fn pad_len(&self) -> usize {
+        format!("{:X}", self.fanout - 1).len()
+    }
+
+    fn from_node(node: &MerkleNode) -> Result<Shard, HamtErrors> {
+        let unixfs = node
+            .unixfs
+            .as_ref()
+            .filter(|unixfs| unixfs.data_type == DataType::HamtShard)
+            .ok_or(HamtErrors::NotAShardError(node.cid))?;
+        let hash_type = unixfs.hash_type.unwrap_or(HAMT_HASH_MURMUR3);
+        if hash_type != HAMT_HASH_MURMUR3 {
+            return Err(HamtErrors::UnsupportedHashError(hash_type));
+        }
+        let fanout = unixfs.fanout.unwrap_or(DEFAULT_FANOUT);
+        validate_fanout(fanout)?;
+
+        let mut shard = Shard {
+            fanout,
+            slots: BTreeMap::new(),
+        };
+        let pad_len = shard.pad_len();
+        for link in &node.links {
+            let prefix = link
+                .name
+                .get(..pad_len)
+                .ok_or_else(|| HamtErrors::InvalidLinkNameError(link.name.clone()))?;
+            let index = u64::from_str_radix(prefix, 16)
+                .ok()
+                .filter(|index| *index < fanout)
+                .ok_or_else(|| HamtErrors::InvalidLinkNameError(link.name.clone()))?;
+            let mut link = link.clone();
+            link.name = link.name[pad_len..].to_string();
+            let slot = if link.name.is_empty() {
+                Slot::Shard(link)
+            } else {
+                Slot::Entry(link)
+            };
+            shard.slots.insert(index, slot);
+        }
+        Ok(shard)
+    }
+
+    fn to_node(&self, builder: &CidBuilder) -> Result<MerkleNode, HamtErrors> {
+        let pad_len = self.pad_len();
+        let mut bitfield = vec![0u8; (self.fanout / 8) as usize];
+        let mut links = Vec::with_capacity(self.slots.len());
+        for (index, slot) in &self.slots {
+            let len = bitfield.len();
+            bitfield[len - 1 - (*index / 8) as usize] |= 1 << (index % 8);
+            let (link, name) = match slot {
+                Slot::Entry(link) => (link, link.name.as_str()),
+                Slot::Shard(link) => (link, ""),
+            };
+            links.push(Link {
+                cid: link.cid,
+                name: format!("{:0width$X}{}", index, name, width = pad_len),
+                tsize: link.tsize,
+            });
+        }
+        let unixfs = UnixFsData::hamt_shard(bitfield, HAMT_HASH_MURMUR3, self.fanout);
+        Ok(dag_pb_node(links, unixfs, builder)?)
+    }
+}
+
+fn validate_fanout(fanout: u64) -> Result<(), HamtErrors> {
+    if !fanout.is_power_of_two() || !(8..=1 << 16).contains(&fanout) {
+        return Err(HamtErrors::InvalidFanoutError(fanout));
+    }
+    Ok(())
+}
+
+fn hash_name(name: &str) -> u64 {
+    // reading from a slice can not fail, h1 sits in the low half
+    murmur3_x64_128(&mut name.as_bytes(), 0).expect("hashing an in-memory name") as u64
+}
+
+// the slot a name falls in at a given depth
+fn slot_index(name: &str, depth: u32, bits: u32) -> Result<u64, HamtErrors> {
+    let start = depth * bits;
+    if start + bits > 64 {
+        return Err(HamtErrors::HashExhaustedError(name.to_string()));
+    }
+    Ok((hash_name(name) >> (64 - start - bits)) & ((1 << bits) - 1))
+}
+
+fn shard_link(node: &MerkleNode) -> Result<Link, HamtErrors> {
+    Ok(Link {
+        cid: node.cid,
+        name: String::new(),
+        tsize: node.tree_size()?,
+    })
+}
+
+// one level of a fresh shard tree, child shards are collected in children for the caller to store
+fn build_level(
+    entries: Vec<Link>,
+    depth: u32,
+    fanout: u64,
+    builder: &CidBuilder,
+    children: &mut Vec<MerkleNode>,
+) -> Result<MerkleNode, HamtErrors> {
+    let bits = fanout.trailing_zeros();
+    let mut buckets: BTreeMap<u64, Vec<Link>> = BTreeMap::new();
+    for entry in entries {
+        let index = slot_index(&entry.name, depth, bits)?;
+        buckets.entry(index).or_default().push(entry);
+    }
+
+    let mut shard = Shard {
+        fanout,
+        slots: BTreeMap::new(),
+    };
+    for (index, mut bucket) in buckets {
+        let slot = if bucket.len() == 1 {
+            Slot::Entry(bucket.remove(0))
+        } else {
+            let child = build_level(bucket, depth + 1, fanout, builder, children)?;
+            let link = shard_link(&child)?;
+            children.push(child);
+            Slot::Shard(link)
+        };
+        shard.slots.insert(index, slot);
+    }
+    shard.to_node(builder)
+}
+
+// shards a directory's entries, only the inner shards get written so the caller can
+// attach a record to the root before storing it, the same as with directory_of
+pub async fn build_shard(
+    entries: Vec<Link>,
+    fanout: u64,
+    builder: &CidBuilder,
+    store: &dyn Blockstore,
+) -> Result<MerkleNode, HamtErrors> {
+    validate_fanout(fanout)?;
+    let mut children = Vec::new();
+    let root = build_level(entries, 0, fanout, builder, &mut children)?;
+    store.put_many(&children).await?;
+    Ok(root)
+}
+
+async fn load_shard(cid: Cid, store: &dyn Blockstore) -> Result<Shard, HamtErrors> {
+    Shard::from_node(&load_node(&cid, store).await?)
+}
+
+pub async fn hamt_lookup(
+    root: &Cid,
+    name: &str,
+    store: &dyn Blockstore,
+) -> Result<Option<Link>, HamtErrors> {
+    let mut shard = load_shard(*root, store).await?;
+    let mut depth = 0;
+    loop {
+        let index = slot_index(name, depth, shard.bits())?;
+        match shard.slots.remove(&index) {
+            Some(Slot::Shard(link)) => {
+                shard = load_shard(link.cid, store).await?;
+                depth += 1;
+            }
+            Some(Slot::Entry(link)) if link.name == name => return Ok(Some(link)),
+            _ => return Ok(None),
+        }
+    }
+}
+
+// every entry of the sharded directory, in slot order
+pub async fn hamt_entries(root: &Cid, store: &dyn Blockstore) -> Result<Vec<Link>, HamtErrors> {
+    let mut entries = Vec::new();
+    let mut pending = vec![*root];
+    while let Some(cid) = pending.pop() {
+        let shard = load_shard(cid, store).await?;
+        // children are pushed in reverse so they come off the stack in slot order
+        let mut children = Vec::new();
+        for slot in shard.slots.into_values() {
+            match slot {
+                Slot::Entry(link) => entries.push(link),
+                Slot::Shard(link) => children.push(link.cid),
+            }
+        }
+        pending.extend(children.into_iter().rev());
+    }
+    Ok(entries)
+}
+
+// writes the shard at the bottom of a walk and every parent above it, returns the new root
+async fn rewrite_path(
+    mut path: Vec<(Shard, u64)>,
+    mut shard: Shard,
+    builder: &CidBuilder,
+    store: &dyn Blockstore,
+) -> Result<MerkleNode, HamtErrors> {
+    while let Some((mut parent, index)) = path.pop() {
+        match shard.slots.len() {
+            0 => {
+                parent.slots.remove(&index);
+            }
+            1 if matches!(shard.slots.values().next(), Some(Slot::Entry(_))) => {
+                let lone = shard.slots.into_values().next().unwrap();
+                parent.slots.insert(index, lone);
+            }
+            _ => {
+                let node = shard.to_node(builder)?;
+                store.put(&node).await?;
+                parent.slots.insert(index, Slot::Shard(shard_link(&node)?));
+            }
+        }
+        shard = parent;
+    }
+    let root = shard.to_node(builder)?;
+    store.put(&root).await?;
+    Ok(root)
+}
+
+// adds or replaces an entry, returns the new (stored) root shard
+pub async fn hamt_insert(
+    root: &Cid,
+    entry: Link,
+    builder: &CidBuilder,
+    store: &dyn Blockstore,
+) -> Result<MerkleNode, HamtErrors> {
+    let mut path = Vec::new();
+    let mut shard = load_shard(*root, store).await?;
+    let mut depth = 0;
+    loop {
+        let index = slot_index(&entry.name, depth, shard.bits())?;
+        match shard.slots.remove(&index) {
+            Some(Slot::Shard(link)) => {
+                path.push((shard, index));
+                shard = load_shard(link.cid, store).await?;
+                depth += 1;
+            }
+            Some(Slot::Entry(existing)) if existing.name != entry.name => {
+                // two names in one slot, they move one level down into a new shard
+                let mut children = Vec::new();
+                let child = build_level(vec![existing, entry], depth + 1, shard.fanout, builder, &mut children)?;
+                shard.slots.insert(index, Slot::Shard(shard_link(&child)?));
+                children.push(child);
+                store.put_many(&children).await?;
+                return rewrite_path(path, shard, builder, store).await;
+            }
+            _ => {
+                shard.slots.insert(index, Slot::Entry(entry));
+                return rewrite_path(path, shard, builder, store).await;
+            }
+        }
+    }
+}
+
+// removes an entry, returns the new (stored) root shard
+pub async fn hamt_remove(
+    root: &Cid,
+    name: &str,
+    builder: &CidBuilder,
+    store: &dyn Blockstore,
+) -> Result<MerkleNode, HamtErrors> {
+    let mut path = Vec::new();
+    let mut shard = load_shard(*root, store).await?;
+    let mut depth = 0;
+    loop {
+        let index = slot_index(name, depth, shard.bits())?;
+        match shard.slots.remove(&index) {
+            Some(Slot::Shard(link)) => {
+                path.push((shard, index));
+                shard = load_shard(link.cid, store).await?;
+                depth += 1;
+            }
+            Some(Slot::Entry(link)) if link.name == name => {
+                return rewrite_path(path, shard, builder, store).await;
+            }
+            _ => return Err(HamtErrors::EntryNotFoundError(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cid::generate_cid;
+    use crate::storage::blockstore::MemoryBlockstore;
+
+    fn entry(name: &str) -> Link {
+        Link {
+            cid: generate_cid(name.as_bytes()),
+            name: name.to_string(),
+            tsize: name.len() as u64,
+        }
+    }
+
+    async fn build_and_store(names: &[String], fanout: u64, store: &dyn Blockstore) -> MerkleNode {
+        let entries = names.iter().map(|name| entry(name)).collect();
+        let root = build_shard(entries, fanout, &CidBuilder::default(), store).await.unwrap();
+        store.put(&root).await.unwrap();
+        root
+    }
+
+    #[tokio::test]
+    async fn test_shard_node_layout() {
+        let store = MemoryBlockstore::default();
+
+        let root = build_shard(vec![entry("a"), entry("b")], 256, &CidBuilder::default(), &store).await.unwrap();
+        let unixfs = root.unixfs.as_ref().unwrap();
+        assert_eq!(unixfs.data_type, DataType::HamtShard);
+        assert_eq!(unixfs.fanout, Some(256));
+        assert_eq!(unixfs.hash_type, Some(HAMT_HASH_MURMUR3));
+        assert_eq!(unixfs.data.as_ref().unwrap().len(), 32);
+
+        // slot is the top byte of the name's hash, written as two hex digits
+        for link in &root.links {
+            let name = &link.name[2..];
+            let index = hash_name(name) >> 56;
+            assert_eq!(&link.name[..2], format!("{:02X}", index));
+            let bitfield = unixfs.data.as_ref().unwrap();
+            assert_ne!(bitfield[31 - (index / 8) as usize] & (1 << (index % 8)), 0);
+        }
+        assert!(root.verify().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_and_list() {
+        let store = MemoryBlockstore::default();
+        let names: Vec<String> = (0..3000).map(|i| format!("file-{}.txt", i)).collect();
+        // a small fanout gives a deeper tree
+        let root = build_and_store(&names, 16, &store).await;
+
+        for name in names.iter().step_by(97) {
+            let found = hamt_lookup(&root.cid, name, &store).await.unwrap().unwrap();
+            assert_eq!(found, entry(name));
+        }
+        assert_eq!(hamt_lookup(&root.cid, "missing", &store).await.unwrap(), None);
+
+        let mut listed: Vec<String> = hamt_entries(&root.cid, &store)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|link| link.name)
+            .collect();
+        listed.sort();
+        let mut expected = names.clone();
+        expected.sort();
+        assert_eq!(listed, expected);
+    }
+
+    #[tokio::test]
+    async fn test_insert_and_remove_match_a_fresh_build() {
+        let store = MemoryBlockstore::default();
+        let builder = CidBuilder::default();
+        let names: Vec<String> = (0..500).map(|i| format!("entry-{}", i)).collect();
+
+        let mut root = build_and_store(&names[..400], 16, &store).await;
+        for name in &names[400..] {
+            root = hamt_insert(&root.cid, entry(name), &builder, &store).await.unwrap();
+        }
+        assert_eq!(root.cid, build_and_store(&names, 16, &store).await.cid);
+
+        for name in &names[..250] {
+            root = hamt_remove(&root.cid, name, &builder, &store).await.unwrap();
+        }
+        assert_eq!(root.cid, build_and_store(&names[250..], 16, &store).await.cid);
+        assert_eq!(hamt_lookup(&root.cid, &names[10], &store).await.unwrap(), None);
+
+        assert!(matches!(
+            hamt_remove(&root.cid, "missing", &builder, &store).await,
+            Err(HamtErrors::EntryNotFoundError(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_fanout() {
+        assert!(matches!(validate_fanout(12), Err(HamtErrors::InvalidFanoutError(12))));
+        assert!(matches!(validate_fanout(4), Err(HamtErrors::InvalidFanoutError(4))));
+        assert!(validate_fanout(DEFAULT_FANOUT).is_ok());
+    }
+}
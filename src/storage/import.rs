@@ -0,0 +1,350 @@
+use crate::cid::builder::CidBuilder;
+use crate::cid::chunker::{ChunkerErrors, ChunkerType};
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::dag::{leaf_of, DagErrors};
+use crate::storage::hamt::{DEFAULT_FANOUT, DEFAULT_SHARD_THRESHOLD};
+use crate::storage::layout::DagLayout;
+use crate::storage::metadata::FileMetadata;
+use crate::storage::reassemble::detect_file_type;
+use crate::storage::MerkleNode;
+use cid::Cid;
+use std::path::Path;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// leaf bytes held back before a partial batch is written, see import_root
+const BATCH_BYTES: usize = 8 << 20;
+
+#[derive(Debug, Error)]
+pub enum ImportErrors {
+    #[error("The import did not produce a root node")]
+    EmptyError,
+    #[error("Error reading the input: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Error writing a block: {0}")]
+    StorageError(#[from] BlockstoreErrors),
+    #[error(transparent)]
+    ChunkerError(#[from] ChunkerErrors),
+    #[error(transparent)]
+    DagError(#[from] DagErrors),
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub chunker: ChunkerType,
+    pub layout: DagLayout,
+    // version and hash function of every cid, the codec is picked per node:
+    // raw leaves (unixfs wrapped under v0) and dag-pb parents
+    pub cid_builder: CidBuilder,
+    // directories with more entries than this become hamt shards of this fanout
+    pub shard_threshold: usize,
+    pub hamt_fanout: u64,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            chunker: ChunkerType::default(),
+            layout: DagLayout::default(),
+            cid_builder: CidBuilder::default(),
+            shard_threshold: DEFAULT_SHARD_THRESHOLD,
+            hamt_fanout: DEFAULT_FANOUT,
+        }
+    }
+}
+
+/*
+tldr; how it works
+reads one chunk at a time and hands each leaf to the layout builder, which gives back
+the parents it completed. finished nodes are collected and go to the store as one
+put_many, so a file that fits in a batch is stored all-or-nothing. a bigger one is
+flushed every BATCH_BYTES, children first, and the root (the last node built, lone leaf
+for a file of one chunk) only ever goes out in the final batch with its file record:
+a crash halfway leaves loose blocks for gc, never something that looks like a file.
+memory holds at most a batch plus the open nodes of the layout, never the whole file.
+*/
+pub async fn import_reader<R: AsyncRead + Unpin>(
+    reader: R,
+    store: &dyn Blockstore,
+    options: &ImportOptions,
+) -> Result<Cid, ImportErrors> {
+    import_reader_with_metadata(reader, store, options, FileMetadata::default()).await
+}
+
+// size and, when not already known, the mime type are filled in from the stream
+pub async fn import_reader_with_metadata<R: AsyncRead + Unpin>(
+    reader: R,
+    store: &dyn Blockstore,
+    options: &ImportOptions,
+    metadata: FileMetadata,
+) -> Result<Cid, ImportErrors> {
+    let root = import_root(reader, store, options, metadata).await?;
+    Ok(root.cid)
+}
+
+// same as above but hands back the stored root, directories need its tree size for their links
+pub(crate) async fn import_root<R: AsyncRead + Unpin>(
+    mut reader: R,
+    store: &dyn Blockstore,
+    options: &ImportOptions,
+    mut metadata: FileMetadata,
+) -> Result<MerkleNode, ImportErrors> {
+    let chunker = options.chunker.build()?;
+    let max_size = chunker.max_size();
+    let mut buffer: Vec<u8> = Vec::with_capacity(max_size);
+    let mut builder = options.layout.builder(options.cid_builder.clone());
+    let mut leaf_count: u64 = 0;
+    let mut total_size: u64 = 0;
+    // children before parents, flushed whenever a batch worth of leaf bytes is waiting
+    let mut pending: Vec<MerkleNode> = Vec::new();
+    let mut pending_bytes: usize = 0;
+    let mut eof = false;
+
+    loop {
+        while !eof && buffer.len() < max_size {
+            let filled = buffer.len();
+            buffer.resize(max_size, 0);
+            let bytes_read = reader.read(&mut buffer[filled..]).await?;
+            buffer.truncate(filled + bytes_read);
+            eof = bytes_read == 0;
+        }
+        // an empty file still gets one (empty) leaf, the same block kubo names it with
+        if buffer.is_empty() && leaf_count > 0 {
+            break;
+        }
+        if leaf_count == 0 && metadata.mime_type.is_none() {
+            metadata.mime_type = detect_file_type(&buffer);
+        }
+        let len = chunker.cut(&buffer);
+        let chunk: Vec<u8> = buffer.drain(..len).collect();
+        total_size += len as u64;
+        let leaf = leaf_of(chunk, &options.cid_builder)?;
+        pending.push(leaf.clone());
+        leaf_count += 1;
+        pending.extend(builder.push(leaf)?);
+        pending_bytes += len;
+        // the newest node may turn out to be the root, it stays for the last batch
+        if pending_bytes >= BATCH_BYTES && pending.len() > 1 {
+            let newest = pending.pop();
+            store.put_many(&pending).await?;
+            pending.clear();
+            pending.extend(newest);
+            pending_bytes = 0;
+        }
+    }
+
+    let (parents, root) = builder.finish()?;
+    pending.extend(parents);
+
+    let mut root_node = match pending.pop() {
+        Some(node) if node.cid == root => node,
+        _ => return Err(ImportErrors::EmptyError),
+    };
+    metadata.size = total_size;
+    root_node.metadata = Some(metadata);
+    pending.push(root_node.clone());
+    store.put_many(&pending).await?;
+    Ok(root_node)
+}
+
+pub async fn import_file<P: AsRef<Path>>(
+    path: P,
+    store: &dyn Blockstore,
+    options: &ImportOptions,
+) -> Result<Cid, ImportErrors> {
+    let root = import_file_root(path, store, options).await?;
+    Ok(root.cid)
+}
+
+pub(crate) async fn import_file_root<P: AsRef<Path>>(
+    path: P,
+    store: &dyn Blockstore,
+    options: &ImportOptions,
+) -> Result<MerkleNode, ImportErrors> {
+    let metadata = FileMetadata::from_path(&path)?;
+    let file = tokio::fs::File::open(path).await?;
+    import_root(tokio::io::BufReader::new(file), store, options, metadata).await
+}
+
+// a file split into 4 byte chunks, so even a short one is a dag a few links deep
+#[cfg(test)]
+pub(crate) async fn file_dag(store: &dyn Blockstore, data: &[u8]) -> Cid {
+    let options = ImportOptions {
+        chunker: ChunkerType::FixedSize(4),
+        ..Default::default()
+    };
+    import_reader(data, store, &options).await.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::dag::generate_merkle_tree;
+    use crate::storage::blockstore::MemoryBlockstore;
+    use crate::storage::reassemble::return_node_from_db_with_handle;
+
+    #[tokio::test]
+    async fn test_streaming_import_matches_generate_merkle_tree() {
+        let store = MemoryBlockstore::default();
+        let options = ImportOptions {
+            chunker: ChunkerType::FixedSize(4),
+            layout: DagLayout::Binary,
+            ..Default::default()
+        };
+
+        for leaf_count in 1..=17usize {
+            let data: Vec<u8> = (0..leaf_count * 4).map(|i| (i * 7 % 251) as u8).collect();
+            let root = import_reader(data.as_slice(), &store, &options).await.unwrap();
+
+            let leaves = data.chunks(4).map(crate::storage::create_leaf).collect();
+            let tree = generate_merkle_tree(leaves, FileMetadata::default()).unwrap();
+            assert_eq!(root, tree.last().unwrap().cid, "{} leaves", leaf_count);
+
+            for node in tree {
+                assert!(return_node_from_db_with_handle(node.cid.to_string(), &store)
+                    .await
+                    .is_some());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streaming_import_with_other_hash() {
+        use crate::cid::builder::HashAlgorithm;
+
+        let store = MemoryBlockstore::default();
+        let options = ImportOptions {
+            chunker: ChunkerType::FixedSize(4),
+            cid_builder: CidBuilder::new().hash(HashAlgorithm::Blake3),
+            ..Default::default()
+        };
+        let root = import_reader(&b"some bytes hashed with blake3"[..], &store, &options)
+            .await
+            .unwrap();
+        assert_eq!(root.hash().code(), HashAlgorithm::Blake3.code());
+
+        let node = return_node_from_db_with_handle(root.to_string(), &store).await.unwrap();
+        assert!(node.verify().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_streaming_import_cid_v0() {
+        let store = MemoryBlockstore::default();
+        let options = ImportOptions {
+            chunker: ChunkerType::FixedSize(4),
+            cid_builder: CidBuilder::v0(),
+            ..Default::default()
+        };
+        let root = import_reader(&b"hello wo"[..], &store, &options).await.unwrap();
+        assert!(root.to_string().starts_with("Qm"));
+
+        let node = return_node_from_db_with_handle(root.to_string(), &store).await.unwrap();
+        assert_eq!(node.file_size(), 8);
+        assert_eq!(node.links.len(), 2);
+        for link in node.links {
+            let leaf = return_node_from_db_with_handle(link.cid.to_string(), &store).await.unwrap();
+            assert!(leaf.content().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streaming_import_layouts() {
+        let store = MemoryBlockstore::default();
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 253) as u8).collect();
+
+        for layout in [
+            DagLayout::Balanced { max_links: 4 },
+            DagLayout::Trickle { max_links: 4, layer_repeat: 2 },
+        ] {
+            let options = ImportOptions {
+                chunker: ChunkerType::FixedSize(10),
+                layout,
+                ..Default::default()
+            };
+            let root = import_reader(data.as_slice(), &store, &options).await.unwrap();
+            let node = return_node_from_db_with_handle(root.to_string(), &store).await.unwrap();
+            assert_eq!(node.file_size(), 1000);
+            assert!(node.verify().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_file_records_metadata() {
+        use crate::storage::metadata::stat_with_handle;
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemoryBlockstore::default();
+        let file_path = dir.path().join("image.png");
+        let mut png = vec![0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+        png.extend(std::iter::repeat_n(7u8, 100));
+        std::fs::File::create(&file_path).unwrap().write_all(&png).unwrap();
+
+        for chunk_size in [16, 4096] {
+            let options = ImportOptions {
+                chunker: ChunkerType::FixedSize(chunk_size),
+                ..Default::default()
+            };
+            let root = import_file(&file_path, &store, &options).await.unwrap();
+            let metadata = stat_with_handle(root.to_string(), &store).await.unwrap();
+            assert_eq!(metadata.size, 108);
+            assert_eq!(metadata.mime_type.as_deref(), Some("image/png"));
+            assert_eq!(metadata.filename.as_deref(), Some("image.png"));
+            assert!(metadata.mtime.is_some());
+
+            // the record does not touch the block, a single chunk root keeps its bytes
+            let node = return_node_from_db_with_handle(root.to_string(), &store).await.unwrap();
+            assert!(node.verify().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streaming_import_of_empty_input() {
+        let store = MemoryBlockstore::default();
+        // kubo: ipfs add --cid-version 1 on an empty file
+        let root = import_reader(&b""[..], &store, &ImportOptions::default()).await.unwrap();
+        assert_eq!(root.to_string(), "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku");
+
+        let options = ImportOptions {
+            cid_builder: CidBuilder::v0(),
+            ..Default::default()
+        };
+        let root = import_reader(&b""[..], &store, &options).await.unwrap();
+        assert_eq!(root.to_string(), "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH");
+        let node = return_node_from_db_with_handle(root.to_string(), &store).await.unwrap();
+        assert_eq!(node.metadata.unwrap().size, 0);
+    }
+
+    #[tokio::test]
+    async fn test_failed_import_stores_nothing() {
+        let store = MemoryBlockstore::default();
+        let options = ImportOptions {
+            chunker: ChunkerType::FixedSize(4),
+            ..Default::default()
+        };
+        // a few chunks come through, then the source breaks
+        let broken = tokio::io::AsyncReadExt::chain(
+            &b"some chunks before the error"[..],
+            broken_reader(),
+        );
+        assert!(matches!(
+            import_reader(broken, &store, &options).await,
+            Err(ImportErrors::IoError(_))
+        ));
+        assert!(store.list().await.unwrap().is_empty());
+    }
+
+    fn broken_reader() -> impl AsyncRead + Unpin {
+        struct Broken;
+        impl AsyncRead for Broken {
+            fn poll_read(
+                self: std::pin::Pin<&mut Self>,
+                _: &mut std::task::Context<'_>,
+                _: &mut tokio::io::ReadBuf<'_>,
+            ) -> std::task::Poll<std::io::Result<()>> {
+                std::task::Poll::Ready(Err(std::io::Error::other("disk went away")))
+            }
+        }
+        Broken
+    }
+}
@@ -1,20 +1,7 @@
-use fjall::{Config, Error, Keyspace, PartitionCreateOptions, PartitionHandle};
-use std::fs;
-use std::path::PathBuf;
-use serde_json;
+use crate::repo::Repo;
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors, FjallBlockstore};
 use crate::storage::MerkleNode;
 
-pub async fn init_db(path: String) -> Result<PartitionHandle, Error> {
-    let folder: PathBuf = PathBuf::from(path);
-    if !folder.exists() {
-        fs::create_dir_all(&folder)?;
-    }
-    let keyspace: Keyspace = Config::new(folder).open()?;
-    let items: PartitionHandle =
-        keyspace.open_partition("slices", PartitionCreateOptions::default())?;
-    Ok(items)
-}
-
 /*
 tldr; how it works
 take a tree, each node is added iteratively to the kv store, with key being its cid value
@@ -38,55 +25,29 @@ Proof
     Contradiction: We assumed N1≠N2​, but we just proved that their content must be identical.
     Therefore, if two nodes have the same CID, they must be identical. 
  */
-pub async fn store_file(tree: Vec<MerkleNode>) -> bool {
-    let items = init_db(String::from("./tmp/data")).await.unwrap();
-    let mut success = true;  
-
-    for x in tree.iter() {
-        let value = serde_json::to_string(x).unwrap();
-        let output = items.insert(x.cid.to_string(), value);
-
-        match output {
-            Ok(()) => println!("Success storing the file"),
-            Err(error) => {
-                eprintln!("Error while storing the file: {:?}", error);
-                success = false;  
-            }
-        }
-    }
-    
-    success  
+pub async fn store_file(tree: Vec<MerkleNode>, repo: &Repo) -> Result<(), BlockstoreErrors> {
+    let store = FjallBlockstore::new(repo);
+    store_file_with_handle(tree, &store).await
 }
 
-pub async fn store_file_with_handle(tree: Vec<MerkleNode>, items: PartitionHandle) -> bool {
-    let mut success = true;
-
-    for x in tree.iter() {
-        let value = serde_json::to_string(x).unwrap();
-        let output = items.insert(x.cid.to_string(), value);
-
-        match output {
-            Ok(()) => println!("Success storing the file"),
-            Err(error) => {
-                eprintln!("Error while storing the file: {:?}", error);
-                success = false;
-            }
-        }
-    }
-
-    success
+// the whole tree in one write, either every node is stored or none is
+pub async fn store_file_with_handle(
+    tree: Vec<MerkleNode>,
+    store: &dyn Blockstore,
+) -> Result<(), BlockstoreErrors> {
+    store.put_many(&tree).await
 }
 
-
-
 #[cfg(test)]
 mod tests {
-    use super::init_db;
+    use crate::repo::Repo;
 
     #[tokio::test]
     async fn test_insert() {
-        let items = init_db(String::from("./tmp/data")).await.unwrap();
-        let _ = items.insert("key", "value").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repo::open(dir.path()).unwrap();
+        let items = repo.blocks();
+        items.insert("key", "value").unwrap();
         let ret: fjall::Slice = items.get("key").unwrap().unwrap();
         let value_from_db = String::from_utf8_lossy(ret.as_ref()).to_string();
         assert_eq!(value_from_db, "value");
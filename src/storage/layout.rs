@@ -0,0 +1,355 @@
+use crate::cid::builder::CidBuilder;
+use crate::storage::dag::{parent_of, parent_of_pair, parent_of_single, DagErrors};
+use crate::storage::MerkleNode;
+use cid::Cid;
+
+// kubo's defaults for both layouts
+pub const DEFAULT_MAX_LINKS: usize = 174;
+pub const DEFAULT_LAYER_REPEAT: usize = 4;
+
+/*
+tldr; how it works
+the shape of the dag above the leaves, picked per import alongside the chunker.
+binary is the original pairwise tree generate_merkle_tree builds.
+balanced packs up to max_links children into every parent and keeps all leaves at the same
+depth, so a file of n leaves is only log_max_links(n) levels deep.
+trickle puts max_links leaves straight under every node, followed by layer_repeat subtrees
+of each increasing depth. the first bytes sit right under the root and appending only
+touches the right edge, which suits streaming media.
+every builder takes leaves one at a time and hands back parents as they complete, so an
+import never holds more than one open node per level.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DagLayout {
+    Binary,
+    Balanced { max_links: usize },
+    Trickle { max_links: usize, layer_repeat: usize },
+}
+
+impl Default for DagLayout {
+    fn default() -> Self {
+        DagLayout::Balanced {
+            max_links: DEFAULT_MAX_LINKS,
+        }
+    }
+}
+
+impl DagLayout {
+    pub(crate) fn builder(&self, cid_builder: CidBuilder) -> Box<dyn LayoutBuilder> {
+        match *self {
+            DagLayout::Binary => Box::new(BinaryBuilder {
+                cid_builder,
+                levels: Vec::new(),
+            }),
+            DagLayout::Balanced { max_links } => Box::new(BalancedBuilder {
+                cid_builder,
+                max_links: max_links.max(2),
+                levels: Vec::new(),
+            }),
+            DagLayout::Trickle {
+                max_links,
+                layer_repeat,
+            } => Box::new(TrickleBuilder {
+                cid_builder,
+                max_links: max_links.max(1),
+                layer_repeat: layer_repeat.max(1),
+                stack: Vec::new(),
+            }),
+        }
+    }
+}
+
+pub(crate) trait LayoutBuilder: Send {
+    // takes the next leaf in file order, returns the parents it completed
+    fn push(&mut self, leaf: MerkleNode) -> Result<Vec<MerkleNode>, DagErrors>;
+    // closes every open node, returns them along with the root cid
+    fn finish(self: Box<Self>) -> Result<(Vec<MerkleNode>, Cid), DagErrors>;
+}
+
+/*
+levels[i] holds the node at height i still waiting for a right sibling, new nodes pair up
+and carry like a binary counter. whatever is left at the end is the odd node of its level:
+it pairs with the parked node of the level it lands on, or gets a single-link parent if
+more levels sit above it.
+*/
+struct BinaryBuilder {
+    cid_builder: CidBuilder,
+    levels: Vec<Option<MerkleNode>>,
+}
+
+impl LayoutBuilder for BinaryBuilder {
+    fn push(&mut self, leaf: MerkleNode) -> Result<Vec<MerkleNode>, DagErrors> {
+        let mut created = Vec::new();
+        let mut node = leaf;
+        let mut level = 0;
+        loop {
+            if self.levels.len() <= level {
+                self.levels.push(None);
+            }
+            match self.levels[level].take() {
+                None => {
+                    self.levels[level] = Some(node);
+                    return Ok(created);
+                }
+                Some(left) => {
+                    node = parent_of_pair(&left, &node, &self.cid_builder)?;
+                    created.push(node.clone());
+                    level += 1;
+                }
+            }
+        }
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(Vec<MerkleNode>, Cid), DagErrors> {
+        let mut created = Vec::new();
+        let mut carry: Option<MerkleNode> = None;
+        for level in 0..self.levels.len() {
+            let node = match (self.levels[level].take(), carry.take()) {
+                (Some(left), Some(right)) => {
+                    let parent = parent_of_pair(&left, &right, &self.cid_builder)?;
+                    created.push(parent.clone());
+                    carry = Some(parent);
+                    continue;
+                }
+                (Some(node), None) | (None, Some(node)) => node,
+                (None, None) => continue,
+            };
+            if self.levels[level + 1..].iter().all(Option::is_none) {
+                return Ok((created, node.cid));
+            }
+            let parent = parent_of_single(&node, &self.cid_builder)?;
+            created.push(parent.clone());
+            carry = Some(parent);
+        }
+        let root = carry.ok_or(DagErrors::EmptyError)?;
+        Ok((created, root.cid))
+    }
+}
+
+/*
+levels[i] collects the children of the next node at height i+1. once a level holds
+max_links nodes and another one arrives, the full level becomes a parent one level up.
+on finish every partial level is closed the same way, bottom up, so leaves stay at one
+depth and a lone leaf is its own root.
+*/
+struct BalancedBuilder {
+    cid_builder: CidBuilder,
+    max_links: usize,
+    levels: Vec<Vec<MerkleNode>>,
+}
+
+impl BalancedBuilder {
+    fn add(
+        &mut self,
+        level: usize,
+        node: MerkleNode,
+        created: &mut Vec<MerkleNode>,
+    ) -> Result<(), DagErrors> {
+        if self.levels.len() <= level {
+            self.levels.push(Vec::new());
+        }
+        if self.levels[level].len() == self.max_links {
+            let full = std::mem::take(&mut self.levels[level]);
+            let parent = parent_of(&full.iter().collect::<Vec<_>>(), &self.cid_builder)?;
+            created.push(parent.clone());
+            self.add(level + 1, parent, created)?;
+        }
+        self.levels[level].push(node);
+        Ok(())
+    }
+}
+
+impl LayoutBuilder for BalancedBuilder {
+    fn push(&mut self, leaf: MerkleNode) -> Result<Vec<MerkleNode>, DagErrors> {
+        let mut created = Vec::new();
+        self.add(0, leaf, &mut created)?;
+        Ok(created)
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(Vec<MerkleNode>, Cid), DagErrors> {
+        let mut created = Vec::new();
+        let mut level = 0;
+        while level < self.levels.len() {
+            let nodes = std::mem::take(&mut self.levels[level]);
+            if nodes.is_empty() {
+                level += 1;
+                continue;
+            }
+            let higher = self.levels[level + 1..].iter().any(|nodes| !nodes.is_empty());
+            if !higher && nodes.len() == 1 {
+                return Ok((created, nodes[0].cid));
+            }
+            let parent = parent_of(&nodes.iter().collect::<Vec<_>>(), &self.cid_builder)?;
+            created.push(parent.clone());
+            self.add(level + 1, parent, &mut created)?;
+            level += 1;
+        }
+        Err(DagErrors::EmptyError)
+    }
+}
+
+/*
+the recursive trickle fill from go-unixfs, unrolled into a stack so it can be fed one leaf
+at a time. a frame is a node being filled: first up to max_links leaves, then layer_repeat
+children of depth 1, layer_repeat of depth 2 and so on. a child of depth d stops before
+its own depth d layer, the root never stops. children are only opened when a leaf needs
+them, so the tree ends exactly where the input does.
+*/
+struct TrickleFrame {
+    children: Vec<MerkleNode>,
+    depth: usize, // 0 while taking leaves, otherwise the depth of the subtrees being added
+    repeat: usize,
+    max_depth: Option<usize>,
+}
+
+struct TrickleBuilder {
+    cid_builder: CidBuilder,
+    max_links: usize,
+    layer_repeat: usize,
+    stack: Vec<TrickleFrame>,
+}
+
+impl TrickleBuilder {
+    fn close_top(&mut self, created: &mut Vec<MerkleNode>) -> Result<MerkleNode, DagErrors> {
+        let frame = self.stack.pop().expect("close_top is only called on an open frame");
+        let node = parent_of(&frame.children.iter().collect::<Vec<_>>(), &self.cid_builder)?;
+        created.push(node.clone());
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children.push(node.clone());
+            parent.repeat += 1;
+            if parent.repeat == self.layer_repeat {
+                parent.repeat = 0;
+                parent.depth += 1;
+            }
+        }
+        Ok(node)
+    }
+}
+
+impl LayoutBuilder for TrickleBuilder {
+    fn push(&mut self, leaf: MerkleNode) -> Result<Vec<MerkleNode>, DagErrors> {
+        let mut created = Vec::new();
+        if self.stack.is_empty() {
+            self.stack.push(TrickleFrame {
+                children: Vec::new(),
+                depth: 0,
+                repeat: 0,
+                max_depth: None,
+            });
+        }
+        loop {
+            let top = self.stack.last_mut().expect("the root frame is always open");
+            if top.depth == 0 {
+                if top.children.len() < self.max_links {
+                    top.children.push(leaf);
+                    return Ok(created);
+                }
+                top.depth = 1;
+            }
+            if top.max_depth.is_some_and(|max_depth| top.depth >= max_depth) {
+                self.close_top(&mut created)?;
+                continue;
+            }
+            let depth = top.depth;
+            self.stack.push(TrickleFrame {
+                children: Vec::new(),
+                depth: 0,
+                repeat: 0,
+                max_depth: Some(depth),
+            });
+        }
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(Vec<MerkleNode>, Cid), DagErrors> {
+        let mut created = Vec::new();
+        let mut root = None;
+        while !self.stack.is_empty() {
+            root = Some(self.close_top(&mut created)?);
+        }
+        let root = root.ok_or(DagErrors::EmptyError)?;
+        Ok((created, root.cid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::create_leaf;
+    use std::collections::HashMap;
+
+    fn build(layout: DagLayout, leaf_count: u8) -> (HashMap<Cid, MerkleNode>, Cid) {
+        let mut builder = layout.builder(CidBuilder::default());
+        let mut nodes = HashMap::new();
+        for i in 1..=leaf_count {
+            let leaf = create_leaf(&[i]);
+            nodes.insert(leaf.cid, leaf.clone());
+            for parent in builder.push(leaf).unwrap() {
+                nodes.insert(parent.cid, parent);
+            }
+        }
+        let (created, root) = builder.finish().unwrap();
+        for parent in created {
+            nodes.insert(parent.cid, parent);
+        }
+        (nodes, root)
+    }
+
+    // leaves print as their byte, parents as their children in parentheses
+    fn render(nodes: &HashMap<Cid, MerkleNode>, cid: &Cid) -> String {
+        let node = &nodes[cid];
+        if node.links.is_empty() {
+            return node.data.as_ref().unwrap()[0].to_string();
+        }
+        let children: Vec<String> = node.links.iter().map(|link| render(nodes, &link.cid)).collect();
+        format!("({})", children.join(" "))
+    }
+
+    #[test]
+    fn test_balanced_layout_shapes() {
+        let layout = DagLayout::Balanced { max_links: 3 };
+        let (nodes, root) = build(layout, 1);
+        assert_eq!(render(&nodes, &root), "1");
+        let (nodes, root) = build(layout, 3);
+        assert_eq!(render(&nodes, &root), "(1 2 3)");
+        let (nodes, root) = build(layout, 4);
+        assert_eq!(render(&nodes, &root), "((1 2 3) (4))");
+        let (nodes, root) = build(layout, 10);
+        assert_eq!(render(&nodes, &root), "(((1 2 3) (4 5 6) (7 8 9)) ((10)))");
+
+        let root_node = &nodes[&root];
+        assert_eq!(root_node.file_size(), 10);
+        assert_eq!(root_node.unixfs.as_ref().unwrap().blocksizes, vec![9, 1]);
+    }
+
+    #[test]
+    fn test_trickle_layout_shapes() {
+        let layout = DagLayout::Trickle {
+            max_links: 2,
+            layer_repeat: 2,
+        };
+        let (nodes, root) = build(layout, 1);
+        assert_eq!(render(&nodes, &root), "(1)");
+        let (nodes, root) = build(layout, 6);
+        assert_eq!(render(&nodes, &root), "(1 2 (3 4) (5 6))");
+        let (nodes, root) = build(layout, 10);
+        assert_eq!(render(&nodes, &root), "(1 2 (3 4) (5 6) (7 8 (9 10)))");
+        let (nodes, root) = build(layout, 19);
+        assert_eq!(
+            render(&nodes, &root),
+            "(1 2 (3 4) (5 6) (7 8 (9 10) (11 12)) (13 14 (15 16) (17 18)) (19))"
+        );
+    }
+
+    #[test]
+    fn test_binary_layout_matches_generate_merkle_tree() {
+        use crate::storage::dag::generate_merkle_tree;
+        use crate::storage::metadata::FileMetadata;
+
+        for leaf_count in 1..=9u8 {
+            let (_, root) = build(DagLayout::Binary, leaf_count);
+            let leaves = (1..=leaf_count).map(|i| create_leaf(&[i])).collect();
+            let tree = generate_merkle_tree(leaves, FileMetadata::default()).unwrap();
+            assert_eq!(root, tree.last().unwrap().cid);
+        }
+    }
+}
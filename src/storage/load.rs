@@ -0,0 +1,31 @@
+use crate::cid::builder::verify_cid;
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::MerkleNode;
+use cid::Cid;
+
+/*
+tldr; how it works
+what every walk over a dag (resolving paths, exporting folders, hamt lookups, cat) reads
+its blocks through. a block that is not there (NotFoundError), one that does not hash to
+its cid (CorruptedError) and a store that fails are told apart, so a missing path is not
+confused with a disk going bad. callers fold these into their own errors with #[from].
+*/
+pub async fn load_block(cid: &Cid, store: &dyn Blockstore) -> Result<Vec<u8>, BlockstoreErrors> {
+    let block = store.get_block(cid).await?.ok_or(BlockstoreErrors::NotFoundError(*cid))?;
+    if !verify_cid(cid, &block).unwrap_or(false) {
+        return Err(BlockstoreErrors::CorruptedError(*cid));
+    }
+    Ok(block)
+}
+
+// the node without its file record
+pub async fn load_node(cid: &Cid, store: &dyn Blockstore) -> Result<MerkleNode, BlockstoreErrors> {
+    Ok(MerkleNode::decode(*cid, &load_block(cid, store).await?)?)
+}
+
+// the node with its file record, like Blockstore::get
+pub async fn load_node_with_record(cid: &Cid, store: &dyn Blockstore) -> Result<MerkleNode, BlockstoreErrors> {
+    let mut node = load_node(cid, store).await?;
+    node.metadata = store.get_metadata(cid).await?;
+    Ok(node)
+}
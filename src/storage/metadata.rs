@@ -0,0 +1,128 @@
+use crate::repo::Repo;
+use crate::storage::blockstore::{Blockstore, FjallBlockstore};
+use crate::storage::MerkleNode;
+use crate::storage::reassemble::{return_node_from_db_with_handle, ReassembleErrors};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::SystemTime;
+
+/*
+tldr; how it works
+what we know about a file beyond its bytes. it rides along on the root node of the file's
+dag but is not part of the encoded block, so it never changes the root cid and never mixes
+with chunk payloads. two imports of identical bytes share a root, the last one's record wins.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    pub size: u64,
+    pub mime_type: Option<String>,
+    pub filename: Option<String>,
+    pub mode: Option<u32>,
+    pub mtime: Option<SystemTime>,
+}
+
+impl FileMetadata {
+    // everything the filesystem knows, size and mime type get filled in by the import
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let fs_metadata = std::fs::metadata(path)?;
+        Ok(FileMetadata {
+            size: fs_metadata.len(),
+            mime_type: None,
+            filename: path.file_name().map(|name| name.to_string_lossy().to_string()),
+            mode: unix_mode(&fs_metadata),
+            mtime: fs_metadata.modified().ok(),
+        })
+    }
+
+    // puts mode and mtime back on an exported file or directory, mtime first since the
+    // recorded mode may not let us open it anymore
+    pub fn apply_to_path<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref();
+        if let Some(mtime) = self.mtime {
+            std::fs::File::open(path)?.set_modified(mtime)?;
+        }
+        if let Some(mode) = self.mode {
+            set_unix_mode(path, mode)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn unix_mode(fs_metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(fs_metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn unix_mode(_fs_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_unix_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_unix_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+pub async fn stat(cid_string: String, repo: &Repo) -> Result<FileMetadata, ReassembleErrors> {
+    let store = FjallBlockstore::new(repo);
+    stat_with_handle(cid_string, &store).await
+}
+
+// nodes imported without a record (or inner nodes) still report their size
+pub async fn stat_with_handle(
+    cid_string: String,
+    store: &dyn Blockstore,
+) -> Result<FileMetadata, ReassembleErrors> {
+    let node = return_node_from_db_with_handle(cid_string, store)
+        .await
+        .ok_or(ReassembleErrors::RootNodeNotFoundError)?;
+    Ok(record_of(&node))
+}
+
+pub(crate) fn record_of(node: &MerkleNode) -> FileMetadata {
+    node.metadata.clone().unwrap_or_else(|| FileMetadata {
+        size: node.file_size(),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::dag::{create_leaf, generate_merkle_tree};
+    use crate::storage::blockstore::MemoryBlockstore;
+    use crate::storage::init_db::store_file_with_handle;
+
+    #[tokio::test]
+    async fn test_stat_returns_root_metadata() {
+        let store = MemoryBlockstore::default();
+
+        let leaves = vec![create_leaf(b"File Chunk 1"), create_leaf(b"File Chunk 2")];
+        let metadata = FileMetadata {
+            size: 24,
+            mime_type: Some("text/plain".to_string()),
+            filename: Some("chunks.txt".to_string()),
+            mode: Some(0o644),
+            mtime: Some(SystemTime::UNIX_EPOCH),
+        };
+        let tree = generate_merkle_tree(leaves.clone(), metadata.clone()).unwrap();
+        let root = tree.last().unwrap().cid.to_string();
+        store_file_with_handle(tree, &store).await.unwrap();
+
+        assert_eq!(stat_with_handle(root, &store).await.unwrap(), metadata);
+        // an inner node without a record still knows its size
+        let leaf = leaves[0].cid.to_string();
+        assert_eq!(stat_with_handle(leaf, &store).await.unwrap().size, 12);
+        assert!(stat_with_handle(crate::cid::generate_cid(b"missing").to_string(), &store)
+            .await
+            .is_err());
+    }
+}
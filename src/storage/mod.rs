@@ -1,7 +1,26 @@
+pub mod blockstore;
+pub mod car;
+pub mod cat;
 pub mod dag;
+pub mod dag_pb;
+pub mod directory;
+pub mod hamt;
+pub mod import;
 pub mod init_db;
+pub mod layout;
+pub mod load;
+pub mod metadata;
 pub mod reassemble;
+pub mod scrub;
+pub mod unixfs;
 
-pub use init_db::{init_db,store_file};
-pub use reassemble::detect_file_type;
-pub use dag::{MerkleNode,create_leaf};
\ No newline at end of file
+pub use blockstore::{Blockstore, FjallBlockstore, FlatfsBlockstore, MemoryBlockstore};
+pub use car::{export_car, import_car, CarVersion};
+pub use cat::{cat, CatReader};
+pub use init_db::store_file;
+pub use reassemble::{detect_file_type, read_range};
+pub use dag::{MerkleNode,Link,create_leaf};
+pub use directory::{export_directory, import_directory};
+pub use import::{import_file, import_reader, import_reader_with_metadata, ImportOptions};
+pub use layout::DagLayout;
+pub use metadata::{stat, FileMetadata};
\ No newline at end of file
@@ -1,41 +1,56 @@
-use crate::storage::init_db;
+use crate::repo::Repo;
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors, FjallBlockstore};
+use crate::storage::cat::{load_file_root, walk_bytes, CatErrors, Walk, DEFAULT_PREFETCH};
 use crate::storage::MerkleNode;
 use cid::Cid;
+use futures::StreamExt;
 use infer::get;
-use queues::*;
 use thiserror::Error;
 // use serde::{Serialize, Deserialize};
 
-const PATH: &str = "./tmp/data";
-
 #[derive(Debug, Error)]
 pub enum ReassembleErrors {
     #[error("The root node with this cid does not exist here.")]
     RootNodeNotFoundError,
     #[error("The node with this cid does not exist here.")]
     NotFoundError,
+    #[error("The node with this cid does not match its hash.")]
+    CorruptedNodeError,
+    #[error("The node with this cid is not a file.")]
+    NotAFileError,
+    #[error("Error reading the file: {0}")]
+    ReadError(CatErrors),
     #[error("Unknown Error Occured")]
     UnknownError,
 }
 
 pub fn detect_file_type(file_data: &[u8]) -> Option<String> {
     let kind = get(file_data);
-    match kind {
-        Some(val) => return Some(val.mime_type().to_string()),
-        None => return None,
-    }
+    kind.map(|val| val.mime_type().to_string())
 }
 
 /*
 tldr; how it works
 just fetching stuff from the db
 */
-pub async fn return_node_from_db(cid_string: String) -> Option<MerkleNode> {
-    let db = init_db(String::from(PATH)).await.unwrap();
-    let root_db_node: Result<Option<fjall::Slice>, fjall::Error> = db.get(cid_string);
+pub async fn return_node_from_db(cid_string: String, repo: &Repo) -> Option<MerkleNode> {
+    let store = FjallBlockstore::new(repo);
+    return_node_from_db_with_handle(cid_string, &store).await
+}
 
-    let slice = match root_db_node {
-        Ok(Some(value)) => value,
+pub async fn return_node_from_db_with_handle(
+    cid_string: String,
+    store: &dyn Blockstore,
+) -> Option<MerkleNode> {
+    let cid = match Cid::try_from(cid_string.as_str()) {
+        Ok(cid) => cid,
+        Err(_) => {
+            eprintln!("{}", ReassembleErrors::NotFoundError);
+            return None;
+        }
+    };
+    let node = match store.get(&cid).await {
+        Ok(Some(node)) => node,
         Ok(None) => {
             eprintln!("{}", ReassembleErrors::RootNodeNotFoundError);
             return None;
@@ -50,65 +65,123 @@ pub async fn return_node_from_db(cid_string: String) -> Option<MerkleNode> {
         }
     };
 
-    let str_val = String::from_utf8_lossy(&slice).to_string();
-    let node: MerkleNode = serde_json::from_str(&str_val).unwrap();
-    Some(node)
+    // verify with whatever hash the cid names, not just sha2-256
+    match node.verify() {
+        Ok(true) => Some(node),
+        Ok(false) => {
+            eprintln!("{}", ReassembleErrors::CorruptedNodeError);
+            None
+        }
+        Err(err) => {
+            eprintln!("{} - {}", err, ReassembleErrors::CorruptedNodeError);
+            None
+        }
+    }
 }
 
 /*
 tldr; how it works
-performing bfs works best i think here, because at every level, starting from the root node,
-need to check for duplication of the last node (caused by the ensure_even) function
-if last a dup node/ same as second last node, do not explore it's children
-assemble all the data and in the end extract from the root node the extension type
-1. check at each level if the last and second last node's cids are equal,
-if so then no need to explore the last node, only explore second last
+depth first, children left to right, so leaves come out in file order whatever the shape
+of the tree (leaves at different depths, parents with one child, trickle layers...).
+a cid that shows up under several links (a chunk repeated in the file) is visited every
+time it is linked, so repeated chunks come out as often as they occur in the file.
+a file of a single chunk is its own leaf.
 */
 pub async fn get_leaves_from_root_node_cid(
     cid_string: String,
+    repo: &Repo,
 ) -> Result<Vec<MerkleNode>, ReassembleErrors> {
-    let mut res: Vec<MerkleNode> = Vec::new();
+    let store = FjallBlockstore::new(repo);
+    get_leaves_from_root_node_cid_with_handle(cid_string, &store).await
+}
 
-    let root_node: Option<MerkleNode> = return_node_from_db(cid_string).await;
-    if root_node.is_none() {
-        return Err(ReassembleErrors::RootNodeNotFoundError);
+pub async fn get_leaves_from_root_node_cid_with_handle(
+    cid_string: String,
+    store: &dyn Blockstore,
+) -> Result<Vec<MerkleNode>, ReassembleErrors> {
+    let root_node = return_node_from_db_with_handle(cid_string, store)
+        .await
+        .ok_or(ReassembleErrors::RootNodeNotFoundError)?;
+    let mut res: Vec<MerkleNode> = Vec::new();
+    // next links to visit, the top of the stack is the leftmost
+    let mut stack: Vec<Cid> = Vec::new();
+    let mut node = root_node;
+    loop {
+        if node.links.is_empty() {
+            res.push(node);
+        } else {
+            stack.extend(node.links.iter().rev().map(|link| link.cid));
+        }
+        let Some(cid) = stack.pop() else {
+            return Ok(res);
+        };
+        node = return_node_from_db_with_handle(cid.to_string(), store)
+            .await
+            .ok_or(ReassembleErrors::NotFoundError)?;
     }
-    // res.push(root_node.clone().unwrap());
-    let mut q: Queue<Cid> = queue![];
-    let root_node_links = root_node.unwrap().links;
+}
 
-    //added 2nd level links to the queue to explore from (l->r)
-    for node_link in root_node_links {
-        let _ = q.add(node_link);
-    }
-    while q.size() != 0 {
-        //get the node whos link is q.remove()
-        let node: Option<MerkleNode> = return_node_from_db(q.remove().unwrap().to_string()).await;
-        if node.is_none() {
-            return Err(ReassembleErrors::NotFoundError);
-        }
-        if let Some(node) = node {
-            if !node.is_dup {
-                let links = node.links.clone();
-                if links.len() == 0 {
-                    res.push(node);
-                }
-                for link in links {
-                    let _ = q.add(link);
-                }
-            }
+/*
+tldr; how it works
+`len` bytes of a file starting at `offset`, fewer if the file ends first. only the nodes on
+the way down to offset are loaded: every parent records how many file bytes sit below
+each of its links (child_sizes), so whole subtrees before offset are skipped without being
+read. from there leaves are read in order until len bytes are in hand.
+*/
+pub async fn read_range(
+    cid_string: String,
+    offset: u64,
+    len: u64,
+    repo: &Repo,
+) -> Result<Vec<u8>, ReassembleErrors> {
+    let store = FjallBlockstore::new(repo);
+    read_range_with_handle(cid_string, offset, len, &store).await
+}
+
+pub async fn read_range_with_handle(
+    cid_string: String,
+    offset: u64,
+    len: u64,
+    store: &dyn Blockstore,
+) -> Result<Vec<u8>, ReassembleErrors> {
+    let cid = Cid::try_from(cid_string.as_str()).map_err(|_| ReassembleErrors::RootNodeNotFoundError)?;
+    let root = load_file_root(&cid, store).await.map_err(|err| match err {
+        CatErrors::StorageError(BlockstoreErrors::NotFoundError(_)) => ReassembleErrors::RootNodeNotFoundError,
+        err => range_error(err),
+    })?;
+    let wanted = len.min(root.file_size().saturating_sub(offset));
+    let mut out = Vec::with_capacity(wanted as usize);
+    let bytes = walk_bytes(Walk::new(root, offset, len, store), DEFAULT_PREFETCH);
+    futures::pin_mut!(bytes);
+    while let Some(piece) = bytes.next().await {
+        out.extend(piece.map_err(range_error)?);
+        if out.len() as u64 >= len {
+            break;
         }
     }
-    if q.size() == 0 {
-        return Ok(res);
+    // the last leaf read can run past the end of the range
+    out.truncate(wanted as usize);
+    Ok(out)
+}
+
+fn range_error(err: CatErrors) -> ReassembleErrors {
+    match err {
+        CatErrors::StorageError(BlockstoreErrors::NotFoundError(_)) => ReassembleErrors::NotFoundError,
+        CatErrors::StorageError(BlockstoreErrors::CorruptedError(_)) => ReassembleErrors::CorruptedNodeError,
+        CatErrors::NotAFileError(_) => ReassembleErrors::NotAFileError,
+        err => ReassembleErrors::ReadError(err),
     }
-    return Err(ReassembleErrors::UnknownError);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::storage::dag::{create_leaf, generate_merkle_tree};
+    use crate::storage::metadata::FileMetadata;
+    use crate::storage::blockstore::BlockstoreErrors;
+    use crate::cid::chunker::ChunkerType;
+    use crate::storage::import::{import_reader, ImportOptions};
+    use crate::storage::layout::DagLayout;
     use crate::storage::store_file;
     use std::fs::File;
     use std::io::Write;
@@ -129,13 +202,15 @@ mod tests {
             create_leaf(b"File Chunk 2"),
         ];
 
-        let tree = generate_merkle_tree(leaves.clone(), "png").unwrap();
+        let tree = generate_merkle_tree(leaves.clone(), FileMetadata::default()).unwrap();
         println!("{}", tree.len());
         let root_node = tree.last().unwrap().cid.to_string();
-        let res = store_file(tree).await;
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repo::open(dir.path()).unwrap();
+        let res = store_file(tree, &repo).await;
         //check if the file is stored correctly
-        assert_eq!(res, true);
-        let retrived = return_node_from_db(root_node.to_string())
+        assert!(res.is_ok());
+        let retrived = return_node_from_db(root_node.to_string(), &repo)
             .await
             .unwrap()
             .cid
@@ -152,11 +227,13 @@ mod tests {
             create_leaf(b"File Chunk 42"),
             create_leaf(b"File Chunk 42"),
         ];
-        let tree = generate_merkle_tree(leaves.clone(), "txt").unwrap();
+        let tree = generate_merkle_tree(leaves.clone(), FileMetadata::default()).unwrap();
         let root_node = tree.last().unwrap().cid.to_string();
-        let res = store_file(tree).await;
-        assert_eq!(res, true);
-        let retrieved_leaves = get_leaves_from_root_node_cid(root_node).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repo::open(dir.path()).unwrap();
+        let res = store_file(tree, &repo).await;
+        assert!(res.is_ok());
+        let retrieved_leaves = get_leaves_from_root_node_cid(root_node, &repo).await.unwrap();
         // let mut final_result_to_string: Vec<String> = Vec::new();
 
         let file_path = Path::new("manas.txt"); // Root directory of the project
@@ -169,4 +246,166 @@ mod tests {
         }
         
     }
+
+    // counts block reads, to see how much of the dag a range read touches
+    #[derive(Default)]
+    struct CountingBlockstore {
+        inner: crate::storage::blockstore::MemoryBlockstore,
+        reads: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Blockstore for CountingBlockstore {
+        async fn get_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+            self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.get_block(cid).await
+        }
+        async fn put_block(&self, cid: &Cid, block: &[u8]) -> Result<(), BlockstoreErrors> {
+            self.inner.put_block(cid, block).await
+        }
+        async fn get_record(&self, cid: &Cid) -> Result<Option<Vec<u8>>, BlockstoreErrors> {
+            self.inner.get_record(cid).await
+        }
+        async fn put_record(&self, cid: &Cid, record: &[u8]) -> Result<(), BlockstoreErrors> {
+            self.inner.put_record(cid, record).await
+        }
+        async fn has(&self, cid: &Cid) -> Result<bool, BlockstoreErrors> {
+            self.inner.has(cid).await
+        }
+        async fn delete(&self, cid: &Cid) -> Result<(), BlockstoreErrors> {
+            self.inner.delete(cid).await
+        }
+        async fn list(&self) -> Result<Vec<Cid>, BlockstoreErrors> {
+            self.inner.list().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_range() {
+        let store = CountingBlockstore::default();
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let options = ImportOptions {
+            chunker: ChunkerType::FixedSize(10),
+            layout: DagLayout::Balanced { max_links: 10 },
+            ..Default::default()
+        };
+        // 1000 leaves, three levels of parents
+        let root = import_reader(data.as_slice(), &store, &options).await.unwrap().to_string();
+
+        for (offset, len) in [(0, 10), (5, 20), (9_995, 100), (4_321, 1_234), (10_000, 5), (0, 10_000)] {
+            let range = read_range_with_handle(root.clone(), offset, len, &store).await.unwrap();
+            let start = (offset as usize).min(data.len());
+            let end = (start + len as usize).min(data.len());
+            assert_eq!(range, data[start..end], "{} +{}", offset, len);
+        }
+
+        // a few bytes deep inside: the path down (root, two parents) and the one leaf,
+        // the walk knows from the sizes it has enough and fetches nothing ahead
+        store.reads.store(0, std::sync::atomic::Ordering::SeqCst);
+        let range = read_range_with_handle(root.clone(), 7_777, 3, &store).await.unwrap();
+        assert_eq!(range, data[7_777..7_780]);
+        assert_eq!(store.reads.load(std::sync::atomic::Ordering::SeqCst), 4);
+
+        assert!(matches!(
+            read_range_with_handle(crate::cid::generate_cid(b"missing").to_string(), 0, 1, &store).await,
+            Err(ReassembleErrors::RootNodeNotFoundError)
+        ));
+    }
+
+    fn concat_leaves(leaves: &[MerkleNode]) -> Vec<u8> {
+        leaves.iter().flat_map(|leaf| leaf.content().unwrap_or_default().to_vec()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_repeated_chunks_keep_their_order() {
+        let store = crate::storage::blockstore::MemoryBlockstore::default();
+        // five leaves, three of them the same block, the odd one out gets a parent of its own
+        let chunks: [&[u8]; 5] = [b"AAAA", b"BBBB", b"AAAA", b"AAAA", b"CCCC"];
+        let leaves = chunks.iter().map(|chunk| create_leaf(chunk)).collect();
+        let tree = generate_merkle_tree(leaves, FileMetadata::default()).unwrap();
+        let root = tree.last().unwrap().cid.to_string();
+        crate::storage::init_db::store_file_with_handle(tree, &store).await.unwrap();
+        let leaves = get_leaves_from_root_node_cid_with_handle(root, &store).await.unwrap();
+        assert_eq!(concat_leaves(&leaves), b"AAAABBBBAAAAAAAACCCC");
+
+        // a single chunk file is its own leaf
+        let tree = generate_merkle_tree(vec![create_leaf(b"alone")], FileMetadata::default()).unwrap();
+        let root = tree.last().unwrap().cid.to_string();
+        crate::storage::init_db::store_file_with_handle(tree, &store).await.unwrap();
+        let leaves = get_leaves_from_root_node_cid_with_handle(root, &store).await.unwrap();
+        assert_eq!(concat_leaves(&leaves), b"alone");
+    }
+
+    // file contents that repeat a lot, so plenty of chunks come out identical
+    fn file_strategy() -> impl proptest::strategy::Strategy<Value = Vec<u8>> {
+        use proptest::prelude::*;
+        prop_oneof![
+            proptest::collection::vec(any::<u8>(), 0..3000),
+            (proptest::collection::vec(0u8..3, 1..40), 0usize..3000)
+                .prop_map(|(pattern, len)| pattern.iter().copied().cycle().take(len).collect()),
+        ]
+    }
+
+    fn layout_strategy() -> impl proptest::strategy::Strategy<Value = DagLayout> {
+        use proptest::prelude::*;
+        prop_oneof![
+            Just(DagLayout::Binary),
+            (2usize..6).prop_map(|max_links| DagLayout::Balanced { max_links }),
+            (2usize..6, 1usize..4).prop_map(|(max_links, layer_repeat)| DagLayout::Trickle {
+                max_links,
+                layer_repeat
+            }),
+        ]
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_generated_trees_round_trip(data in file_strategy(), chunk in 1usize..33) {
+            proptest::prop_assume!(!data.is_empty());
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            let leaves = runtime.block_on(async {
+                let store = crate::storage::blockstore::MemoryBlockstore::default();
+                let leaves = data.chunks(chunk).map(create_leaf).collect();
+                let tree = generate_merkle_tree(leaves, FileMetadata::default()).unwrap();
+                let root = tree.last().unwrap().cid.to_string();
+                crate::storage::init_db::store_file_with_handle(tree, &store).await.unwrap();
+                get_leaves_from_root_node_cid_with_handle(root, &store).await.unwrap()
+            });
+            proptest::prop_assert_eq!(concat_leaves(&leaves), data);
+        }
+
+        #[test]
+        fn prop_imported_files_round_trip(
+            data in file_strategy(),
+            chunk in 1usize..33,
+            layout in layout_strategy(),
+            v0 in proptest::bool::ANY,
+        ) {
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            let (leaves, range) = runtime.block_on(async {
+                let store = crate::storage::blockstore::MemoryBlockstore::default();
+                let options = ImportOptions {
+                    chunker: ChunkerType::FixedSize(chunk),
+                    layout,
+                    cid_builder: match v0 {
+                        true => crate::cid::builder::CidBuilder::v0(),
+                        false => Default::default(),
+                    },
+                    ..Default::default()
+                };
+                let root = import_reader(data.as_slice(), &store, &options).await.unwrap().to_string();
+                let leaves = get_leaves_from_root_node_cid_with_handle(root.clone(), &store)
+                    .await
+                    .unwrap();
+                let (offset, len) = (data.len() as u64 / 3, data.len() as u64 / 2);
+                let range = read_range_with_handle(root, offset, len, &store).await.unwrap();
+                (leaves, range)
+            });
+            proptest::prop_assert_eq!(&concat_leaves(&leaves), &data);
+            let offset = data.len() / 3;
+            proptest::prop_assert_eq!(&range, &data[offset..offset + data.len() / 2]);
+        }
+    }
 }
@@ -0,0 +1,334 @@
+use crate::cid::builder::verify_cid;
+use crate::pinning::pin::{PinErrors, Pinset};
+use crate::storage::blockstore::{Blockstore, BlockstoreErrors};
+use crate::storage::{Link, MerkleNode};
+use cid::Cid;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ScrubErrors {
+    #[error("Error talking to the store: {0}")]
+    StorageError(#[from] BlockstoreErrors),
+    #[error("Error writing to the quarantine folder: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Error reading the pin set: {0}")]
+    PinError(#[from] PinErrors),
+    #[error("The block {0} is not stored here")]
+    NotFoundError(Cid),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScrubOptions {
+    // corrupt blocks are moved here (one file per cid) and dropped from the store
+    pub quarantine: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MissingChild {
+    pub parent: Cid,
+    pub cid: Cid,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ScrubReport {
+    pub checked: usize,
+    // bytes that no longer hash to their cid, or do not decode as the codec it names
+    pub corrupt: Vec<Cid>,
+    pub missing_children: Vec<MissingChild>,
+    // stored, not a root and not linked from any other stored block
+    pub orphaned: Vec<Cid>,
+    pub quarantined: Vec<Cid>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty() && self.missing_children.is_empty() && self.orphaned.is_empty()
+    }
+}
+
+// a good block hands back its links, all the walks need from it
+enum Checked {
+    Good(Vec<Link>),
+    Missing,
+    Corrupt(Vec<u8>),
+}
+
+// rehashed with whatever function the cid names, a hash we can not compute counts as corrupt
+async fn check_block(cid: &Cid, store: &dyn Blockstore) -> Result<Checked, ScrubErrors> {
+    let Some(block) = store.get_block_uncached(cid).await? else {
+        return Ok(Checked::Missing);
+    };
+    if !verify_cid(cid, &block).unwrap_or(false) {
+        return Ok(Checked::Corrupt(block));
+    }
+    Ok(match MerkleNode::decode(*cid, &block) {
+        Ok(node) => Checked::Good(node.links),
+        Err(_) => Checked::Corrupt(block),
+    })
+}
+
+async fn corrupt(
+    cid: Cid,
+    block: &[u8],
+    store: &dyn Blockstore,
+    options: &ScrubOptions,
+    report: &mut ScrubReport,
+) -> Result<(), ScrubErrors> {
+    report.corrupt.push(cid);
+    if let Some(quarantine) = &options.quarantine {
+        std::fs::create_dir_all(quarantine)?;
+        std::fs::write(quarantine.join(cid.to_string()), block)?;
+        store.delete(&cid).await?;
+        report.quarantined.push(cid);
+    }
+    Ok(())
+}
+
+/*
+tldr; how it works
+walks one dag from its root and checks every block on the way, a corrupt block is not
+walked into (its links can not be trusted), a link to a block that is not stored is a
+missing child. shared subtrees are checked once.
+*/
+pub async fn verify_dag(
+    root: &Cid,
+    store: &dyn Blockstore,
+    options: &ScrubOptions,
+) -> Result<ScrubReport, ScrubErrors> {
+    if !store.has(root).await? {
+        return Err(ScrubErrors::NotFoundError(*root));
+    }
+    let mut report = ScrubReport::default();
+    let mut seen = BTreeSet::from([*root]);
+    let mut stack = vec![(*root, None)];
+    while let Some((cid, parent)) = stack.pop() {
+        report.checked += 1;
+        match check_block(&cid, store).await? {
+            Checked::Good(links) => {
+                for link in links {
+                    if seen.insert(link.cid) {
+                        stack.push((link.cid, Some(cid)));
+                    }
+                }
+            }
+            Checked::Missing => {
+                if let Some(parent) = parent {
+                    report.missing_children.push(MissingChild { parent, cid });
+                }
+            }
+            Checked::Corrupt(block) => corrupt(cid, &block, store, options, &mut report).await?,
+        }
+    }
+    Ok(report)
+}
+
+// one full pass over every stored block, roots are what may stand on their own (the pins)
+pub async fn verify_all(
+    store: &dyn Blockstore,
+    roots: &BTreeSet<Cid>,
+    options: &ScrubOptions,
+) -> Result<ScrubReport, ScrubErrors> {
+    Scrubber::new(usize::MAX).step(store, roots, options).await
+}
+
+/*
+tldr; how it works
+checks the store a batch of blocks at a time in cid order, remembering where it stopped,
+so a big store is covered bit by bit without holding anything up for long. the store is
+listed and sorted once when a pass starts and the steps work through that list, blocks
+written during a pass are left to the next one. which blocks are linked to is collected
+over the whole pass, so orphans are only reported by the step that finishes a pass, after
+that the next step lists the store again.
+*/
+pub struct Scrubber {
+    batch: usize,
+    // the pass being worked through and how far it got
+    pass: Vec<Cid>,
+    next: usize,
+    scanned: BTreeSet<Cid>,
+    referenced: BTreeSet<Cid>,
+}
+
+impl Scrubber {
+    pub fn new(batch: usize) -> Self {
+        Scrubber {
+            batch: batch.max(1),
+            pass: Vec::new(),
+            next: 0,
+            scanned: BTreeSet::new(),
+            referenced: BTreeSet::new(),
+        }
+    }
+
+    pub async fn step(
+        &mut self,
+        store: &dyn Blockstore,
+        roots: &BTreeSet<Cid>,
+        options: &ScrubOptions,
+    ) -> Result<ScrubReport, ScrubErrors> {
+        if self.next == 0 {
+            self.pass = store.list().await?;
+            self.pass.sort();
+        }
+        let start = self.next;
+        let end = start.saturating_add(self.batch).min(self.pass.len());
+
+        let mut report = ScrubReport::default();
+        for cid in &self.pass[start..end] {
+            report.checked += 1;
+            match check_block(cid, store).await? {
+                Checked::Good(links) => {
+                    self.scanned.insert(*cid);
+                    for link in links {
+                        self.referenced.insert(link.cid);
+                        if !store.has(&link.cid).await? {
+                            report.missing_children.push(MissingChild {
+                                parent: *cid,
+                                cid: link.cid,
+                            });
+                        }
+                    }
+                }
+                // listed a moment ago, deleted since
+                Checked::Missing => {}
+                Checked::Corrupt(block) => corrupt(*cid, &block, store, options, &mut report).await?,
+            }
+        }
+        self.next = end;
+
+        if end == self.pass.len() {
+            for cid in std::mem::take(&mut self.scanned) {
+                if !self.referenced.contains(&cid) && !roots.contains(&cid) {
+                    report.orphaned.push(cid);
+                }
+            }
+            self.referenced.clear();
+            self.pass = Vec::new();
+            self.next = 0;
+        }
+        Ok(report)
+    }
+}
+
+// runs for the life of the node, a batch every interval, findings go to the log
+pub async fn scrub_in_background(
+    store: Arc<dyn Blockstore>,
+    pins: Pinset,
+    options: ScrubOptions,
+    batch: usize,
+    interval: Duration,
+) {
+    let mut scrubber = Scrubber::new(batch);
+    loop {
+        tokio::time::sleep(interval).await;
+        let roots = match pins.list() {
+            Ok(pins) => pins.into_iter().map(|pin| pin.cid).collect(),
+            Err(err) => {
+                eprintln!("Scrub could not read the pin set: {:?}", err);
+                continue;
+            }
+        };
+        match scrubber.step(store.as_ref(), &roots, &options).await {
+            Ok(report) if !report.is_clean() => eprintln!("Scrub found problems: {:?}", report),
+            Ok(_) => {}
+            Err(err) => eprintln!("Scrub failed: {:?}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::blockstore::MemoryBlockstore;
+    use crate::storage::create_leaf;
+    use crate::storage::import::file_dag;
+
+    async fn damaged_store() -> (MemoryBlockstore, Cid, Vec<Cid>, Cid) {
+        let store = MemoryBlockstore::default();
+        let root = file_dag(&store, b"sixteen bytes...").await;
+        let links: Vec<Cid> = store
+            .get(&root)
+            .await
+            .unwrap()
+            .unwrap()
+            .links
+            .iter()
+            .map(|link| link.cid)
+            .collect();
+        store.put_block(&links[0], b"bit rot").await.unwrap();
+        store.delete(&links[1]).await.unwrap();
+        let stray = create_leaf(b"nobody links here");
+        store.put(&stray).await.unwrap();
+        (store, root, links, stray.cid)
+    }
+
+    #[tokio::test]
+    async fn test_verify_dag() {
+        let (store, root, links, _) = damaged_store().await;
+        let report = verify_dag(&root, &store, &ScrubOptions::default()).await.unwrap();
+        assert_eq!(report.checked, 5);
+        assert_eq!(report.corrupt, vec![links[0]]);
+        assert_eq!(report.missing_children, vec![MissingChild { parent: root, cid: links[1] }]);
+        assert!(report.orphaned.is_empty());
+        assert!(matches!(
+            verify_dag(&links[1], &store, &ScrubOptions::default()).await,
+            Err(ScrubErrors::NotFoundError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verify_all_and_quarantine() {
+        let (store, root, links, stray) = damaged_store().await;
+        let dir = tempfile::tempdir().unwrap();
+        let options = ScrubOptions {
+            quarantine: Some(dir.path().to_path_buf()),
+        };
+        let report = verify_all(&store, &BTreeSet::from([root]), &options).await.unwrap();
+        assert_eq!(report.checked, 5);
+        assert_eq!(report.corrupt, vec![links[0]]);
+        assert_eq!(report.quarantined, vec![links[0]]);
+        // once quarantined the corrupt block is missing too, whichever is checked first
+        let missing: BTreeSet<Cid> = report.missing_children.iter().map(|child| child.cid).collect();
+        assert!(missing.contains(&links[1]));
+        assert!(missing.iter().all(|cid| *cid == links[0] || *cid == links[1]));
+        assert_eq!(report.orphaned, vec![stray]);
+        // the bad bytes were kept aside and are gone from the store
+        assert_eq!(std::fs::read(dir.path().join(links[0].to_string())).unwrap(), b"bit rot");
+        assert!(!store.has(&links[0]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_incremental_scrub() {
+        let (store, root, links, stray) = damaged_store().await;
+        let roots = BTreeSet::from([root]);
+        let mut scrubber = Scrubber::new(2);
+        let mut total = ScrubReport::default();
+        // 5 blocks in batches of 2, the third step ends the pass
+        for step in 0..3 {
+            let report = scrubber.step(&store, &roots, &ScrubOptions::default()).await.unwrap();
+            // not in the list this pass started from
+            if step == 0 {
+                store.put(&create_leaf(b"written mid pass")).await.unwrap();
+            }
+            total.checked += report.checked;
+            total.corrupt.extend(report.corrupt);
+            total.missing_children.extend(report.missing_children);
+            total.orphaned.extend(report.orphaned);
+        }
+        assert_eq!(total.checked, 5);
+        assert_eq!(total.corrupt, vec![links[0]]);
+        assert_eq!(total.missing_children.len(), 1);
+        assert_eq!(total.orphaned, vec![stray]);
+        // and the next pass starts over, with it
+        let mut checked = 0;
+        for _ in 0..3 {
+            checked += scrubber.step(&store, &roots, &ScrubOptions::default()).await.unwrap().checked;
+        }
+        assert_eq!(checked, 6);
+    }
+}
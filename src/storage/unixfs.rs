@@ -0,0 +1,199 @@
+use crate::storage::dag_pb::{
+    write_bytes_field, write_varint_field, DagPbErrors, FieldReader, FieldValue,
+};
+use serde::{Deserialize, Serialize};
+
+/*
+tldr; how it works
+unixfs is the protobuf that sits in the Data field of a dag-pb node and says what the node
+is (file, directory, ...) and, for files, how many bytes sit below it:
+    Data { Type = 1, Data = 2, filesize = 3, blocksizes = 4 (repeated), hashType = 5, fanout = 6 }
+blocksizes[i] is the number of file bytes under link i, so a reader can tell which child
+holds a given offset without fetching the others. hashType and fanout only show up on hamt
+shards, whose Data field is the bitfield of occupied slots. fields are written in field number order
+and blocksizes unpacked, which is what go-unixfs emits.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DataType {
+    Raw = 0,
+    Directory = 1,
+    File = 2,
+    Metadata = 3,
+    Symlink = 4,
+    HamtShard = 5,
+}
+
+impl TryFrom<u64> for DataType {
+    type Error = DagPbErrors;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => DataType::Raw,
+            1 => DataType::Directory,
+            2 => DataType::File,
+            3 => DataType::Metadata,
+            4 => DataType::Symlink,
+            5 => DataType::HamtShard,
+            other => return Err(DagPbErrors::UnknownDataTypeError(other)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnixFsData {
+    pub data_type: DataType,
+    pub data: Option<Vec<u8>>,
+    pub filesize: Option<u64>,
+    pub blocksizes: Vec<u64>,
+    #[serde(default)]
+    pub hash_type: Option<u64>,
+    #[serde(default)]
+    pub fanout: Option<u64>,
+}
+
+impl UnixFsData {
+    // an inner file node, it only records how the bytes are spread over its children
+    pub fn file(blocksizes: Vec<u64>) -> Self {
+        UnixFsData {
+            data_type: DataType::File,
+            data: None,
+            filesize: Some(blocksizes.iter().sum()),
+            blocksizes,
+            hash_type: None,
+            fanout: None,
+        }
+    }
+
+    // a leaf that wraps its chunk in unixfs, what CIDv0 imports use instead of raw leaves.
+    // go-unixfs leaves the Data field out for an empty file
+    pub fn file_leaf(chunk: Vec<u8>) -> Self {
+        UnixFsData {
+            data_type: DataType::File,
+            filesize: Some(chunk.len() as u64),
+            data: if chunk.is_empty() { None } else { Some(chunk) },
+            blocksizes: Vec::new(),
+            hash_type: None,
+            fanout: None,
+        }
+    }
+
+    // a plain directory node, the entries live in the dag-pb links
+    pub fn directory() -> Self {
+        UnixFsData {
+            data_type: DataType::Directory,
+            data: None,
+            filesize: None,
+            blocksizes: Vec::new(),
+            hash_type: None,
+            fanout: None,
+        }
+    }
+
+    // one level of a sharded directory, the bitfield says which of the fanout slots are used
+    pub fn hamt_shard(bitfield: Vec<u8>, hash_type: u64, fanout: u64) -> Self {
+        UnixFsData {
+            data_type: DataType::HamtShard,
+            data: Some(bitfield),
+            filesize: None,
+            blocksizes: Vec::new(),
+            hash_type: Some(hash_type),
+            fanout: Some(fanout),
+        }
+    }
+
+    // bytes of file content below this node
+    pub fn file_size(&self) -> u64 {
+        self.filesize
+            .unwrap_or_else(|| self.data.as_ref().map_or(0, |data| data.len() as u64))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint_field(&mut out, 1, self.data_type as u64);
+        if let Some(data) = &self.data {
+            write_bytes_field(&mut out, 2, data);
+        }
+        if let Some(filesize) = self.filesize {
+            write_varint_field(&mut out, 3, filesize);
+        }
+        for blocksize in &self.blocksizes {
+            write_varint_field(&mut out, 4, *blocksize);
+        }
+        if let Some(hash_type) = self.hash_type {
+            write_varint_field(&mut out, 5, hash_type);
+        }
+        if let Some(fanout) = self.fanout {
+            write_varint_field(&mut out, 6, fanout);
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<UnixFsData, DagPbErrors> {
+        let mut data_type = None;
+        let mut unixfs = UnixFsData {
+            data_type: DataType::Raw,
+            data: None,
+            filesize: None,
+            blocksizes: Vec::new(),
+            hash_type: None,
+            fanout: None,
+        };
+        let mut reader = FieldReader::new(bytes);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (1, FieldValue::Varint(kind)) => data_type = Some(DataType::try_from(kind)?),
+                (2, FieldValue::Bytes(data)) => unixfs.data = Some(data.to_vec()),
+                (3, FieldValue::Varint(size)) => unixfs.filesize = Some(size),
+                (4, FieldValue::Varint(size)) => unixfs.blocksizes.push(size),
+                // some encoders pack repeated varints
+                (4, FieldValue::Bytes(packed)) => {
+                    let mut packed_reader = FieldReader::new(packed);
+                    while let Some(size) = packed_reader.next_varint()? {
+                        unixfs.blocksizes.push(size);
+                    }
+                }
+                (5, FieldValue::Varint(hash_type)) => unixfs.hash_type = Some(hash_type),
+                (6, FieldValue::Varint(fanout)) => unixfs.fanout = Some(fanout),
+                _ => {}
+            }
+        }
+        unixfs.data_type = data_type.ok_or(DagPbErrors::MissingFieldError("Type"))?;
+        Ok(unixfs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unixfs_round_trip() {
+        let inner = UnixFsData::file(vec![262144, 262144, 1000]);
+        assert_eq!(inner.filesize, Some(525288));
+        assert_eq!(UnixFsData::decode(&inner.encode()).unwrap(), inner);
+
+        let leaf = UnixFsData::file_leaf(b"hello world\n".to_vec());
+        // Type=File, Data, filesize=12
+        let mut expected = vec![0x08, 0x02, 0x12, 0x0c];
+        expected.extend_from_slice(b"hello world\n");
+        expected.extend_from_slice(&[0x18, 0x0c]);
+        assert_eq!(leaf.encode(), expected);
+        assert_eq!(UnixFsData::decode(&expected).unwrap(), leaf);
+    }
+
+    #[test]
+    fn test_unixfs_hamt_shard_round_trip() {
+        let shard = UnixFsData::hamt_shard(vec![0x80, 0x01], 0x22, 16);
+        assert_eq!(shard.encode(), vec![0x08, 0x05, 0x12, 0x02, 0x80, 0x01, 0x28, 0x22, 0x30, 0x10]);
+        assert_eq!(UnixFsData::decode(&shard.encode()).unwrap(), shard);
+    }
+
+    #[test]
+    fn test_unixfs_requires_type() {
+        assert_eq!(
+            UnixFsData::decode(&[0x18, 0x01]),
+            Err(DagPbErrors::MissingFieldError("Type"))
+        );
+    }
+}